@@ -0,0 +1,494 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::Shutdown,
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use zeroize::Zeroize;
+
+// Import functions from other files
+use crate::{
+    masterfile::{self, MasterfileData},
+    secret::SecretBytes,
+};
+
+// Environment variable that points clients to the socket of the agent
+pub const SOCKET_ENV: &str = "RUSTY_VAULT_AGENT";
+
+// Permissions of the directory of the socket, for the user only
+const DIR_MODE: u32 = 0o700;
+
+// Seconds without a request before the agent forgets every key
+pub const DEFAULT_TIMEOUT: u64 = 900;
+
+// How long a client waits for the agent before prompting as usual
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Largest secret the agent accepts
+const MAX_SECRET_LEN: usize = 1024;
+
+// Prefix of the names registry keys are held under, vault keys are held
+// under the absolute path of their masterfile
+const REGISTRY_PREFIX: &str = "registry:";
+
+// Requests and responses of the protocol, one tab separated line each
+const REQUEST_GET: &str = "GET";
+const REQUEST_ADD: &str = "ADD";
+const REQUEST_FORGET: &str = "FORGET";
+const REQUEST_LOCK: &str = "LOCK";
+const RESPONSE_KEY: &str = "KEY";
+const RESPONSE_OK: &str = "OK";
+const RESPONSE_NONE: &str = "NONE";
+
+///
+/// Keys held by a running agent.
+/// # Data
+/// - `keys: HashMap<String, SecretBytes>`
+///     - Unlocked vault key material by the path of its masterfile, and
+///       registry keys, locked in RAM
+/// - `last_used: Instant`
+///     - Time of the last request, for the idle timeout
+/// 
+struct AgentState {
+    keys: HashMap<String, SecretBytes>,
+    last_used: Instant,
+}
+
+///
+/// Get the path of the agent socket, from the environment, below
+/// `$XDG_RUNTIME_DIR` or in `~/.rusty-vault` when that is not set
+/// 
+/// Returns `String`
+/// 
+pub fn socket_path() -> String {
+    if let Ok(path) = std::env::var(SOCKET_ENV) {
+        if !path.is_empty() {
+            return path;
+        }
+    }
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if Path::new(&dir).is_absolute() => format!("{}/rusty-vault/agent.sock", dir.trim_end_matches('/')),
+        _ => shellexpand::tilde("~/.rusty-vault/agent.sock").to_string(),
+    }
+}
+
+///
+/// Create the directory of the socket for the user only, and refuse one
+/// that belongs to someone else or that others can write to, where the
+/// socket could be replaced.
+/// # Arguments
+/// - `dir_path: &Path`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn prepare_socket_dir(dir_path: &Path) -> Result<(), anyhow::Error> {
+    fs::DirBuilder::new().recursive(true).mode(DIR_MODE).create(dir_path)?;
+    let metadata = fs::metadata(dir_path)?;
+    if metadata.uid() != unsafe { libc::getuid() } {
+        return Err(anyhow!("{} belongs to another user.", dir_path.display()));
+    }
+    if metadata.mode() & 0o022 != 0 {
+        return Err(anyhow!("{} can be written by other users. Make it private with chmod 700.", dir_path.display()));
+    }
+    Ok(())
+}
+
+///
+/// Run the agent in the foreground until it is killed. Unlocked vault keys
+/// are kept in locked memory and handed to clients of the same user over
+/// the socket, and forgotten after the idle timeout.
+/// # Arguments
+/// - `timeout: Duration`
+///     - Time without requests before every key is forgotten, zero to
+///       keep them until the agent is locked
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn run(timeout: Duration) -> Result<(), anyhow::Error> {
+    let path = socket_path();
+    if UnixStream::connect(&path).is_ok() {
+        return Err(anyhow!("An agent is already running on {}.", path));
+    }
+
+    // Replace a stale socket and create the new one for the user only
+    if Path::new(&path).exists() {
+        fs::remove_file(&path)?;
+    }
+    if let Some(parent) = Path::new(&path).parent() {
+        prepare_socket_dir(parent)?;
+    }
+    let old_umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(&path);
+    unsafe { libc::umask(old_umask) };
+    let listener = listener?;
+
+    println!("Agent listening on {}", path);
+    if timeout.is_zero() {
+        println!("Keys are kept until the agent is locked.");
+    } else {
+        println!("Keys are forgotten after {} seconds without use.", timeout.as_secs());
+    }
+
+    let state = Arc::new(Mutex::new(AgentState { keys: HashMap::new(), last_used: Instant::now() }));
+
+    // Forget the keys once the agent has been idle for too long
+    if !timeout.is_zero() {
+        let watched_state = Arc::clone(&state);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            let mut state = watched_state.lock().unwrap();
+            if !state.keys.is_empty() && state.last_used.elapsed() >= timeout {
+                state.keys.clear();
+                println!("Idle timeout reached, keys forgotten.");
+            }
+        });
+    }
+
+    // Serve one request per connection
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        if let Err(err) = serve(stream, &state) {
+            println!("Agent request failed: {}", err);
+        }
+    }
+    Ok(())
+}
+
+///
+/// Answer a single request of a client.
+/// # Arguments
+/// - `stream: UnixStream`
+///     - Connection of the client
+/// - `state: &Mutex<AgentState>`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn serve(
+    mut stream: UnixStream,
+    state: &Mutex<AgentState>,
+) -> Result<(), anyhow::Error> {
+    // Only the user running the agent may talk to it
+    if peer_uid(&stream)? != unsafe { libc::getuid() } {
+        return Err(anyhow!("Refused a connection from another user."));
+    }
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+
+    // A connection without a request only checks that the agent is running
+    if line.is_empty() {
+        return Ok(());
+    }
+    let response = handle(line.trim_end_matches('\n'), state);
+    line.zeroize();
+
+    let mut response = response?;
+    stream.write_all(format!("{}\n", response).as_bytes())?;
+    response.zeroize();
+    Ok(())
+}
+
+///
+/// Carry out a request and build the response.
+/// # Arguments
+/// - `request: &str`
+///     - Request line without the newline
+/// - `state: &Mutex<AgentState>`
+/// 
+/// Returns `Result<String, anyhow::Error>`
+/// 
+fn handle(
+    request: &str,
+    state: &Mutex<AgentState>,
+) -> Result<String, anyhow::Error> {
+    let mut state = state.lock().unwrap();
+    state.last_used = Instant::now();
+    match request.splitn(3, '\t').collect::<Vec<&str>>().as_slice() {
+        [REQUEST_GET, path] => match state.keys.get(*path) {
+            Some(secret) => {
+                let mut encoded = hex::encode(secret.expose());
+                let response = format!("{}\t{}", RESPONSE_KEY, encoded);
+                encoded.zeroize();
+                Ok(response)
+            }
+            None => Ok(RESPONSE_NONE.to_string()),
+        },
+        [REQUEST_ADD, path, encoded] => {
+            let mut secret = hex::decode(encoded)?;
+            if secret.is_empty() || secret.len() > MAX_SECRET_LEN {
+                secret.zeroize();
+                return Err(anyhow!("Malformed keys for {}.", path));
+            }
+            state.keys.insert(path.to_string(), SecretBytes::new(secret));
+            Ok(RESPONSE_OK.to_string())
+        },
+        [REQUEST_FORGET, path] => match state.keys.remove(*path) {
+            Some(_) => Ok(RESPONSE_OK.to_string()),
+            None => Ok(RESPONSE_NONE.to_string()),
+        },
+        [REQUEST_LOCK] => {
+            state.keys.clear();
+            Ok(RESPONSE_OK.to_string())
+        },
+        _ => Err(anyhow!("Unknown request.")),
+    }
+}
+
+///
+/// Get the user id of the process on the other end of a socket.
+/// # Arguments
+/// - `stream: &UnixStream`
+/// 
+/// Returns `Result<u32, anyhow::Error>`
+/// 
+fn peer_uid(stream: &UnixStream) -> Result<u32, anyhow::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0 };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                &mut credentials as *mut libc::ucred as *mut libc::c_void, &mut len)
+        };
+        if result != 0 {
+            return Err(anyhow!("Could not check the client: {}", std::io::Error::last_os_error()));
+        }
+        Ok(credentials.uid)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let (mut uid, mut gid) = (0, 0);
+        if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+            return Err(anyhow!("Could not check the client: {}", std::io::Error::last_os_error()));
+        }
+        Ok(uid)
+    }
+}
+
+///
+/// Send a request to the running agent and read its response.
+/// # Arguments
+/// - `request: &str`
+/// 
+/// Returns `Result<String, anyhow::Error>`
+/// 
+fn send(request: &str) -> Result<String, anyhow::Error> {
+    let path = socket_path();
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|_| anyhow!("No agent is running on {}.", path))?;
+    if peer_uid(&stream)? != unsafe { libc::getuid() } {
+        return Err(anyhow!("The agent on {} belongs to another user.", path));
+    }
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.write_all(format!("{}\n", request).as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response)?;
+    if response.is_empty() {
+        return Err(anyhow!("The agent refused the request."));
+    }
+    response.truncate(response.trim_end_matches('\n').len());
+    Ok(response)
+}
+
+///
+/// Get the path a vault or registry is known by in the agent, so different
+/// spellings of the same path share the keys
+/// 
+/// Returns `String`
+/// 
+fn agent_key(masterfile_path: &str) -> String {
+    fs::canonicalize(masterfile_path)
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_else(|_| masterfile_path.to_string())
+}
+
+///
+/// Get a secret from the agent, if one is running and holds it.
+/// # Arguments
+/// - `name: &str`
+///     - Name the secret is held under
+/// 
+/// Returns `Option<SecretBytes>`
+/// 
+fn fetch_secret(name: &str) -> Option<SecretBytes> {
+    if !Path::new(&socket_path()).exists() {
+        return None;
+    }
+    let mut response = send(&format!("{}\t{}", REQUEST_GET, name)).ok()?;
+    let secret = response.strip_prefix(&format!("{}\t", RESPONSE_KEY))
+        .and_then(|encoded| hex::decode(encoded).ok())
+        .map(SecretBytes::new);
+    response.zeroize();
+    secret
+}
+
+///
+/// Hand a secret to the agent, if one is running.
+/// # Arguments
+/// - `name: &str`
+///     - Name to hold the secret under
+/// - `secret: &[u8]`
+/// 
+fn store_secret(name: &str, secret: &[u8]) {
+    if !Path::new(&socket_path()).exists() {
+        return;
+    }
+    let mut encoded = hex::encode(secret);
+    let mut request = format!("{}\t{}\t{}", REQUEST_ADD, name, encoded);
+    encoded.zeroize();
+    send(&request).ok();
+    request.zeroize();
+}
+
+///
+/// Get the keys of a vault from the agent, if one is running and has them.
+/// # Arguments
+/// - `masterfile_path: &str`
+///     - Path to the masterfile of the vault
+/// 
+/// Returns `Option<MasterfileData>`
+/// 
+pub fn fetch(masterfile_path: &str) -> Option<MasterfileData> {
+    let key_material = fetch_secret(&agent_key(masterfile_path))
+        .filter(|key_material| key_material.expose().len() == masterfile::KEY_MATERIAL_LEN)?;
+    let settings = masterfile::read_settings(masterfile_path).ok()?;
+    Some(masterfile::data_from_material(key_material.expose(), settings))
+}
+
+///
+/// Hand the keys of an unlocked vault to the agent, if one is running.
+/// # Arguments
+/// - `masterfile_path: &str`
+///     - Path to the masterfile of the vault
+/// - `data: &MasterfileData`
+/// 
+pub fn store(masterfile_path: &str, data: &MasterfileData) {
+    let mut key_material = masterfile::material_from_data(data);
+    store_secret(&agent_key(masterfile_path), &key_material);
+    key_material.zeroize();
+}
+
+///
+/// Make the running agent forget the keys of a vault.
+/// # Arguments
+/// - `masterfile_path: &str`
+///     - Path to the masterfile of the vault
+/// 
+/// Returns `Result<bool, anyhow::Error>`, whether the agent held the keys
+/// 
+pub fn forget(masterfile_path: &str) -> Result<bool, anyhow::Error> {
+    let response = send(&format!("{}\t{}", REQUEST_FORGET, agent_key(masterfile_path)))?;
+    Ok(response == RESPONSE_OK)
+}
+
+///
+/// Get the key of an encrypted registry from the agent, if one is running
+/// and has it.
+/// # Arguments
+/// - `config_path: &str`
+///     - Path to the config file holding the registry
+/// 
+/// Returns `Option<SecretBytes>`
+/// 
+pub fn fetch_registry_key(config_path: &str) -> Option<SecretBytes> {
+    fetch_secret(&format!("{}{}", REGISTRY_PREFIX, agent_key(config_path)))
+}
+
+///
+/// Hand the key of an encrypted registry to the agent, if one is running.
+/// # Arguments
+/// - `config_path: &str`
+///     - Path to the config file holding the registry
+/// - `key: &[u8]`
+/// 
+pub fn store_registry_key(config_path: &str, key: &[u8]) {
+    store_secret(&format!("{}{}", REGISTRY_PREFIX, agent_key(config_path)), key)
+}
+
+///
+/// Make the running agent forget the key of an encrypted registry, if one
+/// is running.
+/// # Arguments
+/// - `config_path: &str`
+///     - Path to the config file holding the registry
+/// 
+pub fn forget_registry_key(config_path: &str) {
+    if Path::new(&socket_path()).exists() {
+        send(&format!("{}\t{}{}", REQUEST_FORGET, REGISTRY_PREFIX, agent_key(config_path))).ok();
+    }
+}
+
+///
+/// Make the running agent forget every key it holds.
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn lock() -> Result<(), anyhow::Error> {
+    send(REQUEST_LOCK)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(state: &Mutex<AgentState>, parts: &[&str]) -> Result<String, anyhow::Error> {
+        handle(&parts.join("\t"), state)
+    }
+
+    #[test]
+    fn handle_requests() {
+        let state = Mutex::new(AgentState { keys: HashMap::new(), last_used: Instant::now() });
+        let key = hex::encode([7u8; 88]);
+        assert_eq!(request(&state, &[REQUEST_GET, "/v/masterfile.e"]).unwrap(), RESPONSE_NONE);
+        assert_eq!(request(&state, &[REQUEST_ADD, "/v/masterfile.e", &key]).unwrap(), RESPONSE_OK);
+        assert_eq!(request(&state, &[REQUEST_GET, "/v/masterfile.e"]).unwrap(), format!("{}\t{}", RESPONSE_KEY, key));
+
+        // Keys are forgotten one at a time or all at once
+        assert_eq!(request(&state, &[REQUEST_FORGET, "/v/masterfile.e"]).unwrap(), RESPONSE_OK);
+        assert_eq!(request(&state, &[REQUEST_FORGET, "/v/masterfile.e"]).unwrap(), RESPONSE_NONE);
+        request(&state, &[REQUEST_ADD, "/a/masterfile.e", &key]).unwrap();
+        request(&state, &[REQUEST_ADD, "registry:/config", &key]).unwrap();
+        assert_eq!(request(&state, &[REQUEST_LOCK]).unwrap(), RESPONSE_OK);
+        assert!(state.lock().unwrap().keys.is_empty());
+    }
+
+    #[test]
+    fn malformed_requests() {
+        let state = Mutex::new(AgentState { keys: HashMap::new(), last_used: Instant::now() });
+        let too_long = hex::encode(vec![7u8; MAX_SECRET_LEN + 1]);
+        for parts in [
+            &[REQUEST_ADD, "/v/masterfile.e", "not hex"][..],
+            &[REQUEST_ADD, "/v/masterfile.e", ""],
+            &[REQUEST_ADD, "/v/masterfile.e", &too_long],
+            &[REQUEST_ADD, "/v/masterfile.e", "00\t00"],
+            &[REQUEST_ADD, "/v/masterfile.e"],
+            &[REQUEST_GET],
+            &[REQUEST_GET, "/v", "masterfile.e"],
+            &[REQUEST_LOCK, "extra"],
+            &["PUT", "/v/masterfile.e"],
+            &[""],
+        ] {
+            assert!(request(&state, parts).is_err(), "{:?}", parts);
+        }
+        assert!(state.lock().unwrap().keys.is_empty());
+    }
+}
@@ -0,0 +1,376 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use notify::{RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Import functions from other files
+use crate::{
+    agent,
+    functions,
+    masterfile::MasterfileData,
+    mirror,
+    rekey,
+};
+
+// How often the supervisor checks the vaults
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Set by the signal handler when the session ends
+static SESSION_ENDED: AtomicBool = AtomicBool::new(false);
+
+///
+/// Policy for locking an unlocked vault again without being asked to. It is
+/// stored in the config file as `"idle=30;at=18:00;logout"`, where every
+/// part is optional.
+/// # Data
+/// - `idle_minutes: Option<u64>`
+///     - Lock after this many minutes without any file of the vault being
+///       read or written
+/// - `at: Option<(u8, u8)>`
+///     - Lock every day at this local hour and minute
+/// - `on_logout: bool`
+///     - Lock when the session of the supervisor ends
+/// 
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct AutoLock {
+    pub idle_minutes: Option<u64>,
+    pub at: Option<(u8, u8)>,
+    pub on_logout: bool,
+}
+
+impl AutoLock {
+    ///
+    /// Read a policy from the config file, an empty field means none
+    /// # Arguments
+    /// - `field: &str`
+    /// 
+    /// Returns `Result<AutoLock, anyhow::Error>`
+    /// 
+    pub fn parse(field: &str) -> Result<AutoLock, anyhow::Error> {
+        let mut autolock = AutoLock::default();
+        for part in field.split(';').map(str::trim).filter(|part| !part.is_empty()) {
+            match part.split_once('=') {
+                Some(("idle", minutes)) => autolock.idle_minutes = Some(minutes.parse()?),
+                Some(("at", time)) => autolock.at = Some(parse_time(time)?),
+                None if part == "logout" => autolock.on_logout = true,
+                _ => return Err(anyhow!("Unknown auto-lock setting {}.", part)),
+            }
+        }
+        Ok(autolock)
+    }
+
+    ///
+    /// Check whether the policy ever locks the vault
+    /// 
+    /// Returns `bool`
+    /// 
+    pub fn is_set(&self) -> bool {
+        self.idle_minutes.is_some() || self.at.is_some() || self.on_logout
+    }
+}
+
+// Written back to the config file in the format `parse` reads
+impl fmt::Display for AutoLock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(minutes) = self.idle_minutes {
+            parts.push(format!("idle={}", minutes));
+        }
+        if let Some((hour, minute)) = self.at {
+            parts.push(format!("at={:02}:{:02}", hour, minute));
+        }
+        if self.on_logout {
+            parts.push("logout".to_string());
+        }
+        write!(f, "{}", parts.join(";"))
+    }
+}
+
+// Stored as a string in the config file
+impl TryFrom<String> for AutoLock {
+    type Error = anyhow::Error;
+
+    fn try_from(field: String) -> Result<AutoLock, anyhow::Error> {
+        AutoLock::parse(&field)
+    }
+}
+
+impl From<AutoLock> for String {
+    fn from(autolock: AutoLock) -> String {
+        autolock.to_string()
+    }
+}
+
+///
+/// Parse a time of day written as `HH:MM`.
+/// # Arguments
+/// - `time: &str`
+/// 
+/// Returns `Result<(u8, u8), anyhow::Error>`
+/// 
+pub fn parse_time(time: &str) -> Result<(u8, u8), anyhow::Error> {
+    let (hour, minute) = time.split_once(':')
+        .ok_or_else(|| anyhow!("{} is not a time of day like 18:30.", time))?;
+    let (hour, minute) = (hour.parse::<u8>()?, minute.parse::<u8>()?);
+    if hour > 23 || minute > 59 {
+        return Err(anyhow!("{} is not a time of day like 18:30.", time));
+    }
+    Ok((hour, minute))
+}
+
+///
+/// Unlocked vault followed by the supervisor.
+/// # Data
+/// - `keys: Option<Arc<MasterfileData>>`
+///     - Keys taken from the agent, kept until the vault is locked
+/// - `last_used: Arc<Mutex<Instant>>`
+///     - Time of the last access to a file of the vault
+/// - `watcher: Option<notify::RecommendedWatcher>`
+///     - Updates `last_used`, stops when dropped. None when the vault
+///       could not be watched, which leaves out the idle timeout
+/// - `warned: bool`
+///     - Whether the missing keys were already reported
+/// 
+struct Tracked {
+    keys: Option<Arc<MasterfileData>>,
+    last_used: Arc<Mutex<Instant>>,
+    watcher: Option<notify::RecommendedWatcher>,
+    warned: bool,
+}
+
+///
+/// Run the auto-lock supervisor in the foreground until the session ends.
+/// Every unlocked vault with a policy in the config file is followed, and
+/// locked with the keys cached by the agent once its policy says so. The
+/// keys are taken from the agent while the vault is unlocked, so they are
+/// still at hand when the agent has forgotten them.
+/// # Arguments
+/// - `config_path: &str`
+///     - Path to the config file, read again on every check so policy
+///       changes apply right away
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn supervise(config_path: &str) -> Result<(), anyhow::Error> {
+    // Closing the terminal or logging out ends the session
+    for signal in [libc::SIGHUP, libc::SIGTERM, libc::SIGINT] {
+        unsafe { libc::signal(signal, on_session_end as *const () as libc::sighandler_t) };
+    }
+    println!("Supervising vaults with an auto-lock policy from {}", config_path);
+
+    let mut tracked: HashMap<String, Tracked> = HashMap::new();
+    let mut last_check = minute_of_day();
+    loop {
+        let now = minute_of_day();
+        let vaults = functions::read_config_file(config_path).unwrap_or_else(|err| {
+            println!("{}", err);
+            Vec::new()
+        });
+        for vault in vaults {
+            let unlocked = vault.status == 1 || vault.status == 2;
+            if !vault.autolock.is_set() || !unlocked {
+                tracked.remove(&vault.master_file_path);
+                continue;
+            }
+
+            // Start following vaults that were unlocked since the last check.
+            // A vault that can not be watched is still locked on schedule
+            if !tracked.contains_key(&vault.master_file_path) {
+                let last_used = Arc::new(Mutex::new(Instant::now()));
+                let watcher = match watch_vault(&vault.path, Arc::clone(&last_used)) {
+                    Ok(watcher) => Some(watcher),
+                    Err(err) => {
+                        println!("Could not watch [{}] for the idle timeout: {}", vault.name, err);
+                        None
+                    }
+                };
+                tracked.insert(vault.master_file_path.clone(),
+                    Tracked { keys: None, last_used, watcher, warned: false });
+            }
+            let entry = tracked.get_mut(&vault.master_file_path).unwrap();
+            if entry.keys.is_none() {
+                entry.keys = agent::fetch(&vault.master_file_path).map(MasterfileData::into_shared);
+            }
+
+            // Decide whether the policy asks for a lock now
+            let idle = entry.watcher.is_some() && vault.autolock.idle_minutes.is_some_and(|minutes|
+                entry.last_used.lock().unwrap().elapsed() >= Duration::from_secs(minutes * 60));
+            let scheduled = vault.autolock.at.is_some_and(|(hour, minute)|
+                passed(last_check, now, hour as u32 * 60 + minute as u32));
+            if !idle && !scheduled {
+                continue;
+            }
+
+            let reason = if idle { "idle timeout" } else { "scheduled lock" };
+            match entry.keys.clone() {
+                Some(keys) => {
+                    relock(&vault.name, &vault.master_file_path, &keys, reason);
+                    tracked.remove(&vault.master_file_path);
+                }
+                None if !entry.warned => {
+                    println!("Could not lock [{}] ({}): its keys are not cached. Unlock it while the agent runs.",
+                        vault.name, reason);
+                    entry.warned = true;
+                }
+                None => (),
+            }
+        }
+        last_check = now;
+
+        // Wait for the next check, locking on the way out if the session ends
+        let started = Instant::now();
+        while started.elapsed() < CHECK_INTERVAL {
+            if SESSION_ENDED.load(Ordering::SeqCst) {
+                return end_session(config_path, &tracked);
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+}
+
+///
+/// Watch the files of an unlocked vault and note the time whenever one of
+/// them is used. The masterfile and the top directory are left out, the
+/// supervisor reads them itself on every check.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// - `last_used: Arc<Mutex<Instant>>`
+///     - Time of the last access, updated by the watcher
+/// 
+/// Returns `notify::Result<notify::RecommendedWatcher>`
+/// 
+fn watch_vault(
+    top: &str,
+    last_used: Arc<Mutex<Instant>>,
+) -> notify::Result<notify::RecommendedWatcher> {
+    let top_path = PathBuf::from(top);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok_and(|event| event.paths.iter().any(|path| is_vault_file(&top_path, path))) {
+            *last_used.lock().unwrap() = Instant::now();
+        }
+    })?;
+    watcher.watch(Path::new(top), RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+///
+/// Check whether a path is a file or folder the user keeps in a vault,
+/// rather than the top directory or one of the files of the vault itself
+/// # Arguments
+/// - `top: &Path`
+/// - `path: &Path`
+/// 
+/// Returns `bool`
+/// 
+fn is_vault_file(top: &Path, path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+    path != top && !name.starts_with("masterfile.e") && name != rekey::JOURNAL_NAME
+        && !mirror::is_manifest(&path.to_string_lossy())
+}
+
+///
+/// Lock the vaults that should be locked when the session ends.
+/// # Arguments
+/// - `config_path: &str`
+/// - `tracked: &HashMap<String, Tracked>`
+///     - Vaults followed by the supervisor
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn end_session(
+    config_path: &str,
+    tracked: &HashMap<String, Tracked>,
+) -> Result<(), anyhow::Error> {
+    for vault in functions::read_config_file(config_path)? {
+        if !vault.autolock.on_logout || !(vault.status == 1 || vault.status == 2) {
+            continue;
+        }
+        let keys = tracked.get(&vault.master_file_path)
+            .and_then(|entry| entry.keys.clone())
+            .or_else(|| agent::fetch(&vault.master_file_path).map(MasterfileData::into_shared));
+        match keys {
+            Some(keys) => relock(&vault.name, &vault.master_file_path, &keys, "session ended"),
+            None => println!("Could not lock [{}] (session ended): its keys are not cached.", vault.name),
+        }
+    }
+    Ok(())
+}
+
+///
+/// Lock a vault through the normal lock path and report the outcome.
+/// # Arguments
+/// - `name: &str`
+///     - Name of the vault
+/// - `masterfile_path: &str`
+/// - `keys: &Arc<MasterfileData>`
+/// - `reason: &str`
+///     - Policy that asked for the lock
+/// 
+fn relock(
+    name: &str,
+    masterfile_path: &str,
+    keys: &Arc<MasterfileData>,
+    reason: &str,
+) {
+    match functions::process_vault(masterfile_path, keys, true) {
+        Ok(()) => println!("Locked [{}] ({}).", name, reason),
+        Err(err) => println!("Could not lock [{}] ({}): {}", name, reason, err),
+    }
+}
+
+///
+/// Check whether a time of day was reached between two checks, counting
+/// in minutes since midnight and across midnight
+/// # Arguments
+/// - `last: u32`
+/// - `now: u32`
+/// - `target: u32`
+/// 
+/// Returns `bool`
+/// 
+fn passed(last: u32, now: u32, target: u32) -> bool {
+    if last <= now {
+        last < target && target <= now
+    } else {
+        target > last || target <= now
+    }
+}
+
+///
+/// Get the local time as minutes since midnight
+/// 
+/// Returns `u32`
+/// 
+fn minute_of_day() -> u32 {
+    let mut local: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        libc::localtime_r(&now, &mut local);
+    }
+    local.tm_hour as u32 * 60 + local.tm_min as u32
+}
+
+///
+/// Signal handler that marks the end of the session
+/// # Arguments
+/// - `_signal: libc::c_int`
+/// 
+extern "C" fn on_session_end(_signal: libc::c_int) {
+    SESSION_ENDED.store(true, Ordering::SeqCst);
+}
@@ -0,0 +1,215 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use std::{
+    collections::BTreeMap,
+    env,
+    fs,
+    os::unix::fs::DirBuilderExt,
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+// Import functions from other files
+use crate::{
+    encryptionFunctions,
+    flatstore,
+    functions,
+    masterfile::{self, MasterfileData},
+    mirror::{self, Manifest, ManifestEntry, SyncStats},
+    wipe,
+};
+
+// Permissions of the working copy, for the user only
+const DIR_MODE: u32 = 0o700;
+
+///
+/// Get the default working copy of a vault, below `$XDG_RUNTIME_DIR`,
+/// which is kept in memory on most systems.
+/// # Arguments
+/// - `name: &str`
+///     - Name of the vault
+/// 
+/// Returns `Result<String, anyhow::Error>`
+/// 
+pub fn default_dir(name: &str) -> Result<String, anyhow::Error> {
+    match env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if Path::new(&dir).is_absolute() => Ok(format!("{}/rusty-vault/{}", dir.trim_end_matches('/'), name)),
+        _ => Err(anyhow!("XDG_RUNTIME_DIR is not set, choose a working copy with --to.")),
+    }
+}
+
+///
+/// Decrypt a locked vault into a working copy, leaving the vault itself
+/// encrypted. The working copy is recorded in the manifest of the vault,
+/// so `close` can encrypt what changed back into the vault.
+/// # Arguments
+/// - `masterfile_path: &str`
+///     - Path to the masterfile of the vault
+/// - `masterfile_data: &MasterfileData`
+///     - Data of the decrypted masterfile
+/// - `dist_path: &str`
+///     - Working copy, a directory that does not exist yet or is empty
+/// 
+/// Returns `Result<usize, anyhow::Error>`, the number of files decrypted
+/// 
+pub fn open(
+    masterfile_path: &str,
+    masterfile_data: &MasterfileData,
+    dist_path: &str,
+) -> Result<usize, anyhow::Error> {
+    let top = masterfile_path.strip_suffix("/masterfile.e")
+        .ok_or_else(|| anyhow!("{} is not a masterfile.", masterfile_path))?;
+    if masterfile_data.settings.layout == flatstore::LAYOUT_FLAT {
+        return Err(anyhow!("Only vaults with the tree layout can be opened in a working copy."));
+    }
+    if masterfile::rekey_pending(masterfile_path) {
+        return Err(anyhow!("Key rotation of the vault was interrupted. Rotate the keys again to finish it."));
+    }
+    if !matches!(functions::check_vault_status(top), 0 | 3) {
+        return Err(anyhow!("The vault is unlocked. Lock it before opening it in a working copy."));
+    }
+    let mut manifest = mirror::read_manifest(top, masterfile_data).unwrap_or_default();
+    if let Some(checkout) = manifest.checkout {
+        return Err(anyhow!("The vault is already open in {}.", checkout));
+    }
+
+    // Start from an empty directory only the user can read
+    if Path::new(dist_path).exists() && fs::read_dir(dist_path)?.next().is_some() {
+        return Err(anyhow!("{} is not empty.", dist_path));
+    }
+    fs::DirBuilder::new().recursive(true).mode(DIR_MODE).create(dist_path)?;
+    let dist_path = fs::canonicalize(dist_path)?.to_str()
+        .ok_or_else(|| anyhow!("Invalid path {}.", dist_path))?
+        .to_string();
+    if !wipe::is_ram_backed(&dist_path) {
+        println!("##Warning##");
+        println!("{} is not kept in memory, the plaintext is written to the disk.", dist_path);
+    }
+
+    // Never leave part of the plaintext behind when the vault can not be read
+    let mut files = BTreeMap::new();
+    let folder_key = encryptionFunctions::folder_key(masterfile_data)?;
+    if let Err(err) = open_dir(top, "", &dist_path, "", masterfile_data, folder_key.expose(), &mut files) {
+        wipe::wipe_dir(&dist_path)?;
+        return Err(err);
+    }
+
+    let count = files.len();
+    manifest.checkout = Some(dist_path);
+    manifest.files = files;
+    mirror::write_manifest(top, &manifest, masterfile_data)?;
+    Ok(count)
+}
+
+///
+/// Encrypt what changed in the working copy of a vault back into the vault
+/// and wipe the working copy.
+/// # Arguments
+/// - `masterfile_path: &str`
+///     - Path to the masterfile of the vault
+/// - `masterfile_data: &MasterfileData`
+///     - Data of the decrypted masterfile
+/// 
+/// Returns `Result<SyncStats, anyhow::Error>`
+/// 
+pub fn close(
+    masterfile_path: &str,
+    masterfile_data: &MasterfileData,
+) -> Result<SyncStats, anyhow::Error> {
+    let top = masterfile_path.strip_suffix("/masterfile.e")
+        .ok_or_else(|| anyhow!("{} is not a masterfile.", masterfile_path))?;
+    let checkout = mirror::read_manifest(top, masterfile_data)?.checkout
+        .ok_or_else(|| anyhow!("The vault is not open in a working copy."))?;
+    if !Path::new(&checkout).is_dir() {
+        return Err(anyhow!("The working copy {} is gone. Run close with --discard to keep the vault as it was opened.", checkout));
+    }
+
+    // The vault is complete again before the working copy goes away
+    let stats = mirror::sync(&checkout, masterfile_path, masterfile_data)?;
+    let Manifest { files, .. } = mirror::read_manifest(top, masterfile_data)?;
+    mirror::write_manifest(top, &Manifest { checkout: None, files }, masterfile_data)?;
+    wipe::wipe_dir(&checkout)?;
+    Ok(stats)
+}
+
+///
+/// Wipe the working copy of a vault without encrypting anything back, for
+/// a working copy that is gone or whose changes are not wanted. The vault
+/// keeps the files it had when it was opened.
+/// # Arguments
+/// - `masterfile_path: &str`
+///     - Path to the masterfile of the vault
+/// - `masterfile_data: &MasterfileData`
+///     - Data of the decrypted masterfile
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn discard(
+    masterfile_path: &str,
+    masterfile_data: &MasterfileData,
+) -> Result<(), anyhow::Error> {
+    let top = masterfile_path.strip_suffix("/masterfile.e")
+        .ok_or_else(|| anyhow!("{} is not a masterfile.", masterfile_path))?;
+    let Manifest { checkout, files } = mirror::read_manifest(top, masterfile_data)?;
+    let checkout = checkout.ok_or_else(|| anyhow!("The vault is not open in a working copy."))?;
+    if Path::new(&checkout).is_dir() {
+        wipe::wipe_dir(&checkout)?;
+    }
+    mirror::write_manifest(top, &Manifest { checkout: None, files }, masterfile_data)
+}
+
+///
+/// Decrypt an encrypted directory of the vault into the working copy.
+/// # Arguments
+/// - `top: &str`
+///     - Top of the vault
+/// - `relative: &str`
+///     - Encrypted path of the directory, relative to the top
+/// - `dist_top: &str`
+///     - Top of the working copy
+/// - `dist_relative: &str`
+///     - Plaintext path of the directory, relative to the top
+/// - `data: &MasterfileData`
+/// - `folder_key: &[u8]`
+/// - `files: &mut BTreeMap<String, ManifestEntry>`
+///     - Files decrypted so far, by their plaintext path
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn open_dir(
+    top: &str,
+    relative: &str,
+    dist_top: &str,
+    dist_relative: &str,
+    data: &MasterfileData,
+    folder_key: &[u8],
+    files: &mut BTreeMap<String, ManifestEntry>,
+) -> Result<(), anyhow::Error> {
+    for entry in fs::read_dir(mirror::join(top, relative))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.ends_with(".encrypted") {
+            continue;
+        }
+        let path = mirror::join(relative, &name);
+        if entry.file_type()?.is_dir() {
+            let foldername = encryptionFunctions::decrypt_foldername_with(&name, folder_key, data)?;
+            let dist_dir = mirror::join(dist_relative, &foldername);
+            fs::create_dir(mirror::join(dist_top, &dist_dir))?;
+            open_dir(top, &path, dist_top, &dist_dir, data, folder_key, files)?;
+        } else {
+            let dist_file_path = encryptionFunctions::decrypt_file_into_dir(&mirror::join(top, &path),
+                &mirror::join(dist_top, dist_relative), &data.master_key)?;
+            let metadata = fs::metadata(&dist_file_path)?;
+            let file_relative = dist_file_path.strip_prefix(dist_top).unwrap_or(&dist_file_path)
+                .trim_start_matches('/')
+                .to_string();
+            files.insert(file_relative, ManifestEntry {
+                mirror: path,
+                size: metadata.len(),
+                modified: metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos(),
+            });
+        }
+    }
+    Ok(())
+}
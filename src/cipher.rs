@@ -0,0 +1,414 @@
+// Import functions from external crates
+use aead::{stream as stream_v5, Aead as AeadV5, KeyInit, Payload as PayloadV5};
+use aes_gcm::Aes256Gcm;
+use aes_gcm_siv::Aes256GcmSiv;
+use anyhow::anyhow;
+use chacha20poly1305::{
+    aead::{stream, Aead, NewAead, Payload, generic_array::GenericArray},
+    XChaCha20Poly1305,
+};
+
+// Bytes of the stream nonce taken up by the counter and the last block flag
+const STREAM_OVERHEAD: usize = 5;
+
+///
+/// AEAD cipher suites a vault can be encrypted with. The suite is chosen
+/// when the vault is created, recorded in the masterfile and in the header
+/// of every file, and used for the key slots, file contents and names.
+/// All suites take a 256 bit key and add a 16 byte tag.
+/// 
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum CipherSuite {
+    #[default]
+    XChaCha20Poly1305,
+    Aes256Gcm,
+    Aes256GcmSiv,
+}
+
+impl CipherSuite {
+    ///
+    /// Get the suite from the id stored in the masterfile or file header.
+    /// # Arguments
+    /// - `id: u8`
+    /// 
+    /// Returns `Result<CipherSuite, anyhow::Error>`
+    /// 
+    pub fn from_id(id: u8) -> Result<CipherSuite, anyhow::Error> {
+        match id {
+            0 => Ok(CipherSuite::XChaCha20Poly1305),
+            1 => Ok(CipherSuite::Aes256Gcm),
+            2 => Ok(CipherSuite::Aes256GcmSiv),
+            _ => Err(anyhow!("Unsupported cipher suite {}.", id)),
+        }
+    }
+
+    ///
+    /// Get the id of the suite stored in the masterfile and file headers
+    /// 
+    /// Returns `u8`
+    /// 
+    pub fn id(self) -> u8 {
+        match self {
+            CipherSuite::XChaCha20Poly1305 => 0,
+            CipherSuite::Aes256Gcm => 1,
+            CipherSuite::Aes256GcmSiv => 2,
+        }
+    }
+
+    ///
+    /// Get the name of the suite for display
+    /// 
+    /// Returns `&'static str`
+    /// 
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherSuite::XChaCha20Poly1305 => "XChaCha20-Poly1305",
+            CipherSuite::Aes256Gcm => "AES-256-GCM",
+            CipherSuite::Aes256GcmSiv => "AES-256-GCM-SIV",
+        }
+    }
+
+    ///
+    /// Get the length of a full nonce of the suite
+    /// 
+    /// Returns `usize`
+    /// 
+    pub fn nonce_len(self) -> usize {
+        match self {
+            CipherSuite::XChaCha20Poly1305 => 24,
+            CipherSuite::Aes256Gcm | CipherSuite::Aes256GcmSiv => 12,
+        }
+    }
+
+    ///
+    /// Get the length of the nonce prefix of a stream, the rest of the
+    /// nonce holds the chunk counter and the last chunk flag
+    /// 
+    /// Returns `usize`
+    /// 
+    pub fn stream_nonce_len(self) -> usize {
+        self.nonce_len() - STREAM_OVERHEAD
+    }
+
+    ///
+    /// Encrypt a single message.
+    /// # Arguments
+    /// - `key: &[u8]`
+    ///     - 32 byte key
+    /// - `nonce: &[u8]`
+    ///     - Nonce of `nonce_len` bytes
+    /// - `plaintext: &[u8]`
+    /// 
+    /// Returns `Result<Vec<u8>, anyhow::Error>`
+    /// 
+    pub fn seal(self, key: &[u8], nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        self.seal_with_aad(key, nonce, plaintext, &[])
+    }
+
+    ///
+    /// Encrypt a single message, authenticating associated data with it.
+    /// # Arguments
+    /// - `key: &[u8]`
+    ///     - 32 byte key
+    /// - `nonce: &[u8]`
+    ///     - Nonce of `nonce_len` bytes
+    /// - `plaintext: &[u8]`
+    /// - `aad: &[u8]`
+    ///     - Associated data, needed again to decrypt
+    /// 
+    /// Returns `Result<Vec<u8>, anyhow::Error>`
+    /// 
+    pub fn seal_with_aad(self, key: &[u8], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        self.check_lengths(key, nonce, self.nonce_len())?;
+        let ciphertext = match self {
+            CipherSuite::XChaCha20Poly1305 => XChaCha20Poly1305::new(GenericArray::from_slice(key))
+                .encrypt(GenericArray::from_slice(nonce), Payload { msg: plaintext, aad })
+                .map_err(|err| err.to_string()),
+            CipherSuite::Aes256Gcm => <Aes256Gcm as KeyInit>::new(GenericArray::from_slice(key))
+                .encrypt(GenericArray::from_slice(nonce), PayloadV5 { msg: plaintext, aad })
+                .map_err(|err| err.to_string()),
+            CipherSuite::Aes256GcmSiv => <Aes256GcmSiv as KeyInit>::new(GenericArray::from_slice(key))
+                .encrypt(GenericArray::from_slice(nonce), PayloadV5 { msg: plaintext, aad })
+                .map_err(|err| err.to_string()),
+        };
+        ciphertext.map_err(|err| anyhow!("Encrypting with {}: {}", self.name(), err))
+    }
+
+    ///
+    /// Decrypt and authenticate a single message.
+    /// # Arguments
+    /// - `key: &[u8]`
+    ///     - 32 byte key
+    /// - `nonce: &[u8]`
+    ///     - Nonce of `nonce_len` bytes
+    /// - `ciphertext: &[u8]`
+    /// 
+    /// Returns `Result<Vec<u8>, anyhow::Error>`
+    /// 
+    pub fn open(self, key: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        self.open_with_aad(key, nonce, ciphertext, &[])
+    }
+
+    ///
+    /// Decrypt and authenticate a single message together with the
+    /// associated data it was encrypted with.
+    /// # Arguments
+    /// - `key: &[u8]`
+    ///     - 32 byte key
+    /// - `nonce: &[u8]`
+    ///     - Nonce of `nonce_len` bytes
+    /// - `ciphertext: &[u8]`
+    /// - `aad: &[u8]`
+    /// 
+    /// Returns `Result<Vec<u8>, anyhow::Error>`
+    /// 
+    pub fn open_with_aad(self, key: &[u8], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        self.check_lengths(key, nonce, self.nonce_len())?;
+        let plaintext = match self {
+            CipherSuite::XChaCha20Poly1305 => XChaCha20Poly1305::new(GenericArray::from_slice(key))
+                .decrypt(GenericArray::from_slice(nonce), Payload { msg: ciphertext, aad })
+                .ok(),
+            CipherSuite::Aes256Gcm => <Aes256Gcm as KeyInit>::new(GenericArray::from_slice(key))
+                .decrypt(GenericArray::from_slice(nonce), PayloadV5 { msg: ciphertext, aad })
+                .ok(),
+            CipherSuite::Aes256GcmSiv => <Aes256GcmSiv as KeyInit>::new(GenericArray::from_slice(key))
+                .decrypt(GenericArray::from_slice(nonce), PayloadV5 { msg: ciphertext, aad })
+                .ok(),
+        };
+        plaintext.ok_or_else(|| anyhow!("Decryption with {} failed.", self.name()))
+    }
+
+    ///
+    /// Create the encryptor of a stream of chunks.
+    /// # Arguments
+    /// - `key: &[u8]`
+    ///     - 32 byte key
+    /// - `nonce: &[u8]`
+    ///     - Nonce prefix of `stream_nonce_len` bytes
+    /// 
+    /// Returns `Result<StreamEncryptor, anyhow::Error>`
+    /// 
+    pub fn stream_encryptor(self, key: &[u8], nonce: &[u8]) -> Result<StreamEncryptor, anyhow::Error> {
+        self.check_lengths(key, nonce, self.stream_nonce_len())?;
+        Ok(match self {
+            CipherSuite::XChaCha20Poly1305 => StreamEncryptor::XChaCha20Poly1305(stream::EncryptorBE32::from_aead(
+                XChaCha20Poly1305::new(GenericArray::from_slice(key)), GenericArray::from_slice(nonce))),
+            CipherSuite::Aes256Gcm => StreamEncryptor::Aes256Gcm(stream_v5::EncryptorBE32::from_aead(
+                <Aes256Gcm as KeyInit>::new(GenericArray::from_slice(key)), GenericArray::from_slice(nonce))),
+            CipherSuite::Aes256GcmSiv => StreamEncryptor::Aes256GcmSiv(stream_v5::EncryptorBE32::from_aead(
+                <Aes256GcmSiv as KeyInit>::new(GenericArray::from_slice(key)), GenericArray::from_slice(nonce))),
+        })
+    }
+
+    ///
+    /// Create the decryptor of a stream of chunks.
+    /// # Arguments
+    /// - `key: &[u8]`
+    ///     - 32 byte key
+    /// - `nonce: &[u8]`
+    ///     - Nonce prefix of `stream_nonce_len` bytes
+    /// 
+    /// Returns `Result<StreamDecryptor, anyhow::Error>`
+    /// 
+    pub fn stream_decryptor(self, key: &[u8], nonce: &[u8]) -> Result<StreamDecryptor, anyhow::Error> {
+        self.check_lengths(key, nonce, self.stream_nonce_len())?;
+        Ok(match self {
+            CipherSuite::XChaCha20Poly1305 => StreamDecryptor::XChaCha20Poly1305(stream::DecryptorBE32::from_aead(
+                XChaCha20Poly1305::new(GenericArray::from_slice(key)), GenericArray::from_slice(nonce))),
+            CipherSuite::Aes256Gcm => StreamDecryptor::Aes256Gcm(stream_v5::DecryptorBE32::from_aead(
+                <Aes256Gcm as KeyInit>::new(GenericArray::from_slice(key)), GenericArray::from_slice(nonce))),
+            CipherSuite::Aes256GcmSiv => StreamDecryptor::Aes256GcmSiv(stream_v5::DecryptorBE32::from_aead(
+                <Aes256GcmSiv as KeyInit>::new(GenericArray::from_slice(key)), GenericArray::from_slice(nonce))),
+        })
+    }
+
+    ///
+    /// Check the key and nonce lengths before they are turned into
+    /// generic arrays, which panic on a length mismatch
+    /// 
+    /// Returns `Result<(), anyhow::Error>`
+    /// 
+    fn check_lengths(self, key: &[u8], nonce: &[u8], nonce_len: usize) -> Result<(), anyhow::Error> {
+        if key.len() != 32 || nonce.len() != nonce_len {
+            return Err(anyhow!("Invalid key or nonce length for {}.", self.name()));
+        }
+        Ok(())
+    }
+}
+
+///
+/// Encryptor of a stream of chunks with the STREAM construction, using a
+/// big endian 32 bit chunk counter.
+/// 
+pub enum StreamEncryptor {
+    XChaCha20Poly1305(stream::EncryptorBE32<XChaCha20Poly1305>),
+    Aes256Gcm(stream_v5::EncryptorBE32<Aes256Gcm>),
+    Aes256GcmSiv(stream_v5::EncryptorBE32<Aes256GcmSiv>),
+}
+
+impl StreamEncryptor {
+    ///
+    /// Encrypt a chunk that is not the last one of the stream.
+    /// # Arguments
+    /// - `msg: &[u8]`
+    /// - `aad: &[u8]`
+    /// 
+    /// Returns `Result<Vec<u8>, anyhow::Error>`
+    /// 
+    pub fn encrypt_next(&mut self, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        match self {
+            StreamEncryptor::XChaCha20Poly1305(encryptor) => encryptor
+                .encrypt_next(Payload { msg, aad })
+                .map_err(|err| anyhow!("{}", err)),
+            StreamEncryptor::Aes256Gcm(encryptor) => encryptor
+                .encrypt_next(PayloadV5 { msg, aad })
+                .map_err(|err| anyhow!("{}", err)),
+            StreamEncryptor::Aes256GcmSiv(encryptor) => encryptor
+                .encrypt_next(PayloadV5 { msg, aad })
+                .map_err(|err| anyhow!("{}", err)),
+        }
+    }
+
+    ///
+    /// Encrypt the last chunk of the stream.
+    /// # Arguments
+    /// - `msg: &[u8]`
+    /// - `aad: &[u8]`
+    /// 
+    /// Returns `Result<Vec<u8>, anyhow::Error>`
+    /// 
+    pub fn encrypt_last(self, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        match self {
+            StreamEncryptor::XChaCha20Poly1305(encryptor) => encryptor
+                .encrypt_last(Payload { msg, aad })
+                .map_err(|err| anyhow!("{}", err)),
+            StreamEncryptor::Aes256Gcm(encryptor) => encryptor
+                .encrypt_last(PayloadV5 { msg, aad })
+                .map_err(|err| anyhow!("{}", err)),
+            StreamEncryptor::Aes256GcmSiv(encryptor) => encryptor
+                .encrypt_last(PayloadV5 { msg, aad })
+                .map_err(|err| anyhow!("{}", err)),
+        }
+    }
+}
+
+///
+/// Decryptor of a stream written by `StreamEncryptor`.
+/// 
+pub enum StreamDecryptor {
+    XChaCha20Poly1305(stream::DecryptorBE32<XChaCha20Poly1305>),
+    Aes256Gcm(stream_v5::DecryptorBE32<Aes256Gcm>),
+    Aes256GcmSiv(stream_v5::DecryptorBE32<Aes256GcmSiv>),
+}
+
+impl StreamDecryptor {
+    ///
+    /// Decrypt a chunk that is not the last one of the stream.
+    /// # Arguments
+    /// - `msg: &[u8]`
+    /// - `aad: &[u8]`
+    /// 
+    /// Returns `Result<Vec<u8>, anyhow::Error>`
+    /// 
+    pub fn decrypt_next(&mut self, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        match self {
+            StreamDecryptor::XChaCha20Poly1305(decryptor) => decryptor
+                .decrypt_next(Payload { msg, aad })
+                .map_err(|err| anyhow!("{}", err)),
+            StreamDecryptor::Aes256Gcm(decryptor) => decryptor
+                .decrypt_next(PayloadV5 { msg, aad })
+                .map_err(|err| anyhow!("{}", err)),
+            StreamDecryptor::Aes256GcmSiv(decryptor) => decryptor
+                .decrypt_next(PayloadV5 { msg, aad })
+                .map_err(|err| anyhow!("{}", err)),
+        }
+    }
+
+    ///
+    /// Decrypt the last chunk of the stream.
+    /// # Arguments
+    /// - `msg: &[u8]`
+    /// - `aad: &[u8]`
+    /// 
+    /// Returns `Result<Vec<u8>, anyhow::Error>`
+    /// 
+    pub fn decrypt_last(self, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        match self {
+            StreamDecryptor::XChaCha20Poly1305(decryptor) => decryptor
+                .decrypt_last(Payload { msg, aad })
+                .map_err(|err| anyhow!("{}", err)),
+            StreamDecryptor::Aes256Gcm(decryptor) => decryptor
+                .decrypt_last(PayloadV5 { msg, aad })
+                .map_err(|err| anyhow!("{}", err)),
+            StreamDecryptor::Aes256GcmSiv(decryptor) => decryptor
+                .decrypt_last(PayloadV5 { msg, aad })
+                .map_err(|err| anyhow!("{}", err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUITES: [CipherSuite; 3] = [CipherSuite::XChaCha20Poly1305, CipherSuite::Aes256Gcm, CipherSuite::Aes256GcmSiv];
+
+    #[test]
+    fn suite_ids() {
+        for suite in SUITES {
+            assert!(CipherSuite::from_id(suite.id()).unwrap() == suite);
+        }
+        assert!(CipherSuite::from_id(3).is_err());
+    }
+
+    #[test]
+    fn seal_round_trip() {
+        for suite in SUITES {
+            let key = [1u8; 32];
+            let nonce = vec![2u8; suite.nonce_len()];
+            let sealed = suite.seal(&key, &nonce, b"message").unwrap();
+            assert_eq!(sealed.len(), b"message".len() + 16);
+            assert_eq!(suite.open(&key, &nonce, &sealed).unwrap(), b"message");
+
+            // A flipped bit, another key or nonce, or another suite
+            let mut tampered = sealed.clone();
+            tampered[0] ^= 1;
+            assert!(suite.open(&key, &nonce, &tampered).is_err(), "{}", suite.name());
+            assert!(suite.open(&[3u8; 32], &nonce, &sealed).is_err());
+            assert!(suite.open(&key, &vec![4u8; suite.nonce_len()], &sealed).is_err());
+            for other in SUITES.into_iter().filter(|other| *other != suite && other.nonce_len() == suite.nonce_len()) {
+                assert!(other.open(&key, &nonce, &sealed).is_err());
+            }
+
+            // Lengths that would panic are refused
+            assert!(suite.seal(&key[..16], &nonce, b"message").is_err());
+            assert!(suite.seal(&key, &nonce[1..], b"message").is_err());
+        }
+    }
+
+    #[test]
+    fn stream_round_trip() {
+        for suite in SUITES {
+            let key = [1u8; 32];
+            let nonce = vec![2u8; suite.stream_nonce_len()];
+            let mut encryptor = suite.stream_encryptor(&key, &nonce).unwrap();
+            let first = encryptor.encrypt_next(b"first", b"header").unwrap();
+            let second = encryptor.encrypt_next(b"second", b"header").unwrap();
+            let last = encryptor.encrypt_last(b"last", b"header").unwrap();
+
+            let mut decryptor = suite.stream_decryptor(&key, &nonce).unwrap();
+            assert_eq!(decryptor.decrypt_next(&first, b"header").unwrap(), b"first");
+            assert_eq!(decryptor.decrypt_next(&second, b"header").unwrap(), b"second");
+            assert_eq!(decryptor.decrypt_last(&last, b"header").unwrap(), b"last");
+
+            // Chunks out of order, a stream cut short or another header
+            let mut decryptor = suite.stream_decryptor(&key, &nonce).unwrap();
+            assert!(decryptor.decrypt_next(&second, b"header").is_err(), "{}", suite.name());
+            let mut decryptor = suite.stream_decryptor(&key, &nonce).unwrap();
+            decryptor.decrypt_next(&first, b"header").unwrap();
+            assert!(decryptor.decrypt_last(&second, b"header").is_err());
+            let mut decryptor = suite.stream_decryptor(&key, &nonce).unwrap();
+            assert!(decryptor.decrypt_next(&first, b"other").is_err());
+        }
+    }
+}
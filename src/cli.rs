@@ -0,0 +1,172 @@
+// Import functions from external crates
+use clap::{Parser, Subcommand};
+
+///
+/// Command line arguments. Without a command the menus are shown.
+/// 
+#[derive(Parser)]
+#[command(name = "rusty-vault", version, about = "Encrypt and decrypt vaults of files")]
+pub struct Cli {
+    /// Keyfile for vaults that require one together with the password
+    #[arg(long, global = true, value_name = "PATH")]
+    pub keyfile: Option<String>,
+
+    /// Config file to use instead of `$XDG_CONFIG_HOME/rusty-vault/config`,
+    /// also set with `RUSTY_VAULT_CONFIG`
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<String>,
+
+    /// Overwrite plaintext this many times before removing it once it is
+    /// encrypted, 0 to remove it normally. Defaults to
+    /// `secure_delete_passes` in the config file
+    #[arg(long, global = true, value_name = "PASSES")]
+    pub secure_delete: Option<u32>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+///
+/// Commands that run without the menus
+/// 
+#[derive(Subcommand)]
+pub enum Command {
+    /// Set a new password for a vault using its recovery phrase
+    Recover {
+        /// Top directory of the vault or the path to its masterfile
+        vault: String,
+
+        /// Recover with Shamir shares instead of the phrase. Shares or share
+        /// files can be listed here, any missing ones are asked for
+        #[arg(long, num_args = 0.., value_name = "SHARE")]
+        shares: Option<Vec<String>>,
+    },
+
+    /// Rotate the keys of a vault and encrypt it again with the new ones
+    Rekey {
+        /// Name of the vault, its top directory or the path to its masterfile
+        vault: String,
+    },
+
+    /// Run an agent that keeps unlocked vault keys for the session, so
+    /// the password is only asked once
+    Agent {
+        /// Forget the keys after this many seconds without use, 0 to keep
+        /// them until the agent is locked. Defaults to `agent_timeout` in
+        /// the config file, or 900
+        #[arg(long, value_name = "SECONDS")]
+        timeout: Option<u64>,
+
+        #[command(subcommand)]
+        action: Option<AgentAction>,
+    },
+
+    /// Show or change when a vault is locked again on its own. The locks
+    /// are done by `supervise`
+    Autolock {
+        /// Name of the vault, its top directory or the path to its masterfile
+        vault: String,
+
+        /// Lock after this many minutes without any file being accessed
+        #[arg(long, value_name = "MINUTES")]
+        idle: Option<u64>,
+
+        /// Lock every day at this local time
+        #[arg(long, value_name = "HH:MM")]
+        at: Option<String>,
+
+        /// Lock when the session ends
+        #[arg(long)]
+        logout: bool,
+
+        /// Remove the policy, any other options given set a new one
+        #[arg(long)]
+        off: bool,
+    },
+
+    /// Run the supervisor that locks vaults again by their auto-lock
+    /// policy, using the keys cached by the agent
+    Supervise,
+
+    /// Protect the list of vaults in the config file under a passphrase
+    Registry {
+        #[command(subcommand)]
+        action: RegistryAction,
+    },
+
+    /// Search a directory tree for vaults and offer to register the ones
+    /// that are not in the config file
+    Discover {
+        /// Directory to search, such as a backup drive or a synced folder
+        dir: String,
+    },
+
+    /// Encrypt a plaintext directory into a locked vault without touching
+    /// the plaintext. Run it again to encrypt only what changed
+    Mirror {
+        /// Plaintext directory to mirror
+        source: String,
+
+        /// Name of the vault, its top directory or the path to its masterfile
+        vault: String,
+    },
+
+    /// Decrypt a locked vault into a working copy, leaving the vault
+    /// encrypted
+    Open {
+        /// Name of the vault, its top directory or the path to its masterfile
+        vault: String,
+
+        /// Working copy to create, ideally in memory. Defaults to
+        /// `$XDG_RUNTIME_DIR/rusty-vault/<name>`
+        #[arg(long, value_name = "DIR")]
+        to: Option<String>,
+    },
+
+    /// Encrypt what changed in the working copy of a vault back into it and
+    /// wipe the working copy
+    Close {
+        /// Name of the vault, its top directory or the path to its masterfile
+        vault: String,
+
+        /// Wipe the working copy without encrypting anything back, leaving
+        /// the vault as it was when it was opened
+        #[arg(long)]
+        discard: bool,
+    },
+
+    /// Keep a locked vault locked by encrypting files as soon as they are
+    /// saved into it
+    Watch {
+        /// Name of the vault, its top directory or the path to its masterfile
+        vault: String,
+    },
+}
+
+///
+/// Commands sent to a running agent
+/// 
+#[derive(Subcommand)]
+pub enum AgentAction {
+    /// Make the agent forget the keys of every vault
+    Lock,
+
+    /// Make the agent forget the keys of one vault
+    Forget {
+        /// Name of the vault, its top directory or the path to its masterfile
+        vault: String,
+    },
+}
+
+///
+/// Changes to the protection of the registry
+/// 
+#[derive(Subcommand)]
+pub enum RegistryAction {
+    /// Encrypt the config file under a registry passphrase, or change the
+    /// passphrase of a protected registry
+    Protect,
+
+    /// Store the config file in plain text again
+    Unprotect,
+}
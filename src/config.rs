@@ -0,0 +1,448 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    env,
+    fs,
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt},
+    path::Path,
+};
+use zeroize::Zeroize;
+
+// Import functions from other files
+use crate::{
+    autolock::AutoLock,
+    registry::{self, SealedRegistry},
+    vault::vault::Vault,
+};
+
+// Version of the config format written by this build
+pub const CONFIG_VERSION: u32 = 1;
+
+// Environment variable that points to another config file
+pub const CONFIG_ENV: &str = "RUSTY_VAULT_CONFIG";
+
+// Where versions before the XDG location kept the config file
+const LEGACY_CONFIG_PATH: &str = "~/.rusty-vault/config";
+
+// Permissions of the config file and its directory, for the user only
+const FILE_MODE: u32 = 0o600;
+const DIR_MODE: u32 = 0o700;
+
+// Length of a hex encoded password hash in the old config format
+const LEGACY_HASH_LEN: usize = 64;
+
+///
+/// Settings that apply to every vault.
+/// # Data
+/// - `agent_timeout: Option<u64>`
+///     - Seconds without use before the agent forgets its keys, when
+///       `--timeout` is not given
+/// - `search_roots: Vec<String>`
+///     - Directories searched for vaults that moved, besides the usual
+///       mount points
+/// - `secure_delete_passes: Option<u32>`
+///     - Passes written over plaintext before it is removed, when
+///       `--secure-delete` is not given
+/// 
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct GlobalSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub search_roots: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secure_delete_passes: Option<u32>,
+}
+
+///
+/// Contents of the config file.
+/// # Data
+/// - `settings: GlobalSettings`
+/// - `vaults: Vec<Vault>`
+/// 
+#[derive(Default)]
+pub struct Config {
+    pub settings: GlobalSettings,
+    pub vaults: Vec<Vault>,
+}
+
+///
+/// Vault as it is stored in the config file.
+/// # Data
+/// - `name: String`
+/// - `masterfile: String`
+///     - Last known path to the masterfile
+/// - `password_hash: String`
+///     - Hex encoded hash of the vault password
+/// - `autolock: AutoLock`
+/// - `uuid: Option<String>`
+///     - UUID of the vault from its masterfile
+/// 
+#[derive(Serialize, Deserialize)]
+struct VaultEntry {
+    name: String,
+    masterfile: String,
+    password_hash: String,
+    #[serde(default, skip_serializing_if = "is_unset")]
+    autolock: AutoLock,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+}
+
+///
+/// Layout of the config file, a JSON document with the version first.
+/// A protected registry holds the sealed settings and vaults instead.
+/// # Data
+/// - `version: u32`
+/// - `settings: GlobalSettings`
+/// - `vaults: Vec<VaultEntry>`
+/// - `registry: Option<SealedRegistry>`
+/// 
+#[derive(Serialize, Deserialize)]
+struct ConfigFile {
+    version: u32,
+    #[serde(default)]
+    settings: GlobalSettings,
+    #[serde(default)]
+    vaults: Vec<VaultEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    registry: Option<SealedRegistry>,
+}
+
+///
+/// Layout of the config file when the registry is protected.
+/// # Data
+/// - `version: u32`
+/// - `registry: SealedRegistry`
+/// 
+#[derive(Serialize)]
+struct SealedConfigFile {
+    version: u32,
+    registry: SealedRegistry,
+}
+
+///
+/// Get the path of the config file. The `--config` flag comes first, then
+/// the `RUSTY_VAULT_CONFIG` environment variable, then
+/// `$XDG_CONFIG_HOME/rusty-vault/config`, which falls back to
+/// `~/.config/rusty-vault/config`.
+/// # Arguments
+/// - `flag: Option<&str>`
+///     - Path given with `--config`
+/// 
+/// Returns `String`
+/// 
+pub fn config_path(flag: Option<&str>) -> String {
+    if let Some(path) = flag {
+        return shellexpand::tilde(path).to_string();
+    }
+    match env::var(CONFIG_ENV) {
+        Ok(path) if !path.is_empty() => shellexpand::tilde(&path).to_string(),
+        _ => default_path(),
+    }
+}
+
+///
+/// Get the path of the config file in the XDG config directory. Relative
+/// values of `XDG_CONFIG_HOME` are ignored as the specification asks
+/// 
+/// Returns `String`
+/// 
+fn default_path() -> String {
+    let config_home = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if Path::new(&dir).is_absolute() => dir,
+        _ => shellexpand::tilde("~/.config").to_string(),
+    };
+    format!("{}/rusty-vault/config", config_home.trim_end_matches('/'))
+}
+
+///
+/// Make sure the config file exists, creating it and its directory for the
+/// user only. A config file at the old location in the home directory is
+/// moved to the default location the first time it is used.
+/// # Arguments
+/// - `config_path: &str`
+///     - Path to the config file
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn check_config_file(config_path: &str) -> Result<(), anyhow::Error> {
+    if Path::new(config_path).exists() {
+        return Ok(());
+    }
+    if let Some(parent) = Path::new(config_path).parent() {
+        fs::DirBuilder::new().recursive(true).mode(DIR_MODE).create(parent)?;
+    }
+
+    let legacy_path = shellexpand::tilde(LEGACY_CONFIG_PATH).to_string();
+    if config_path == default_path() && Path::new(&legacy_path).exists() {
+        fs::copy(&legacy_path, config_path)?;
+        fs::set_permissions(config_path, fs::Permissions::from_mode(FILE_MODE))?;
+        fs::remove_file(&legacy_path)?;
+        println!("Moved the config file from {} to {}", legacy_path, config_path);
+        return Ok(());
+    }
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(config_path)?
+        .set_permissions(fs::Permissions::from_mode(FILE_MODE))?;
+    Ok(())
+}
+
+///
+/// Read the config file. A file in the old comma separated format is
+/// migrated to the current format, keeping a copy of the old file next to
+/// it with a `.bak` suffix.
+/// # Arguments
+/// - `config_path: &str`
+///     - Path to the config file
+/// 
+/// Returns `Result<Config, anyhow::Error>`
+/// 
+pub fn load(config_path: &str) -> Result<Config, anyhow::Error> {
+    let contents = fs::read_to_string(config_path)?;
+    if contents.trim().is_empty() {
+        return Ok(Config::default());
+    }
+
+    // Anything that is not a JSON document is the old format
+    if !contents.trim_start().starts_with('{') {
+        let config = Config { settings: GlobalSettings::default(), vaults: parse_legacy(&contents)? };
+        let backup_path = format!("{}.bak", config_path);
+        fs::copy(config_path, &backup_path)?;
+        fs::set_permissions(&backup_path, fs::Permissions::from_mode(FILE_MODE))?;
+        save(config_path, &config)?;
+        println!("Migrated the config file to version {}, the old one is kept at {}", CONFIG_VERSION, backup_path);
+        return Ok(config);
+    }
+
+    let mut file: ConfigFile = serde_json::from_str(&contents)
+        .map_err(|err| anyhow!("Could not read the config file {}: {}", config_path, err))?;
+    if file.version > CONFIG_VERSION {
+        return Err(anyhow!("The config file {} was written by a newer version of rusty-vault.", config_path));
+    }
+
+    // A protected registry has to be unlocked before the vaults are known
+    if let Some(sealed) = file.registry {
+        let mut plaintext = registry::open(config_path, &sealed)?;
+        let parsed = serde_json::from_slice(&plaintext);
+        plaintext.zeroize();
+        file = parsed.map_err(|err| anyhow!("Could not read the registry in {}: {}", config_path, err))?;
+    }
+
+    let mut vaults = Vec::new();
+    for entry in file.vaults {
+        let hashed_password = hex::decode(&entry.password_hash)
+            .map_err(|_| anyhow!("The password hash of [{}] in the config file is malformed.", entry.name))?;
+        let mut vault = Vault::new(entry.name, entry.masterfile, hashed_password)?;
+        vault.autolock = entry.autolock;
+        vault.uuid = entry.uuid;
+        vaults.push(vault);
+    }
+    Ok(Config { settings: file.settings, vaults })
+}
+
+///
+/// Write the config file, sealed when its registry is protected. The new
+/// contents are written to a temporary file first and moved over the old
+/// file, so a crash never leaves a partly written config behind.
+/// # Arguments
+/// - `config_path: &str`
+///     - Path to the config file
+/// - `config: &Config`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn save(config_path: &str, config: &Config) -> Result<(), anyhow::Error> {
+    let file = ConfigFile {
+        version: CONFIG_VERSION,
+        settings: config.settings.clone(),
+        vaults: config.vaults.iter().map(|vault| VaultEntry {
+            name: vault.name.clone(),
+            masterfile: vault.master_file_path.clone(),
+            password_hash: hex::encode(&vault.hashed_password),
+            autolock: vault.autolock.clone(),
+            uuid: vault.uuid.clone(),
+        }).collect(),
+        registry: None,
+    };
+    let mut contents = serde_json::to_vec_pretty(&file)?;
+    if registry::is_protected(config_path) {
+        let registry = registry::seal(config_path, &contents)?;
+        contents.zeroize();
+        contents = serde_json::to_vec_pretty(&SealedConfigFile { version: CONFIG_VERSION, registry })?;
+    }
+    contents.push(b'\n');
+
+    // A temporary file of its own, readable by the user from the start, so
+    // two saves at once never write into the same file
+    let mut suffix = [0u8; 8];
+    OsRng.fill_bytes(&mut suffix);
+    let temp_path = format!("{}.{}.tmp", config_path, hex::encode(suffix));
+    let mut temp_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(FILE_MODE)
+        .open(&temp_path)?;
+    let written = temp_file.write_all(&contents)
+        .and_then(|_| temp_file.sync_all())
+        .and_then(|_| fs::rename(&temp_path, config_path));
+    if let Err(err) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+///
+/// Read the vaults of the old comma separated format, one
+/// `name,masterfile,hash` record per line with an optional auto-lock
+/// policy after the hash. Older versions wrote records without newlines
+/// in between, so the name of the next vault may directly follow a hash.
+/// # Arguments
+/// - `contents: &str`
+/// 
+/// Returns `Result<Vec<Vault>, anyhow::Error>`
+/// 
+fn parse_legacy(contents: &str) -> Result<Vec<Vault>, anyhow::Error> {
+    let mut fields = contents.lines()
+        .flat_map(|line| line.split(','))
+        .map(str::to_string)
+        .collect::<VecDeque<String>>();
+
+    let mut vaults = Vec::new();
+    while let Some(name) = fields.pop_front() {
+        if name.trim().is_empty() {
+            continue;
+        }
+        let master_file_path = fields.pop_front()
+            .filter(|path| path.ends_with("masterfile.e"))
+            .ok_or_else(|| anyhow!("The config file entry of [{}] has no masterfile.", name))?;
+        let mut hash = fields.pop_front()
+            .filter(|hash| hash.len() >= LEGACY_HASH_LEN && hash.is_char_boundary(LEGACY_HASH_LEN))
+            .ok_or_else(|| anyhow!("The config file entry of [{}] has no password hash.", name))?;
+
+        // Split off the name of a vault written on the same line
+        let next_name = hash.split_off(LEGACY_HASH_LEN);
+        if !next_name.is_empty() {
+            fields.push_front(next_name);
+        }
+        let hashed_password = hex::decode(&hash)
+            .map_err(|_| anyhow!("The password hash of [{}] in the config file is malformed.", name))?;
+        let mut vault = Vault::new(name, master_file_path, hashed_password)?;
+
+        // A field after the hash that is not followed by a masterfile is a policy
+        let has_policy = fields.front().is_some()
+            && fields.get(1).is_none_or(|path| !path.ends_with("masterfile.e"));
+        if has_policy {
+            let field = fields.pop_front().unwrap();
+            match AutoLock::parse(&field) {
+                Ok(autolock) => vault.autolock = autolock,
+                Err(err) => println!("Ignoring the auto-lock policy of [{}]: {}", vault.name, err),
+            }
+        }
+        vaults.push(vault);
+    }
+    Ok(vaults)
+}
+
+///
+/// Check whether an auto-lock policy is left out of the config file
+/// # Arguments
+/// - `autolock: &AutoLock`
+/// 
+/// Returns `bool`
+/// 
+fn is_unset(autolock: &AutoLock) -> bool {
+    !autolock.is_set()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    // Hash of a password as written by the old format
+    const HASH: &str = "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8";
+
+    // Config file in an empty directory of the test
+    fn test_config(name: &str) -> String {
+        format!("{}/config", testutil::test_dir(&format!("config-{}", name)))
+    }
+
+    #[test]
+    fn legacy_records() {
+        // Records glued together by old versions, then one with a policy
+        let contents = format!("a,/a/masterfile.e,{HASH}b,/b/masterfile.e,{HASH}\nc,/c/masterfile.e,{HASH},idle=5;logout\n\n");
+        let vaults = parse_legacy(&contents).unwrap();
+        assert_eq!(vaults.iter().map(|vault| vault.name.as_str()).collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(vaults[1].master_file_path, "/b/masterfile.e");
+        assert_eq!(vaults[1].path, "/b");
+        assert_eq!(vaults[0].hashed_password, hex::decode(HASH).unwrap());
+        assert_eq!(vaults[2].autolock.idle_minutes, Some(5));
+        assert!(vaults[2].autolock.on_logout);
+        assert!(parse_legacy("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_legacy_records() {
+        assert!(parse_legacy("a").is_err());
+        assert!(parse_legacy("a,/a/file,0000").is_err());
+        assert!(parse_legacy("a,/a/masterfile.e").is_err());
+        assert!(parse_legacy("a,/a/masterfile.e,abc").is_err());
+        assert!(parse_legacy(&format!("a,/a/masterfile.e,{}", "z".repeat(64))).is_err());
+        assert!(parse_legacy(&format!("a,/amasterfile.e,{HASH}")).is_err());
+
+        // A policy that can not be read is dropped, the vault is kept
+        let vaults = parse_legacy(&format!("a,/a/masterfile.e,{HASH},bogus")).unwrap();
+        assert!(!vaults[0].autolock.is_set());
+    }
+
+    #[test]
+    fn migrate_and_reload() {
+        let config_path = test_config("migrate");
+        let legacy = format!("a,/a/masterfile.e,{HASH}\nb,/b/masterfile.e,{HASH},idle=5\n");
+        fs::write(&config_path, &legacy).unwrap();
+
+        // The old file is kept and the new one is written for the user only
+        let config = load(&config_path).unwrap();
+        assert_eq!(config.vaults.len(), 2);
+        assert_eq!(fs::read_to_string(format!("{}.bak", config_path)).unwrap(), legacy);
+        assert_eq!(fs::metadata(&config_path).unwrap().permissions().mode() & 0o777, FILE_MODE);
+        let contents = fs::read_to_string(&config_path).unwrap();
+        assert!(contents.trim_start().starts_with('{'));
+
+        let reloaded = load(&config_path).unwrap();
+        assert_eq!(reloaded.vaults.len(), 2);
+        assert_eq!(reloaded.vaults[1].name, "b");
+        assert_eq!(reloaded.vaults[1].autolock.idle_minutes, Some(5));
+        assert_eq!(reloaded.vaults[0].hashed_password, config.vaults[0].hashed_password);
+
+        // Saving again leaves no temporary file behind
+        save(&config_path, &reloaded).unwrap();
+        let dir = Path::new(&config_path).parent().unwrap();
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn rejected_config() {
+        let config_path = test_config("rejected");
+        for contents in [
+            "{\"version\": 1, \"vaults\": [{\"name\": \"z\", \"masterfile\": \"/z/masterfile.e\", \"password_hash\": \"zz\"}]}",
+            "{\"version\": 1, \"vaults\": [{\"name\": \"z\", \"masterfile\": \"/z/file\", \"password_hash\": \"00\"}]}",
+            "{\"version\": 99}",
+            "{\"version\": 1, \"vaults\": [",
+        ] {
+            fs::write(&config_path, contents).unwrap();
+            assert!(load(&config_path).is_err(), "{}", contents);
+        }
+        fs::write(&config_path, "").unwrap();
+        assert!(load(&config_path).unwrap().vaults.is_empty());
+    }
+}
@@ -0,0 +1,973 @@
+// Import functions from external crates
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use aes_siv::siv::Aes256Siv;
+use anyhow::anyhow;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE, URL_SAFE_NO_PAD},
+    Engine,
+};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use data_encoding::BASE32;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+};
+use zeroize::Zeroize;
+
+// Import functions from other files
+use crate::{
+    encryptionFunctions,
+    flatstore,
+    masterfile::MasterfileData,
+};
+
+// Vault format and defaults written to new vaults
+const VAULT_FORMAT: u32 = 8;
+const MASTERKEY_VERSION: u32 = 999;
+const SHORTENING_THRESHOLD: usize = 220;
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_BLOCK_SIZE: u32 = 8;
+const MASTERKEY_FILE: &str = "masterkey.cryptomator";
+const VAULT_FILE: &str = "vault.cryptomator";
+
+// Size of the plaintext in each chunk of a file
+const CHUNK_LEN: usize = 32 * 1024;
+
+// Sizes of the file header and the chunk overhead for each cipher combo
+const GCM_HEADER_LEN: usize = 12 + 40 + 16;
+const GCM_CHUNK_OVERHEAD: usize = 12 + 16;
+const CTRMAC_HEADER_LEN: usize = 16 + 40 + 32;
+const CTRMAC_CHUNK_OVERHEAD: usize = 16 + 32;
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+///
+/// Cipher combination used by a Cryptomator vault for its file contents.
+/// File names always use AES-SIV.
+/// 
+#[derive(Clone, Copy, PartialEq)]
+enum CipherCombo {
+    SivGcm,
+    SivCtrMac,
+}
+
+///
+/// Define struct to hold the decrypted masterkey of a Cryptomator vault.
+/// This is the Cryptomator counterpart to `masterfile::MasterfileData`.
+/// # Data
+/// - `enc_key: [u8; 32]`
+///     - Key for AES-GCM/AES-CTR and the CTR half of AES-SIV
+/// - `mac_key: [u8; 32]`
+///     - Key for HMAC and the MAC half of AES-SIV
+/// 
+pub struct CryptomatorMasterkey {
+    pub enc_key: [u8; 32],
+    pub mac_key: [u8; 32],
+}
+
+impl Drop for CryptomatorMasterkey {
+    fn drop(&mut self) {
+        self.enc_key.zeroize();
+        self.mac_key.zeroize();
+    }
+}
+
+///
+/// Contents of `masterkey.cryptomator`
+/// 
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MasterkeyFile {
+    version: u32,
+    scrypt_salt: String,
+    scrypt_cost_param: u64,
+    scrypt_block_size: u32,
+    primary_master_key: String,
+    hmac_master_key: String,
+    version_mac: String,
+}
+
+///
+/// Claims of the signed `vault.cryptomator` token
+/// 
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VaultConfig {
+    format: u32,
+    shortening_threshold: usize,
+    jti: String,
+    cipher_combo: String,
+}
+
+///
+/// A single entry of a directory in a Cryptomator vault
+/// 
+pub enum Node {
+    File { name: String, path: String },
+    Dir { name: String, dir_id: String },
+}
+
+///
+/// Data structure for an opened Cryptomator vault
+/// # Data
+/// - `path: String`
+///     - Path to the top directory of the vault
+/// - `masterkey: CryptomatorMasterkey`
+/// - `cipher_combo: CipherCombo`
+/// - `shortening_threshold: usize`
+///     - Encrypted names longer than this are stored in `.c9s` directories
+/// 
+pub struct CryptomatorVault {
+    path: String,
+    masterkey: CryptomatorMasterkey,
+    cipher_combo: CipherCombo,
+    shortening_threshold: usize,
+}
+
+///
+/// Derive the key encryption key from the password with scrypt.
+/// # Arguments
+/// - `password: &str`
+/// - `salt: &[u8]`
+/// - `cost: u64`
+///     - scrypt cost parameter N, a power of two
+/// - `block_size: u32`
+///     - scrypt block size r
+/// 
+/// Returns `Result<[u8; 32], anyhow::Error>`
+/// 
+fn derive_kek(
+    password: &str,
+    salt: &[u8],
+    cost: u64,
+    block_size: u32,
+) -> Result<[u8; 32], anyhow::Error> {
+    if !cost.is_power_of_two() {
+        return Err(anyhow!("Invalid scrypt cost parameter."));
+    }
+    let params = scrypt::Params::new(cost.trailing_zeros() as u8, block_size, 1, 32)
+        .map_err(|err| anyhow!("Invalid scrypt parameters: {}", err))?;
+    let mut kek = [0u8; 32];
+    scrypt::scrypt(password.as_bytes(), salt, &params, &mut kek)
+        .map_err(|err| anyhow!("Deriving key: {}", err))?;
+    Ok(kek)
+}
+
+///
+/// HMAC-SHA256 of the masterkey file version, used to detect downgrades.
+/// # Arguments
+/// - `mac_key: &[u8; 32]`
+/// - `version: u32`
+/// 
+/// Returns `HmacSha256`, to be finalized or verified
+/// 
+fn version_mac(mac_key: &[u8; 32], version: u32) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(mac_key).unwrap();
+    mac.update(&version.to_be_bytes());
+    mac
+}
+
+///
+/// Sign the claims of a vault config as an HS256 JSON web token.
+/// # Arguments
+/// - `config: &VaultConfig`
+/// - `masterkey: &CryptomatorMasterkey`
+/// 
+/// Returns `Result<String, anyhow::Error>`
+/// 
+fn sign_vault_config(
+    config: &VaultConfig,
+    masterkey: &CryptomatorMasterkey,
+) -> Result<String, anyhow::Error> {
+    let header = serde_json::json!({
+        "kid": format!("masterkeyfile:{}", MASTERKEY_FILE),
+        "typ": "JWT",
+        "alg": "HS256",
+    });
+    let signing_input = format!("{}.{}",
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(config)?));
+
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(&[masterkey.enc_key, masterkey.mac_key].concat())?;
+    mac.update(signing_input.as_bytes());
+    Ok(format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())))
+}
+
+impl CryptomatorVault {
+    ///
+    /// Open an existing Cryptomator vault with its password. Reads the
+    /// masterkey file named by the vault config, unwraps the keys and
+    /// verifies the version of the masterkey file and the signature of the
+    /// config.
+    /// # Arguments
+    /// - `path: &str`
+    ///     - Path to the top directory of the vault
+    /// - `password: &str`
+    /// 
+    /// Returns `Result<CryptomatorVault, anyhow::Error>`
+    /// 
+    pub fn open(path: &str, password: &str) -> Result<CryptomatorVault, anyhow::Error> {
+        // Split the token and find the masterkey file from the key id
+        let token = fs::read_to_string(format!("{}/{}", path, VAULT_FILE))?;
+        let parts = token.trim().split('.').collect::<Vec<&str>>();
+        if parts.len() != 3 {
+            return Err(anyhow!("Malformed {}.", VAULT_FILE));
+        }
+        let header: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[0])?)?;
+        if header["alg"] != "HS256" {
+            return Err(anyhow!("Unsupported signature algorithm in {}.", VAULT_FILE));
+        }
+        let masterkey_file = header["kid"].as_str()
+            .and_then(|kid| kid.strip_prefix("masterkeyfile:"))
+            .ok_or_else(|| anyhow!("Only password protected Cryptomator vaults are supported."))?;
+        if masterkey_file.contains('/') {
+            return Err(anyhow!("Invalid masterkey file name."));
+        }
+
+        // Unwrap the keys with the key derived from the password
+        let masterkey: MasterkeyFile = serde_json::from_slice(
+            &fs::read(format!("{}/{}", path, masterkey_file))?)?;
+        let mut kek = derive_kek(password, &STANDARD.decode(&masterkey.scrypt_salt)?,
+            masterkey.scrypt_cost_param, masterkey.scrypt_block_size)?;
+        let kek_aes = aes_kw::KekAes256::from(kek);
+        kek.zeroize();
+        let mut keys = CryptomatorMasterkey { enc_key: [0u8; 32], mac_key: [0u8; 32] };
+        kek_aes.unwrap(&STANDARD.decode(&masterkey.primary_master_key)?, &mut keys.enc_key)
+            .map_err(|_| anyhow!("Wrong password."))?;
+        kek_aes.unwrap(&STANDARD.decode(&masterkey.hmac_master_key)?, &mut keys.mac_key)
+            .map_err(|_| anyhow!("Wrong password."))?;
+        version_mac(&keys.mac_key, masterkey.version)
+            .verify_slice(&STANDARD.decode(&masterkey.version_mac)?)
+            .map_err(|_| anyhow!("Version of {} does not match.", masterkey_file))?;
+
+        // Verify the signature of the vault config before trusting it
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&[keys.enc_key, keys.mac_key].concat())?;
+        mac.update(format!("{}.{}", parts[0], parts[1]).as_bytes());
+        mac.verify_slice(&URL_SAFE_NO_PAD.decode(parts[2])?)
+            .map_err(|_| anyhow!("Signature of {} does not match.", VAULT_FILE))?;
+        let config: VaultConfig = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1])?)?;
+        if config.format != VAULT_FORMAT {
+            return Err(anyhow!("Unsupported Cryptomator vault format {}.", config.format));
+        }
+        let cipher_combo = match config.cipher_combo.as_str() {
+            "SIV_GCM" => CipherCombo::SivGcm,
+            "SIV_CTRMAC" => CipherCombo::SivCtrMac,
+            other => return Err(anyhow!("Unsupported cipher combo {}.", other)),
+        };
+
+        Ok(CryptomatorVault {
+            path: path.to_string(),
+            masterkey: keys,
+            cipher_combo,
+            shortening_threshold: config.shortening_threshold,
+        })
+    }
+
+    ///
+    /// Create a new, empty Cryptomator vault in the given directory.
+    /// # Arguments
+    /// - `path: &str`
+    ///     - Path to the top directory of the new vault
+    /// - `password: &str`
+    /// 
+    /// Returns `Result<CryptomatorVault, anyhow::Error>`
+    /// 
+    pub fn create(path: &str, password: &str) -> Result<CryptomatorVault, anyhow::Error> {
+        fs::create_dir_all(path)?;
+        if Path::new(&format!("{}/{}", path, VAULT_FILE)).exists() {
+            return Err(anyhow!("{} already contains a Cryptomator vault.", path));
+        }
+
+        // Generate the keys and wrap them with the key derived from the password
+        let mut keys = CryptomatorMasterkey { enc_key: [0u8; 32], mac_key: [0u8; 32] };
+        let mut salt = [0u8; 32];
+        OsRng.fill_bytes(&mut keys.enc_key);
+        OsRng.fill_bytes(&mut keys.mac_key);
+        OsRng.fill_bytes(&mut salt);
+        let cost = 1u64 << SCRYPT_LOG_N;
+        let mut kek = derive_kek(password, &salt, cost, SCRYPT_BLOCK_SIZE)?;
+        let kek_aes = aes_kw::KekAes256::from(kek);
+        kek.zeroize();
+        let mut wrapped_enc_key = [0u8; 40];
+        let mut wrapped_mac_key = [0u8; 40];
+        kek_aes.wrap(&keys.enc_key, &mut wrapped_enc_key)
+            .map_err(|err| anyhow!("Wrapping key: {}", err))?;
+        kek_aes.wrap(&keys.mac_key, &mut wrapped_mac_key)
+            .map_err(|err| anyhow!("Wrapping key: {}", err))?;
+
+        let masterkey = MasterkeyFile {
+            version: MASTERKEY_VERSION,
+            scrypt_salt: STANDARD.encode(salt),
+            scrypt_cost_param: cost,
+            scrypt_block_size: SCRYPT_BLOCK_SIZE,
+            primary_master_key: STANDARD.encode(wrapped_enc_key),
+            hmac_master_key: STANDARD.encode(wrapped_mac_key),
+            version_mac: STANDARD.encode(version_mac(&keys.mac_key, MASTERKEY_VERSION).finalize().into_bytes()),
+        };
+        fs::write(format!("{}/{}", path, MASTERKEY_FILE), serde_json::to_vec_pretty(&masterkey)?)?;
+
+        // Write the signed vault config
+        let mut jti = [0u8; 16];
+        OsRng.fill_bytes(&mut jti);
+        let config = VaultConfig {
+            format: VAULT_FORMAT,
+            shortening_threshold: SHORTENING_THRESHOLD,
+            jti: hex::encode(jti),
+            cipher_combo: String::from("SIV_GCM"),
+        };
+        fs::write(format!("{}/{}", path, VAULT_FILE), sign_vault_config(&config, &keys)?)?;
+
+        // Create the directory of the root
+        let vault = CryptomatorVault {
+            path: path.to_string(),
+            masterkey: keys,
+            cipher_combo: CipherCombo::SivGcm,
+            shortening_threshold: SHORTENING_THRESHOLD,
+        };
+        fs::create_dir_all(vault.dir_path("")?)?;
+        Ok(vault)
+    }
+
+    ///
+    /// Build the AES-SIV cipher used for names and directory ids
+    /// 
+    /// Returns `Aes256Siv`
+    /// 
+    fn siv(&self) -> Aes256Siv {
+        let key = [self.masterkey.mac_key, self.masterkey.enc_key].concat();
+        Aes256Siv::new_from_slice(&key).unwrap()
+    }
+
+    ///
+    /// Get the path of the directory holding the entries of a directory.
+    /// # Arguments
+    /// - `dir_id: &str`
+    ///     - Id of the directory, the root has the empty id
+    /// 
+    /// Returns `Result<String, anyhow::Error>`
+    /// 
+    fn dir_path(&self, dir_id: &str) -> Result<String, anyhow::Error> {
+        let no_headers: [&[u8]; 0] = [];
+        let encrypted = self.siv().encrypt(no_headers, dir_id.as_bytes())
+            .map_err(|err| anyhow!("Encrypting directory id: {}", err))?;
+        let hash = BASE32.encode(&Sha1::digest(encrypted));
+        Ok(format!("{}/d/{}/{}", self.path, &hash[..2], &hash[2..32]))
+    }
+
+    ///
+    /// Encrypt a name for the directory with the given id.
+    /// # Arguments
+    /// - `name: &str`
+    /// - `dir_id: &str`
+    ///     - Id of the parent directory, bound to the name as associated data
+    /// 
+    /// Returns `Result<String, anyhow::Error>`
+    /// 
+    fn encrypt_name(&self, name: &str, dir_id: &str) -> Result<String, anyhow::Error> {
+        let encrypted = self.siv().encrypt([dir_id.as_bytes()], name.as_bytes())
+            .map_err(|err| anyhow!("Encrypting name: {}", err))?;
+        Ok(format!("{}.c9r", URL_SAFE.encode(encrypted)))
+    }
+
+    ///
+    /// Decrypt a name in the directory with the given id.
+    /// # Arguments
+    /// - `encrypted_name: &str`
+    ///     - Encrypted name including the `.c9r` extension
+    /// - `dir_id: &str`
+    /// 
+    /// Returns `Result<String, anyhow::Error>`
+    /// 
+    fn decrypt_name(&self, encrypted_name: &str, dir_id: &str) -> Result<String, anyhow::Error> {
+        let encoded = encrypted_name.strip_suffix(".c9r")
+            .ok_or_else(|| anyhow!("Unexpected name {}.", encrypted_name))?
+            .trim_end_matches('=');
+        let decrypted = self.siv().decrypt([dir_id.as_bytes()], &URL_SAFE_NO_PAD.decode(encoded)?)
+            .map_err(|_| anyhow!("Could not decrypt name {}.", encrypted_name))?;
+        let name = String::from_utf8(decrypted)?;
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(anyhow!("Invalid name in vault."));
+        }
+        Ok(name)
+    }
+
+    ///
+    /// List the entries of a directory. Symlinks are skipped.
+    /// # Arguments
+    /// - `dir_id: &str`
+    /// 
+    /// Returns `Result<Vec<Node>, anyhow::Error>`
+    /// 
+    pub fn list_dir(&self, dir_id: &str) -> Result<Vec<Node>, anyhow::Error> {
+        let mut nodes = Vec::new();
+        let dir_path = self.dir_path(dir_id)?;
+        for path_inv in fs::read_dir(&dir_path)? {
+            let path_inv = path_inv?;
+            if path_inv.file_type()?.is_symlink() {
+                continue;
+            }
+            let file_name = path_inv.file_name().into_string()
+                .map_err(|file_name| anyhow!("Invalid name {} in {}.", file_name.to_string_lossy(), dir_path))?;
+            let path = format!("{}/{}", dir_path, file_name);
+
+            // The backup of the directory id in dirid.c9r is not an entry.
+            // Shortened names keep the full encrypted name in name.c9s
+            let (encrypted_name, node_path) = if file_name.ends_with(".c9s") {
+                (fs::read_to_string(format!("{}/name.c9s", path))?.trim().to_string(), path)
+            } else if file_name.ends_with(".c9r") && file_name != "dirid.c9r" {
+                (file_name, path)
+            } else {
+                continue;
+            };
+            let name = self.decrypt_name(&encrypted_name, dir_id)?;
+
+            if Path::new(&format!("{}/dir.c9r", node_path)).is_file() {
+                let dir_id = fs::read_to_string(format!("{}/dir.c9r", node_path))?;
+                nodes.push(Node::Dir { name, dir_id: dir_id.trim().to_string() });
+            } else if Path::new(&format!("{}/contents.c9r", node_path)).is_file() {
+                nodes.push(Node::File { name, path: format!("{}/contents.c9r", node_path) });
+            } else if Path::new(&node_path).is_file() {
+                nodes.push(Node::File { name, path: node_path });
+            } else {
+                println!("Skipping unsupported entry {}", name);
+            }
+        }
+        Ok(nodes)
+    }
+
+    ///
+    /// Work out where a new entry of a directory is stored. Long encrypted
+    /// names are shortened into a `.c9s` directory holding `name.c9s`.
+    /// # Arguments
+    /// - `name: &str`
+    /// - `dir_id: &str`
+    ///     - Id of the parent directory
+    /// 
+    /// Returns `Result<(String, bool), anyhow::Error>`, the path of the entry
+    /// and whether it was shortened
+    /// 
+    fn node_path(&self, name: &str, dir_id: &str) -> Result<(String, bool), anyhow::Error> {
+        let encrypted_name = self.encrypt_name(name, dir_id)?;
+        let parent = self.dir_path(dir_id)?;
+        if encrypted_name.len() <= self.shortening_threshold {
+            return Ok((format!("{}/{}", parent, encrypted_name), false));
+        }
+
+        let short_name = format!("{}.c9s", URL_SAFE.encode(Sha1::digest(encrypted_name.as_bytes())));
+        let path = format!("{}/{}", parent, short_name);
+        fs::create_dir_all(&path)?;
+        fs::write(format!("{}/name.c9s", path), encrypted_name)?;
+        Ok((path, true))
+    }
+
+    ///
+    /// Create a directory inside the directory with the given id.
+    /// # Arguments
+    /// - `name: &str`
+    /// - `parent_id: &str`
+    /// 
+    /// Returns `Result<String, anyhow::Error>`, the id of the new directory
+    /// 
+    pub fn create_dir(&self, name: &str, parent_id: &str) -> Result<String, anyhow::Error> {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        let dir_id = format!("{}-{}-{}-{}-{}", hex::encode(&id[..4]), hex::encode(&id[4..6]),
+            hex::encode(&id[6..8]), hex::encode(&id[8..10]), hex::encode(&id[10..]));
+
+        let (path, _) = self.node_path(name, parent_id)?;
+        fs::create_dir_all(&path)?;
+        fs::write(format!("{}/dir.c9r", path), &dir_id)?;
+        fs::create_dir_all(self.dir_path(&dir_id)?)?;
+        Ok(dir_id)
+    }
+
+    ///
+    /// Encrypt a plaintext file into the directory with the given id.
+    /// # Arguments
+    /// - `name: &str`
+    /// - `parent_id: &str`
+    /// - `source_file_path: &str`
+    ///     - Path to the plaintext file
+    /// 
+    /// Returns `Result<(), anyhow::Error>`
+    /// 
+    pub fn add_file(
+        &self,
+        name: &str,
+        parent_id: &str,
+        source_file_path: &str,
+    ) -> Result<(), anyhow::Error> {
+        self.add_reader(name, parent_id, &mut File::open(source_file_path)?)
+    }
+
+    ///
+    /// Encrypt plaintext read from a reader into a new file of the vault.
+    /// # Arguments
+    /// - `name: &str`
+    ///     - Plaintext name of the file
+    /// - `parent_id: &str`
+    /// - `source: &mut dyn Read`
+    ///     - Plaintext of the file
+    /// 
+    /// Returns `Result<(), anyhow::Error>`
+    /// 
+    pub fn add_reader(
+        &self,
+        name: &str,
+        parent_id: &str,
+        source: &mut dyn Read,
+    ) -> Result<(), anyhow::Error> {
+        let (path, shortened) = self.node_path(name, parent_id)?;
+        let dist_file_path = if shortened {
+            format!("{}/contents.c9r", path)
+        } else {
+            path
+        };
+        self.encrypt_contents(source, &mut File::create(dist_file_path)?)
+    }
+
+    ///
+    /// Encrypt file contents with AES-GCM in 32 KiB chunks, as written by
+    /// the SIV_GCM cipher combo.
+    /// # Arguments
+    /// - `source: &mut dyn Read`
+    /// - `dist: &mut dyn Write`
+    /// 
+    /// Returns `Result<(), anyhow::Error>`
+    /// 
+    fn encrypt_contents(
+        &self,
+        source: &mut dyn Read,
+        dist: &mut dyn Write,
+    ) -> Result<(), anyhow::Error> {
+        // The header holds the content key encrypted with the master key
+        let mut header_nonce = [0u8; 12];
+        let mut payload = [0xffu8; 40];
+        OsRng.fill_bytes(&mut header_nonce);
+        OsRng.fill_bytes(&mut payload[8..]);
+        let header = Aes256Gcm::new_from_slice(&self.masterkey.enc_key)?
+            .encrypt(Nonce::from_slice(&header_nonce), payload.as_ref())
+            .map_err(|err| anyhow!("Encrypting header: {}", err))?;
+        dist.write_all(&header_nonce)?;
+        dist.write_all(&header)?;
+
+        // Each chunk is bound to its number and the header nonce
+        let content_cipher = Aes256Gcm::new_from_slice(&payload[8..])?;
+        payload.zeroize();
+        let mut buffer = vec![0u8; CHUNK_LEN];
+        let mut chunk_number = 0u64;
+        loop {
+            let read_count = encryptionFunctions::read_full(source, &mut buffer)?;
+            if read_count == 0 {
+                break;
+            }
+            let mut chunk_nonce = [0u8; 12];
+            OsRng.fill_bytes(&mut chunk_nonce);
+            let aad = [&chunk_number.to_be_bytes()[..], &header_nonce].concat();
+            let ciphertext = content_cipher
+                .encrypt(Nonce::from_slice(&chunk_nonce), Payload { msg: &buffer[..read_count], aad: &aad })
+                .map_err(|err| anyhow!("Encrypting chunk: {}", err))?;
+            dist.write_all(&chunk_nonce)?;
+            dist.write_all(&ciphertext)?;
+            chunk_number += 1;
+            if read_count < CHUNK_LEN {
+                break;
+            }
+        }
+        buffer.zeroize();
+        Ok(())
+    }
+
+    ///
+    /// Decrypt the contents of an encrypted file into the writer.
+    /// # Arguments
+    /// - `encrypted_file_path: &str`
+    /// - `dist: &mut dyn Write`
+    /// 
+    /// Returns `Result<(), anyhow::Error>`
+    /// 
+    pub fn decrypt_file(
+        &self,
+        encrypted_file_path: &str,
+        dist: &mut dyn Write,
+    ) -> Result<(), anyhow::Error> {
+        let mut encrypted_file = File::open(encrypted_file_path)?;
+        match self.cipher_combo {
+            CipherCombo::SivGcm => self.decrypt_gcm(&mut encrypted_file, dist),
+            CipherCombo::SivCtrMac => self.decrypt_ctrmac(&mut encrypted_file, dist),
+        }
+    }
+
+    ///
+    /// Decrypt file contents written with the SIV_GCM cipher combo.
+    /// 
+    fn decrypt_gcm(
+        &self,
+        encrypted_file: &mut File,
+        dist: &mut dyn Write,
+    ) -> Result<(), anyhow::Error> {
+        let mut header = [0u8; GCM_HEADER_LEN];
+        encrypted_file.read_exact(&mut header)?;
+        let mut payload = Aes256Gcm::new_from_slice(&self.masterkey.enc_key)?
+            .decrypt(Nonce::from_slice(&header[..12]), &header[12..])
+            .map_err(|_| anyhow!("Could not decrypt file header."))?;
+        let content_cipher = Aes256Gcm::new_from_slice(&payload[8..40])?;
+        payload.zeroize();
+
+        let mut buffer = vec![0u8; CHUNK_LEN + GCM_CHUNK_OVERHEAD];
+        let mut chunk_number = 0u64;
+        loop {
+            let read_count = encryptionFunctions::read_full(encrypted_file, &mut buffer)?;
+            if read_count == 0 {
+                break;
+            }
+            if read_count < GCM_CHUNK_OVERHEAD {
+                return Err(anyhow!("Truncated chunk."));
+            }
+            let aad = [&chunk_number.to_be_bytes()[..], &header[..12]].concat();
+            let plaintext = content_cipher
+                .decrypt(Nonce::from_slice(&buffer[..12]), Payload { msg: &buffer[12..read_count], aad: &aad })
+                .map_err(|_| anyhow!("Could not decrypt chunk {}.", chunk_number))?;
+            dist.write_all(&plaintext)?;
+            chunk_number += 1;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Decrypt file contents written with the SIV_CTRMAC cipher combo,
+    /// checking the HMAC of the header and each chunk before decrypting.
+    /// 
+    fn decrypt_ctrmac(
+        &self,
+        encrypted_file: &mut File,
+        dist: &mut dyn Write,
+    ) -> Result<(), anyhow::Error> {
+        let mut header = [0u8; CTRMAC_HEADER_LEN];
+        encrypted_file.read_exact(&mut header)?;
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.masterkey.mac_key)?;
+        mac.update(&header[..56]);
+        mac.verify_slice(&header[56..])
+            .map_err(|_| anyhow!("Could not authenticate file header."))?;
+        let mut payload = header[16..56].to_vec();
+        Aes256Ctr::new_from_slices(&self.masterkey.enc_key, &header[..16])?
+            .apply_keystream(&mut payload);
+        let mut content_key = [0u8; 32];
+        content_key.copy_from_slice(&payload[8..40]);
+        payload.zeroize();
+
+        let mut buffer = vec![0u8; CHUNK_LEN + CTRMAC_CHUNK_OVERHEAD];
+        let mut chunk_number = 0u64;
+        loop {
+            let read_count = encryptionFunctions::read_full(encrypted_file, &mut buffer)?;
+            if read_count == 0 {
+                break;
+            }
+            if read_count < CTRMAC_CHUNK_OVERHEAD {
+                return Err(anyhow!("Truncated chunk."));
+            }
+            let (chunk, chunk_mac) = buffer[..read_count].split_at(read_count - 32);
+            let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.masterkey.mac_key)?;
+            mac.update(&header[..16]);
+            mac.update(&chunk_number.to_be_bytes());
+            mac.update(chunk);
+            mac.verify_slice(chunk_mac)
+                .map_err(|_| anyhow!("Could not authenticate chunk {}.", chunk_number))?;
+            let mut plaintext = chunk[16..].to_vec();
+            Aes256Ctr::new_from_slices(&content_key, &chunk[..16])?
+                .apply_keystream(&mut plaintext);
+            dist.write_all(&plaintext)?;
+            chunk_number += 1;
+        }
+        content_key.zeroize();
+        Ok(())
+    }
+}
+
+///
+/// Print the decrypted tree of a Cryptomator vault.
+/// # Arguments
+/// - `vault: &CryptomatorVault`
+/// - `dir_id: &str`
+/// - `prefix: &str`
+///     - Plaintext path of the directory
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn list(
+    vault: &CryptomatorVault,
+    dir_id: &str,
+    prefix: &str,
+) -> Result<(), anyhow::Error> {
+    for node in vault.list_dir(dir_id)? {
+        match node {
+            Node::File { name, .. } => println!("{}/{}", prefix, name),
+            Node::Dir { name, dir_id } => {
+                println!("{}/{}/", prefix, name);
+                list(vault, &dir_id, &format!("{}/{}", prefix, name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+///
+/// Decrypt a directory of a Cryptomator vault into a plaintext directory.
+/// # Arguments
+/// - `vault: &CryptomatorVault`
+/// - `dir_id: &str`
+/// - `dist_path: &str`
+///     - Plaintext directory to extract into, created if needed
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn extract(
+    vault: &CryptomatorVault,
+    dir_id: &str,
+    dist_path: &str,
+) -> Result<(), anyhow::Error> {
+    fs::create_dir_all(dist_path)?;
+    for node in vault.list_dir(dir_id)? {
+        match node {
+            Node::File { name, path } => {
+                let mut dist_file = File::create(format!("{}/{}", dist_path, name))?;
+                vault.decrypt_file(&path, &mut dist_file)?;
+            }
+            Node::Dir { name, dir_id } => {
+                extract(vault, &dir_id, &format!("{}/{}", dist_path, name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+///
+/// Encrypt a plaintext directory into a directory of a Cryptomator vault.
+/// The masterfile and macOS metadata files of a rusty-vault are skipped,
+/// and so are symlinks.
+/// # Arguments
+/// - `vault: &CryptomatorVault`
+/// - `source_path: &str`
+///     - Plaintext directory to encrypt
+/// - `dir_id: &str`
+///     - Id of the directory to encrypt into
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn import_tree(
+    vault: &CryptomatorVault,
+    source_path: &str,
+    dir_id: &str,
+) -> Result<(), anyhow::Error> {
+    for path_inv in fs::read_dir(source_path)? {
+        let path_inv = path_inv?;
+        let name = path_inv.file_name().into_string()
+            .map_err(|name| anyhow!("Invalid name {} in {}.", name.to_string_lossy(), source_path))?;
+        let path = format!("{}/{}", source_path, name);
+        let file_type = path_inv.file_type()?;
+        if name == "masterfile.e" || name == ".DS_Store" || file_type.is_symlink() {
+            continue;
+        }
+        if file_type.is_dir() {
+            let child_id = vault.create_dir(&name, dir_id)?;
+            import_tree(vault, &path, &child_id)?;
+        } else {
+            vault.add_file(&name, dir_id, &path)?;
+        }
+    }
+    Ok(())
+}
+
+///
+/// Encrypt a locked vault into a directory of a Cryptomator vault. Every
+/// file is decrypted straight into the Cryptomator vault, so no plaintext
+/// is written to the disk and the vault stays locked throughout.
+/// # Arguments
+/// - `vault: &CryptomatorVault`
+/// - `top: &str`
+///     - Path to the top directory of the locked vault
+/// - `data: &MasterfileData`
+///     - Data of the decrypted masterfile
+/// - `dir_id: &str`
+///     - Id of the directory to encrypt into
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn export_locked(
+    vault: &CryptomatorVault,
+    top: &str,
+    data: &MasterfileData,
+    dir_id: &str,
+) -> Result<(), anyhow::Error> {
+    if data.settings.layout == flatstore::LAYOUT_FLAT {
+        return flatstore::walk(top, data, &dir_id.to_string(),
+            &mut |name, parent_id| vault.create_dir(name, parent_id),
+            &mut |name, parent_id, blob| encryptionFunctions::decrypt_file_streamed(blob, &data.master_key,
+                |_, source| vault.add_reader(name, parent_id, source)));
+    }
+    let folder_key = encryptionFunctions::folder_key(data)?;
+    export_dir(vault, top, data, folder_key.expose(), dir_id)
+}
+
+///
+/// Encrypt a directory of a locked vault with the tree layout into a
+/// directory of a Cryptomator vault.
+/// # Arguments
+/// - `vault: &CryptomatorVault`
+/// - `dir_path: &str`
+///     - Encrypted directory of the locked vault
+/// - `data: &MasterfileData`
+/// - `folder_key: &[u8]`
+/// - `dir_id: &str`
+///     - Id of the directory to encrypt into
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn export_dir(
+    vault: &CryptomatorVault,
+    dir_path: &str,
+    data: &MasterfileData,
+    folder_key: &[u8],
+    dir_id: &str,
+) -> Result<(), anyhow::Error> {
+    for path_inv in fs::read_dir(dir_path)? {
+        let path_inv = path_inv?;
+        let name = path_inv.file_name().to_string_lossy().to_string();
+        let path = format!("{}/{}", dir_path, name);
+        if !name.ends_with(".encrypted") {
+            continue;
+        }
+        if path_inv.file_type()?.is_dir() {
+            let foldername = encryptionFunctions::decrypt_foldername_with(&name, folder_key, data)?;
+            let child_id = vault.create_dir(&foldername, dir_id)?;
+            export_dir(vault, &path, data, folder_key, &child_id)?;
+        } else {
+            encryptionFunctions::decrypt_file_streamed(&path, &data.master_key,
+                |filename, source| vault.add_reader(filename, dir_id, source))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    // Path of the encrypted contents of a file in the root directory
+    fn file_path(vault: &CryptomatorVault, name: &str) -> String {
+        vault.list_dir("").unwrap().into_iter()
+            .find_map(|node| match node {
+                Node::File { name: node_name, path } if node_name == name => Some(path),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn vault_round_trip() {
+        let dir = testutil::test_dir("cryptomator-round-trip");
+        let source = format!("{}/source", dir);
+        let long_name = "x".repeat(200);
+        let big = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        fs::create_dir_all(format!("{}/sub/deeper", source)).unwrap();
+        fs::write(format!("{}/a.txt", source), "hello").unwrap();
+        fs::write(format!("{}/empty", source), "").unwrap();
+        fs::write(format!("{}/sub/big.bin", source), &big).unwrap();
+        fs::write(format!("{}/sub/deeper/{}", source, long_name), "long").unwrap();
+
+        // A symlink back up the tree is skipped instead of followed forever
+        std::os::unix::fs::symlink(&source, format!("{}/sub/loop", source)).unwrap();
+
+        let vault_path = format!("{}/vault", dir);
+        let vault = CryptomatorVault::create(&vault_path, "password").unwrap();
+        import_tree(&vault, &source, "").unwrap();
+        drop(vault);
+        assert!(CryptomatorVault::create(&vault_path, "password").is_err());
+        assert!(CryptomatorVault::open(&vault_path, "wrong").is_err());
+
+        let vault = CryptomatorVault::open(&vault_path, "password").unwrap();
+        let dist = format!("{}/dist", dir);
+        extract(&vault, "", &dist).unwrap();
+        assert_eq!(fs::read(format!("{}/a.txt", dist)).unwrap(), b"hello");
+        assert_eq!(fs::read(format!("{}/empty", dist)).unwrap(), b"");
+        assert_eq!(fs::read(format!("{}/sub/big.bin", dist)).unwrap(), big);
+        assert_eq!(fs::read(format!("{}/sub/deeper/{}", dist, long_name)).unwrap(), b"long");
+        assert!(!Path::new(&format!("{}/sub/loop", dist)).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tampered_config() {
+        let dir = testutil::test_dir("cryptomator-config");
+        CryptomatorVault::create(&dir, "password").unwrap();
+        let config_path = format!("{}/{}", dir, VAULT_FILE);
+        let token = fs::read_to_string(&config_path).unwrap();
+        let parts = token.trim().split('.').collect::<Vec<&str>>();
+
+        // Claims that do not match the signature are refused
+        let mut claims: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+        claims["shorteningThreshold"] = 10_000.into();
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        fs::write(&config_path, format!("{}.{}.{}", parts[0], claims, parts[2])).unwrap();
+        assert!(CryptomatorVault::open(&dir, "password").is_err());
+
+        fs::write(&config_path, format!("{}.{}", parts[0], parts[1])).unwrap();
+        assert!(CryptomatorVault::open(&dir, "password").is_err());
+
+        // So is a masterkey file with a version that does not match its MAC
+        fs::write(&config_path, &token).unwrap();
+        CryptomatorVault::open(&dir, "password").unwrap();
+        let masterkey_path = format!("{}/{}", dir, MASTERKEY_FILE);
+        let mut masterkey: MasterkeyFile = serde_json::from_slice(&fs::read(&masterkey_path).unwrap()).unwrap();
+        masterkey.version -= 1;
+        fs::write(&masterkey_path, serde_json::to_vec(&masterkey).unwrap()).unwrap();
+        assert!(CryptomatorVault::open(&dir, "password").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tampered_contents() {
+        let dir = testutil::test_dir("cryptomator-contents");
+        let vault = CryptomatorVault::create(&dir, "password").unwrap();
+        let contents = vec![3u8; CHUNK_LEN + 1000];
+        vault.add_reader("file", "", &mut &contents[..]).unwrap();
+        let path = file_path(&vault, "file");
+        let encrypted = fs::read(&path).unwrap();
+
+        let mut decrypted = Vec::new();
+        vault.decrypt_file(&path, &mut decrypted).unwrap();
+        assert_eq!(decrypted, contents);
+
+        // A flipped byte in the header, the first or the last chunk
+        for offset in [20, GCM_HEADER_LEN + 40, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[offset] ^= 1;
+            fs::write(&path, &tampered).unwrap();
+            assert!(vault.decrypt_file(&path, &mut Vec::new()).is_err(), "offset {}", offset);
+        }
+
+        // Cut off inside a chunk or inside the header
+        for len in [encrypted.len() - 10, GCM_HEADER_LEN + 10, 30] {
+            fs::write(&path, &encrypted[..len]).unwrap();
+            assert!(vault.decrypt_file(&path, &mut Vec::new()).is_err(), "length {}", len);
+        }
+
+        // Names are bound to their directory
+        let encrypted_name = vault.encrypt_name("file", "").unwrap();
+        assert_eq!(vault.decrypt_name(&encrypted_name, "").unwrap(), "file");
+        assert!(vault.decrypt_name(&encrypted_name, "other").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
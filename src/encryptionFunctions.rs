@@ -1,67 +1,187 @@
 // Import functions from external crates
 use anyhow::anyhow;
-use chacha20poly1305::{
-    aead::{stream, Aead, NewAead, generic_array::GenericArray},
-    XChaCha20Poly1305,
-};
-
 use rand::{rngs::OsRng, RngCore,};
+use sha2::{Digest, Sha256};
 use std::{
     fs,
     fs::File,
-    io::{Read, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    thread,
 };
 use zeroize::Zeroize;
 
 
 // Import functions from files
 use crate::{
+    cipher::CipherSuite,
     functions,
-    masterfile
+    masterfile,
+    padding,
+    secret::SecretBytes,
+    wipe,
 };
 
+// Folder names are encrypted with this suite whatever the suite of the
+// vault, since it stays safe when every name uses the same nonce
+const FOLDER_SUITE: CipherSuite = CipherSuite::Aes256GcmSiv;
+
 // Set buffer length variable
 const BUFFER_LEN: usize = 500;
 
+// Magic and version at the start of every encrypted file written with a header.
+// Files without the magic were written before headers existed and start with
+// the salt directly.
+const FILE_MAGIC: &[u8; 4] = b"RVFH";
+const FILE_VERSION: u8 = 2;
+
+// Headers of this version have no cipher suite byte and always use XChaCha20-Poly1305
+const FILE_VERSION_NO_SUITE: u8 = 1;
+
+// Filler appended to the stream nonce to get the nonce of the filename
+const FILENAME_NONCE_SUFFIX: &[u8; 5] = b"00000";
+
+// Bits of the header flags byte
+const FLAG_ZSTD: u8 = 0x01;
+const FLAG_PADDED: u8 = 0x02;
+
+// Amount of plaintext compressed to decide whether a file is worth compressing,
+// and the ratio the sample must beat
+const COMPRESSION_SAMPLE_LEN: usize = 64 * 1024;
+const COMPRESSION_MIN_RATIO: f64 = 0.9;
+
+// Extensions of formats that are already compressed and are never
+// compressed again
+const INCOMPRESSIBLE_EXTENSIONS: [&str; 24] = [
+    "jpg", "jpeg", "png", "gif", "webp", "heic",
+    "mp3", "aac", "ogg", "flac", "m4a", "opus",
+    "mp4", "m4v", "mkv", "mov", "avi", "webm",
+    "zip", "gz", "xz", "zst", "7z", "rar",
+];
+
+///
+/// Header written at the start of an encrypted file. The header is
+/// authenticated as associated data of every chunk of the stream.
+/// # Data
+/// - `flags: u8`
+///     - Bit flags describing how the plaintext was processed
+///         - 0x01: zstd compressed
+///         - 0x02: padded to hide the length
+/// - `version: u8`
+///     - Version of the header, version 1 headers have no suite
+/// - `suite: CipherSuite`
+///     - Cipher suite the file is encrypted with
+/// 
+struct FileHeader {
+    flags: u8,
+    version: u8,
+    suite: CipherSuite,
+}
+
+impl FileHeader {
+    ///
+    /// Serialize the header into the bytes written to the file
+    /// 
+    /// Returns `Vec<u8>`
+    /// 
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = FILE_MAGIC.to_vec();
+        bytes.push(self.version);
+        bytes.push(self.flags);
+        if self.version != FILE_VERSION_NO_SUITE {
+            bytes.push(self.suite.id());
+        }
+        bytes
+    }
+}
+
+///
+/// Read from the reader until the buffer is full or the end of the
+/// reader is reached. Compressing readers return short reads before the
+/// end, which would otherwise be mistaken for the last chunk.
+/// # Arguments
+/// - `reader: &mut R`
+///     - Reader to fill the buffer from
+/// - `buffer: &mut [u8]`
+///     - Buffer to fill
+/// 
+/// Returns `Result<usize, anyhow::Error>`
+/// 
+pub fn read_full<R: Read + ?Sized>(reader: &mut R, buffer: &mut [u8]) -> Result<usize, anyhow::Error> {
+    let mut total = 0;
+    while total < buffer.len() {
+        let read_count = reader.read(&mut buffer[total..])?;
+        if read_count == 0 {
+            break;
+        }
+        total += read_count;
+    }
+    Ok(total)
+}
+
+///
+/// Decide whether a file should be compressed before encryption. Files with
+/// the extension of an already compressed format are skipped, otherwise a
+/// sample from the start of the file is compressed and checked.
+/// # Arguments
+/// - `source_file_path: &str`
+///     - Path to the file
+/// - `source_file: &mut File`
+///     - Opened source file, rewound to the start afterwards
+/// 
+/// Returns `Result<bool, anyhow::Error>`
+/// 
+fn should_compress(source_file_path: &str, source_file: &mut File) -> Result<bool, anyhow::Error> {
+    if let Some(extension) = Path::new(source_file_path).extension() {
+        let extension = extension.to_string_lossy().to_lowercase();
+        if INCOMPRESSIBLE_EXTENSIONS.contains(&extension.as_str()) {
+            return Ok(false);
+        }
+    }
+
+    // Compress a sample of the file with the fastest level
+    let mut sample = vec![0u8; COMPRESSION_SAMPLE_LEN];
+    let read_count = read_full(source_file, &mut sample)?;
+    source_file.seek(SeekFrom::Start(0))?;
+    if read_count == 0 {
+        return Ok(false);
+    }
+    let compressed = zstd::bulk::compress(&sample[..read_count], 1)?;
+
+    Ok((compressed.len() as f64) < (read_count as f64) * COMPRESSION_MIN_RATIO)
+}
+
 ///
 /// Function to encrypt the filename. Will encrypt the filename and 
 /// return the new path to be used in file creation.
 /// # Arguments
 /// - `source_file_path: &str`
 ///     - Path to the original source file
+/// - `suite: CipherSuite`
+///     - Cipher suite of the file
 /// - `key: &Vec<u8>`
 ///     - Key that is generated in the calling function
-/// - `nonce: &[u8; 19]` 
-///     - Array of 19 bytes from the calling function
+/// - `nonce: &[u8]` 
+///     - Stream nonce of the file from the calling function
 /// 
 /// Returns `String`
 /// 
 pub fn encrypt_filename(
     source_file_path: &str,
+    suite: CipherSuite,
     key: &[u8],
-    nonce: &[u8; 19],
+    nonce: &[u8],
 ) -> String {
     // Add an extra 5 bytes to the end of the nonce for use in standalone encryption
-    let whole_nonce: [u8; 24] = {
-        let mut whole_nonce: [u8; 24] = [0; 24];
-        let (one, two) = whole_nonce.split_at_mut(nonce.len());
-        one.copy_from_slice(nonce);
-        two.copy_from_slice(b"00000");
-        whole_nonce
-    };
+    let whole_nonce = [nonce, FILENAME_NONCE_SUFFIX].concat();
 
     // Split the path and get the filename
     let mut split_path = source_file_path.split('/').collect::<Vec<&str>>();
     let path_size = split_path.len();
     let filename = split_path[path_size-1];
 
-    // Prepare the generic arrays and aead
-    let key_ga = GenericArray::clone_from_slice(key);
-    let nonce_ga = GenericArray::clone_from_slice(&whole_nonce[..]);
-    let aead = XChaCha20Poly1305::new(&key_ga);
-
     // Encrypt the filename
-    let encoded = aead.encrypt(&nonce_ga, filename.as_bytes().as_ref()).expect("Encryption failure");
+    let encoded = suite.seal(key, &whole_nonce, filename.as_bytes()).expect("Encryption failure");
 
     // Replace the filename in the split path
     let encoded_str = format!("{}.encrypted", hex::encode(encoded));
@@ -77,26 +197,23 @@ pub fn encrypt_filename(
 /// # Arguments
 /// - `encrypted_file_path: &str`
 ///     - Path to the original source file
-/// - `key: &Vec<u8>`
+/// - `suite: CipherSuite`
+///     - Cipher suite of the file
+/// - `key: &[u8]`
 ///     - Key that is generated in the calling function
-/// - `nonce: &[u8; 19]` 
-///     - Array of 19 bytes from the calling function
+/// - `nonce: &[u8]` 
+///     - Stream nonce of the file from the calling function
 /// 
-/// Returns `String`
+/// Returns `Result<String, anyhow::Error>`
 /// 
 pub fn decrypt_filename(
     encrypted_file_path: &str,
+    suite: CipherSuite,
     key: &[u8],
-    nonce: &[u8; 19],
-) -> String {
+    nonce: &[u8],
+) -> Result<String, anyhow::Error> {
     // Add an extra 5 bytes to the end of the nonce for use in standalone encryption
-    let whole_nonce: [u8; 24] = {
-        let mut whole_nonce: [u8; 24] = [0; 24];
-        let (one, two) = whole_nonce.split_at_mut(nonce.len());
-        one.copy_from_slice(nonce);
-        two.copy_from_slice(b"00000");
-        whole_nonce
-    };
+    let whole_nonce = [nonce, FILENAME_NONCE_SUFFIX].concat();
 
     // Split the path and get the filename
     let mut split_path = encrypted_file_path.split('/').collect::<Vec<&str>>();
@@ -104,183 +221,714 @@ pub fn decrypt_filename(
     let mut encrypted_filename = String::from(split_path[path_size-1]);
     
     // Remove the '.encrypted' from the end of the filename
-    encrypted_filename = encrypted_filename.strip_suffix(".encrypted").unwrap().to_string();
+    encrypted_filename = encrypted_filename.strip_suffix(".encrypted")
+        .ok_or_else(|| anyhow!("{} is not an encrypted file.", encrypted_file_path))?
+        .to_string();
 
     // Get the bytes to decode
     // TODO: Consider changing to simple .to_bytes
-    let to_decrypt = hex::decode(encrypted_filename).unwrap();
-
-    // Prepare generic arrays and aead
-    let key_ga = GenericArray::clone_from_slice(key);
-    let nonce_ga = GenericArray::clone_from_slice(&whole_nonce[..]);
-    let aead = XChaCha20Poly1305::new(&key_ga);
+    let to_decrypt = hex::decode(encrypted_filename)?;
 
     // Decrypt the filename
-    let decoded = aead.decrypt(&nonce_ga, to_decrypt.as_ref()).expect("Encryption failure");
+    let decoded = suite.open(key, &whole_nonce, &to_decrypt)
+        .map_err(|_| anyhow!("Could not decrypt filename."))?;
 
     // Replace the encrypted filename in the split path
-    let decoded_str = std::str::from_utf8(&decoded).unwrap();
+    let decoded_str = std::str::from_utf8(&decoded)?;
     split_path[path_size-1] = decoded_str;
 
     // Join the path and return
-    split_path.join("/")
+    Ok(split_path.join("/"))
 }
 
+///
+/// Define struct to hold the key of a single encrypted file. The salt and nonce
+/// are stored in the file, the key is derived from them and the master key.
+/// The key material is zeroized when dropped.
+/// # Data
+/// - `salt: [u8; 32]`
+/// - `nonce: Vec<u8>`
+///     - Stream nonce, its length depends on the suite
+/// - `key: Vec<u8>`
+/// - `suite: CipherSuite`
 /// 
-/// Function called to encrypt a file. Will create a new file with an encrypted filename
-/// and stream encrypt data into the new file. Will also store the nonce and salt used for 
-/// encryption in the file to be used later during decryption.
+struct FileKey {
+    salt: [u8; 32],
+    nonce: Vec<u8>,
+    key: Vec<u8>,
+    suite: CipherSuite,
+}
+
+impl FileKey {
+    ///
+    /// Create a key for a new file with a random salt and nonce
+    /// # Arguments
+    /// - `password: &[u8; 32]`
+    ///     - Master key taken from the decrypted masterfile
+    /// - `suite: CipherSuite`
+    ///     - Cipher suite to encrypt the file with
+    /// 
+    /// Returns `Result<FileKey, anyhow::Error>`
+    /// 
+    fn generate(password: &[u8; 32], suite: CipherSuite) -> Result<FileKey, anyhow::Error> {
+        // Create and fill byte arrays for the salt and nonce
+        let mut salt = [0u8; 32];
+        let mut nonce = vec![0u8; suite.stream_nonce_len()];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        FileKey::derive(password, salt, nonce, suite)
+    }
+
+    ///
+    /// Derive the key of a file from its salt and nonce
+    /// # Arguments
+    /// - `password: &[u8; 32]`
+    ///     - Master key taken from the decrypted masterfile
+    /// - `salt: [u8; 32]`
+    /// - `nonce: Vec<u8>`
+    /// - `suite: CipherSuite`
+    /// 
+    /// Returns `Result<FileKey, anyhow::Error>`
+    /// 
+    fn derive(
+        password: &[u8; 32],
+        salt: [u8; 32],
+        nonce: Vec<u8>,
+        suite: CipherSuite,
+    ) -> Result<FileKey, anyhow::Error> {
+        // Get the key from the hashed password using the salt
+        let argon2_config = functions::argon2_config();
+        let key = argon2::hash_raw(password, &salt, &argon2_config)?;
+        Ok(FileKey { salt, nonce, key, suite })
+    }
+}
+
+impl Drop for FileKey {
+    fn drop(&mut self) {
+        self.salt.zeroize();
+        self.nonce.zeroize();
+        self.key.zeroize();
+    }
+}
+
+///
+/// Wrap a source reader in a compressing reader and a padding reader
+/// as required by the vault settings, and build the matching header.
 /// # Arguments
-/// - `source_file_path: &str`
-///     - Path to the original file
-/// - `password: &[u8; 32]` 
-///     - Array of bytes to be used as the password for encryption.
-///       Taken from the decrypted masterfile.
+/// - `source: Box<dyn Read>`
+///     - Reader producing the plaintext
+/// - `compress: bool`
+///     - Whether the plaintext should be compressed
+/// - `settings: &masterfile::VaultSettings`
+///     - Settings of the vault
 /// 
-/// Returns `Result<(), anyhow::Error>`
+/// Returns `Result<(Box<dyn Read>, FileHeader), anyhow::Error>`
 /// 
-pub fn encrypt_file(
-    source_file_path: &str,
-    password: &[u8; 32],
-) -> Result<(), anyhow::Error> {
-    // Get config for the password hashing
-    let argon2_config = functions::argon2_config();
-
-    // Create and fill byte arrays for the salt and nonce
-    let mut salt = [0u8; 32];
-    let mut nonce = [0u8; 19];
-    OsRng.fill_bytes(&mut salt);
-    OsRng.fill_bytes(&mut nonce);
+fn wrap_source<'a>(
+    source: Box<dyn Read + 'a>,
+    compress: bool,
+    settings: &masterfile::VaultSettings,
+) -> Result<(Box<dyn Read + 'a>, FileHeader), anyhow::Error> {
+    let mut header = FileHeader { flags: 0, version: FILE_VERSION, suite: settings.cipher };
+    let mut source = source;
 
-    // Get the key from the hashed password using the randomly
-    // created salt
-    let mut key = argon2::hash_raw(password, &salt, &argon2_config)?;
+    // Compress the plaintext if needed
+    if compress {
+        header.flags |= FLAG_ZSTD;
+        source = Box::new(zstd::stream::read::Encoder::new(source, settings.compression as i32)?);
+    }
 
-    // Create the aead and stream cypher using the key
-    let aead = XChaCha20Poly1305::new(key[..32].as_ref().into());
-    let mut stream_encryptor = stream::EncryptorBE32::from_aead(aead, nonce.as_ref().into());
+    // Pad the compressed data inside the authenticated stream
+    if settings.padding != padding::PADDING_NONE {
+        header.flags |= FLAG_PADDED;
+        source = Box::new(padding::PaddingReader::new(source, settings.padding));
+    }
+    Ok((source, header))
+}
 
-    // Open the source file and create the dist file with the 
-    // encrypted filename from the function call
-    let mut source_file = File::open(source_file_path)?;
-    let mut dist_file = File::create(encrypt_filename(source_file_path, &key, &nonce))?;
+///
+/// Write the header, salt and nonce followed by the encrypted stream
+/// of the source into the dist writer.
+/// # Arguments
+/// - `source: &mut dyn Read`
+///     - Reader producing the (compressed and padded) plaintext
+/// - `dist: &mut dyn Write`
+///     - Writer receiving the encrypted file
+/// - `file_key: &FileKey`
+///     - Key of the file
+/// - `header: &FileHeader`
+///     - Header to write and authenticate
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn write_encrypted(
+    source: &mut dyn Read,
+    dist: &mut dyn Write,
+    file_key: &FileKey,
+    header: &FileHeader,
+) -> Result<(), anyhow::Error> {
+    // Create the stream cypher of the file's suite using the key
+    let mut stream_encryptor = file_key.suite.stream_encryptor(&file_key.key[..32], &file_key.nonce)?;
 
-    // Write the salt and nonce in the dist file
-    dist_file.write_all(&salt)?;
-    dist_file.write_all(&nonce)?;
+    // Write the header, salt, and nonce in the dist file
+    let header_bytes = header.to_bytes();
+    dist.write_all(&header_bytes)?;
+    dist.write_all(&file_key.salt)?;
+    dist.write_all(&file_key.nonce)?;
     
     let mut buffer = [0u8; BUFFER_LEN];
 
     // Loop through the source file, encrypt the data, and write
     // to the dist file until completion
     loop {
-        let read_count = source_file.read(&mut buffer)?;
+        let read_count = read_full(source, &mut buffer)?;
 
         if read_count == BUFFER_LEN {
             let ciphertext = stream_encryptor
-                .encrypt_next(buffer.as_slice())
+                .encrypt_next(buffer.as_slice(), &header_bytes)
                 .map_err(|err| anyhow!("Encrypting large file: {}", err))?;
-            dist_file.write_all(&ciphertext)?;
+            dist.write_all(&ciphertext)?;
         } else {
             let ciphertext = stream_encryptor
-                .encrypt_last(&buffer[..read_count])
+                .encrypt_last(&buffer[..read_count], &header_bytes)
                 .map_err(|err| anyhow!("Encrypting large file: {}", err))?;
-            dist_file.write_all(&ciphertext)?;
+            dist.write_all(&ciphertext)?;
             break;
         }
     }
-
-    // Delete the source file and zerioize sensitive variables in memory
-    fs::remove_file(source_file_path)?;
-    salt.zeroize();
-    nonce.zeroize();
-    key.zeroize();
-
     Ok(())
 }
 
 ///
-/// Function for decrypting a file. Will decrypt the filename with the salt and nonce
-/// stored in the encrypted file, then stream decrypt into the destination file.
+/// Function called to encrypt a file. Will create a new file with an encrypted filename
+/// and stream encrypt data into the new file. Will also store the nonce and salt used for 
+/// encryption in the file to be used later during decryption.
 /// # Arguments
-/// - `encrypted_file_path: &str`
-///     - Path to the encrypted file
+/// - `source_file_path: &str`
+///     - Path to the original file
 /// - `password: &[u8; 32]` 
 ///     - Array of bytes to be used as the password for encryption.
 ///       Taken from the decrypted masterfile.
+/// - `settings: &masterfile::VaultSettings`
+///     - Settings of the vault, used to decide on compression and padding
 /// 
 /// Returns `Result<(), anyhow::Error>`
 /// 
-pub fn decrypt_file(
-    encrypted_file_path: &str,
+pub fn encrypt_file(
+    source_file_path: &str,
+    password: &[u8; 32],
+    settings: &masterfile::VaultSettings,
+) -> Result<(), anyhow::Error> {
+    // Create the key and encrypt into a file named with the encrypted filename
+    let file_key = FileKey::generate(password, settings.cipher)?;
+    let dist_file_path = encrypt_filename(source_file_path, file_key.suite, &file_key.key, &file_key.nonce);
+    encrypt_with_key(source_file_path, &dist_file_path, &file_key, settings)?;
+
+    // Delete the source file
+    wipe::remove_plaintext(source_file_path)?;
+
+    Ok(())
+}
+
+///
+/// Encrypt a file into the given dist path, leaving the source file in place.
+/// # Arguments
+/// - `source_file_path: &str`
+///     - Path to the original file
+/// - `dist_file_path: &str`
+///     - Path of the encrypted file to create
+/// - `password: &[u8; 32]`
+///     - Master key taken from the decrypted masterfile
+/// - `settings: &masterfile::VaultSettings`
+///     - Settings of the vault, used to decide on compression and padding
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn encrypt_file_to(
+    source_file_path: &str,
+    dist_file_path: &str,
     password: &[u8; 32],
+    settings: &masterfile::VaultSettings,
 ) -> Result<(), anyhow::Error> {
-    // Instantiate arrays for salt and nonce
+    let file_key = FileKey::generate(password, settings.cipher)?;
+    encrypt_with_key(source_file_path, dist_file_path, &file_key, settings)
+}
+
+///
+/// Encrypt a file into another directory under an encrypted filename,
+/// leaving the source file in place.
+/// # Arguments
+/// - `source_file_path: &str`
+///     - Path to the original file
+/// - `dist_dir_path: &str`
+///     - Directory to create the encrypted file in
+/// - `password: &[u8; 32]`
+///     - Master key taken from the decrypted masterfile
+/// - `settings: &masterfile::VaultSettings`
+///     - Settings of the vault, used to decide on compression and padding
+/// 
+/// Returns `Result<String, anyhow::Error>`, the path of the encrypted file
+/// 
+pub fn encrypt_file_into(
+    source_file_path: &str,
+    dist_dir_path: &str,
+    password: &[u8; 32],
+    settings: &masterfile::VaultSettings,
+) -> Result<String, anyhow::Error> {
+    let filename = Path::new(source_file_path).file_name()
+        .and_then(|filename| filename.to_str())
+        .ok_or_else(|| anyhow!("Invalid path {}.", source_file_path))?;
+    let file_key = FileKey::generate(password, settings.cipher)?;
+    let dist_file_path = encrypt_filename(&format!("{}/{}", dist_dir_path, filename),
+        file_key.suite, &file_key.key, &file_key.nonce);
+    encrypt_with_key(source_file_path, &dist_file_path, &file_key, settings)?;
+    Ok(dist_file_path)
+}
+
+///
+/// Encrypt a file with an already generated key into the dist path.
+/// # Arguments
+/// - `source_file_path: &str`
+/// - `dist_file_path: &str`
+/// - `file_key: &FileKey`
+/// - `settings: &masterfile::VaultSettings`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn encrypt_with_key(
+    source_file_path: &str,
+    dist_file_path: &str,
+    file_key: &FileKey,
+    settings: &masterfile::VaultSettings,
+) -> Result<(), anyhow::Error> {
+    // Open the source file and decide on compression
+    let mut source_file = File::open(source_file_path)?;
+    let compress = settings.compression != 0
+        && should_compress(source_file_path, &mut source_file)?;
+    let (mut source, header) = wrap_source(Box::new(source_file), compress, settings)?;
+
+    // Create the dist file and encrypt into it
+    let mut dist_file = File::create(dist_file_path)?;
+    write_encrypted(&mut source, &mut dist_file, file_key, &header)
+}
+
+///
+/// Encrypt data held in memory into the given dist path.
+/// # Arguments
+/// - `data: &[u8]`
+///     - Plaintext to encrypt
+/// - `dist_file_path: &str`
+///     - Path of the encrypted file to create
+/// - `password: &[u8; 32]`
+///     - Master key taken from the decrypted masterfile
+/// - `settings: &masterfile::VaultSettings`
+///     - Settings of the vault
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn encrypt_data_to(
+    data: &[u8],
+    dist_file_path: &str,
+    password: &[u8; 32],
+    settings: &masterfile::VaultSettings,
+) -> Result<(), anyhow::Error> {
+    let file_key = FileKey::generate(password, settings.cipher)?;
+    let (mut source, header) = wrap_source(Box::new(data), settings.compression != 0, settings)?;
+    let mut dist_file = File::create(dist_file_path)?;
+    write_encrypted(&mut source, &mut dist_file, &file_key, &header)
+}
+
+///
+/// Read the header, salt and nonce at the start of an encrypted file and
+/// derive the key of the file.
+/// # Arguments
+/// - `encrypted_file: &mut File`
+///     - Encrypted file, left positioned at the start of the stream
+/// - `password: &[u8; 32]`
+///     - Master key taken from the decrypted masterfile
+/// 
+/// Returns `Result<(FileKey, Option<FileHeader>), anyhow::Error>`
+/// 
+fn read_file_key(
+    encrypted_file: &mut File,
+    password: &[u8; 32],
+) -> Result<(FileKey, Option<FileHeader>), anyhow::Error> {
+    // Instantiate the array for the salt
     let mut salt = [0u8; 32];
-    let mut nonce = [0u8; 19];
 
-    // Open the encrypted file
-    let mut encrypted_file = File::open(encrypted_file_path)?;
-    
+    // Read the header if present. Files without the magic start with the salt
+    // and were never compressed or padded
+    let mut magic = [0u8; 4];
+    encrypted_file.read_exact(&mut magic)?;
+    let mut header = None;
+    if &magic == FILE_MAGIC {
+        let mut version_flags = [0u8; 2];
+        encrypted_file.read_exact(&mut version_flags)?;
+        let suite = match version_flags[0] {
+            FILE_VERSION_NO_SUITE => CipherSuite::default(),
+            FILE_VERSION => {
+                let mut suite_id = [0u8; 1];
+                encrypted_file.read_exact(&mut suite_id)?;
+                CipherSuite::from_id(suite_id[0])?
+            },
+            version => return Err(anyhow!("Unsupported file version {}.", version)),
+        };
+        header = Some(FileHeader { flags: version_flags[1], version: version_flags[0], suite });
+        encrypted_file.read_exact(&mut salt[..4])?;
+    } else {
+        salt[..4].copy_from_slice(&magic);
+    }
+
     // Read the salt and nonce
-    let mut read_count = encrypted_file.read(&mut salt)?;
-    if read_count != salt.len() {
+    let mut read_count = encrypted_file.read(&mut salt[4..])?;
+    if read_count != salt.len() - 4 {
         return Err(anyhow!("Error reading salt."));
     }
 
+    // The length of the nonce depends on the suite of the file
+    let suite = header.as_ref().map(|header| header.suite).unwrap_or_default();
+    let mut nonce = vec![0u8; suite.stream_nonce_len()];
     read_count = encrypted_file.read(&mut nonce)?;
     if read_count != nonce.len() {
         return Err(anyhow!("Error reading nonce."));
     }
 
-    // Get config for the password hashing
-    let argon2_config = functions::argon2_config();
-
     // Make key from config, salt, and password
-    let mut key = argon2::hash_raw(password, &salt, &argon2_config)?;
+    let file_key = FileKey::derive(password, salt, nonce, suite)?;
+    salt.zeroize();
+    Ok((file_key, header))
+}
+
+///
+/// Stream decrypt the rest of an encrypted file into the dist writer.
+/// # Arguments
+/// - `encrypted_file: &mut File`
+///     - Encrypted file positioned at the start of the stream
+/// - `file_key: &FileKey`
+///     - Key of the file
+/// - `header: &Option<FileHeader>`
+///     - Header of the file, `None` for files written before headers existed
+/// - `dist: W`
+///     - Writer receiving the plaintext
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn write_decrypted<W: Write>(
+    encrypted_file: &mut File,
+    file_key: &FileKey,
+    header: &Option<FileHeader>,
+    dist: W,
+) -> Result<(), anyhow::Error> {
+    let flags = header.as_ref().map(|header| header.flags).unwrap_or(0);
+    let header_bytes = header.as_ref().map(|header| header.to_bytes()).unwrap_or_default();
+
+    // Wrap the dist in a decompressing writer if the file was compressed
+    let mut dist: Box<dyn Write + '_> = if flags & FLAG_ZSTD != 0 {
+        Box::new(zstd::stream::write::Decoder::new(dist)?)
+    } else {
+        Box::new(dist)
+    };
 
-    // Prepare aead and decryptor
-    let aead = XChaCha20Poly1305::new(key[..32].as_ref().into());
-    let mut stream_decryptor = stream::DecryptorBE32::from_aead(aead, nonce.as_ref().into());
+    // And in a padding stripper if the file was padded, which checks that
+    // the padding is there once the stream ends
+    if flags & FLAG_PADDED != 0 {
+        let mut stripper = padding::PaddingStripper::new(dist);
+        decrypt_stream(encrypted_file, file_key, &header_bytes, &mut stripper)?;
+        dist = stripper.finish()?;
+    } else {
+        decrypt_stream(encrypted_file, file_key, &header_bytes, &mut dist)?;
+    }
+    dist.flush()?;
+    Ok(())
+}
+
+///
+/// Decrypt the chunks of an encrypted file up to and including the last one.
+/// # Arguments
+/// - `encrypted_file: &mut File`
+///     - Encrypted file positioned at the start of the stream
+/// - `file_key: &FileKey`
+///     - Key of the file
+/// - `header_bytes: &[u8]`
+///     - Header of the file, authenticated with every chunk
+/// - `dist: &mut dyn Write`
+///     - Writer receiving the plaintext
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn decrypt_stream(
+    encrypted_file: &mut File,
+    file_key: &FileKey,
+    header_bytes: &[u8],
+    dist: &mut dyn Write,
+) -> Result<(), anyhow::Error> {
+    // Prepare the decryptor of the file's suite
+    let mut stream_decryptor = file_key.suite.stream_decryptor(&file_key.key[..32], &file_key.nonce)?;
 
     // Add 16 bytes to the buffer length because aead stream encryptors
     // add an extra 16 bytes to the file
     let mut buffer = [0u8; BUFFER_LEN+16];
 
-    // Create the dist file with the decrypted filename function call
-    let mut dist_file = File::create(decrypt_filename(encrypted_file_path, &key, &nonce))?;
-
     // Read bytes from the encrypted file, decrypt, and write to destination file
     loop {
-        let read_count = encrypted_file.read(&mut buffer)?;
+        let read_count = read_full(encrypted_file, &mut buffer)?;
 
         if read_count == BUFFER_LEN+16 {
             let plaintext = stream_decryptor
-                .decrypt_next(buffer.as_slice())
+                .decrypt_next(buffer.as_slice(), header_bytes)
                 .map_err(|err| anyhow!("Decrypting large file: {}", err))?;
-            dist_file.write_all(&plaintext)?;
+            dist.write_all(&plaintext)?;
         } 
+        // Every file ends with a last chunk of at least the tag, so the
+        // stream was cut off at a chunk boundary
         else if read_count == 0 {
-            break;
+            return Err(anyhow!("Encrypted file is cut short."));
         }
         else {
             let plaintext = stream_decryptor
-                .decrypt_last(&buffer[..read_count])
+                .decrypt_last(&buffer[..read_count], header_bytes)
                 .map_err(|err| anyhow!("Decrypting large file: {}", err))?;
-            dist_file.write_all(&plaintext)?;
-            break;
+            dist.write_all(&plaintext)?;
+            return Ok(());
         }
     }
+}
 
-    // Remove the encrypted file and zeroize sensitive variables in memory
+///
+/// Function for decrypting a file. Will decrypt the filename with the salt and nonce
+/// stored in the encrypted file, then stream decrypt into the destination file.
+/// # Arguments
+/// - `encrypted_file_path: &str`
+///     - Path to the encrypted file
+/// - `password: &[u8; 32]` 
+///     - Array of bytes to be used as the password for encryption.
+///       Taken from the decrypted masterfile.
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn decrypt_file(
+    encrypted_file_path: &str,
+    password: &[u8; 32],
+) -> Result<(), anyhow::Error> {
+    // Open the encrypted file and read the key
+    let mut encrypted_file = File::open(encrypted_file_path)?;
+    let (file_key, header) = read_file_key(&mut encrypted_file, password)?;
+
+    // Create the dist file with the decrypted filename function call
+    let dist_file = File::create(decrypt_filename(encrypted_file_path, file_key.suite, &file_key.key, &file_key.nonce)?)?;
+    write_decrypted(&mut encrypted_file, &file_key, &header, dist_file)?;
+
+    // Remove the encrypted file
     fs::remove_file(encrypted_file_path)?;
-    salt.zeroize();
-    nonce.zeroize();
-    key.zeroize();
 
     Ok(())
 }
 
+///
+/// Decrypt the contents of an encrypted file into the given writer, leaving
+/// the encrypted file in place.
+/// # Arguments
+/// - `encrypted_file_path: &str`
+///     - Path to the encrypted file
+/// - `dist: W`
+///     - Writer receiving the plaintext
+/// - `password: &[u8; 32]`
+///     - Master key taken from the decrypted masterfile
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn decrypt_file_into<W: Write>(
+    encrypted_file_path: &str,
+    dist: W,
+    password: &[u8; 32],
+) -> Result<(), anyhow::Error> {
+    let mut encrypted_file = File::open(encrypted_file_path)?;
+    let (file_key, header) = read_file_key(&mut encrypted_file, password)?;
+    write_decrypted(&mut encrypted_file, &file_key, &header, dist)
+}
+
+///
+/// Decrypt a file and hand its plaintext to a reader, so it can be
+/// processed without being written anywhere. The decryption runs on its own
+/// thread while the reader is consumed on this one.
+/// # Arguments
+/// - `encrypted_file_path: &str`
+///     - Path to the encrypted file
+/// - `password: &[u8; 32]`
+///     - Master key taken from the decrypted masterfile
+/// - `consume: impl FnOnce(&str, &mut dyn Read) -> Result<T, anyhow::Error>`
+///     - Called with the decrypted filename, or the name of the file itself
+///       when its name is not encrypted, and the plaintext
+/// 
+/// Returns `Result<T, anyhow::Error>`, the result of `consume`
+/// 
+pub fn decrypt_file_streamed<T>(
+    encrypted_file_path: &str,
+    password: &[u8; 32],
+    consume: impl FnOnce(&str, &mut dyn Read) -> Result<T, anyhow::Error>,
+) -> Result<T, anyhow::Error> {
+    let mut encrypted_file = File::open(encrypted_file_path)?;
+    let (file_key, header) = read_file_key(&mut encrypted_file, password)?;
+    let filename = decrypt_filename(encrypted_file_path, file_key.suite, &file_key.key, &file_key.nonce)
+        .unwrap_or_else(|_| encrypted_file_path.to_string());
+    let filename = Path::new(&filename).file_name()
+        .map(|filename| filename.to_string_lossy().to_string())
+        .ok_or_else(|| anyhow!("Invalid path {}.", encrypted_file_path))?;
+
+    let (mut pipe_reader, pipe_writer) = io::pipe()?;
+    thread::scope(|scope| {
+        let decryptor = scope.spawn(move ||
+            write_decrypted(&mut encrypted_file, &file_key, &header, pipe_writer));
+
+        // Close the reader before waiting, a decryptor still writing
+        // to it then stops instead of blocking
+        let result = consume(&filename, &mut pipe_reader);
+        drop(pipe_reader);
+        let decrypted = decryptor.join()
+            .map_err(|_| anyhow!("Decrypting {} failed.", encrypted_file_path))?;
+        let result = result?;
+        decrypted?;
+        Ok(result)
+    })
+}
+
+///
+/// Decrypt a file into another directory under its decrypted filename,
+/// leaving the encrypted file in place.
+/// # Arguments
+/// - `encrypted_file_path: &str`
+///     - Path to the encrypted file
+/// - `dist_dir_path: &str`
+///     - Directory to create the plaintext file in
+/// - `password: &[u8; 32]`
+///     - Master key taken from the decrypted masterfile
+/// 
+/// Returns `Result<String, anyhow::Error>`, the path of the plaintext file
+/// 
+pub fn decrypt_file_into_dir(
+    encrypted_file_path: &str,
+    dist_dir_path: &str,
+    password: &[u8; 32],
+) -> Result<String, anyhow::Error> {
+    let mut encrypted_file = File::open(encrypted_file_path)?;
+    let (file_key, header) = read_file_key(&mut encrypted_file, password)?;
+    let filename = Path::new(encrypted_file_path).file_name()
+        .and_then(|filename| filename.to_str())
+        .ok_or_else(|| anyhow!("Invalid path {}.", encrypted_file_path))?;
+    let dist_file_path = decrypt_filename(&format!("{}/{}", dist_dir_path, filename),
+        file_key.suite, &file_key.key, &file_key.nonce)?;
+    let dist_file = File::create(&dist_file_path)?;
+    write_decrypted(&mut encrypted_file, &file_key, &header, dist_file)?;
+    Ok(dist_file_path)
+}
+
+///
+/// Get the part of the folder nonce used for folder names.
+/// # Arguments
+/// - `data: &masterfile::MasterfileData`
+/// 
+/// Returns `&[u8]`
+/// 
+fn folder_nonce(data: &masterfile::MasterfileData) -> &[u8] {
+    &data.folder_nonce[..FOLDER_SUITE.nonce_len()]
+}
+
+///
+/// Encrypt a folder name. Every name of a vault is encrypted under the same
+/// key and nonce, so that the same name always gives the same encrypted
+/// name, which only a nonce misuse resistant suite allows safely.
+/// # Arguments
+/// - `foldername: &[u8]`
+/// - `key: &[u8]`
+///     - Key from `folder_key`
+/// - `data: &masterfile::MasterfileData`
+/// 
+/// Returns `Result<Vec<u8>, anyhow::Error>`
+/// 
+fn seal_foldername(
+    foldername: &[u8],
+    key: &[u8],
+    data: &masterfile::MasterfileData,
+) -> Result<Vec<u8>, anyhow::Error> {
+    FOLDER_SUITE.seal(key, folder_nonce(data), foldername)
+        .map_err(|err| anyhow!("Encrypting foldername: {}", err))
+}
+
+///
+/// Decrypt a folder name. Names written before folder names had their own
+/// suite were encrypted with the suite of the vault and are still read.
+/// # Arguments
+/// - `encrypted: &[u8]`
+/// - `key: &[u8]`
+///     - Key from `folder_key`
+/// - `data: &masterfile::MasterfileData`
+/// 
+/// Returns `Result<Vec<u8>, anyhow::Error>`
+/// 
+fn open_foldername(
+    encrypted: &[u8],
+    key: &[u8],
+    data: &masterfile::MasterfileData,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let suite = data.settings.cipher;
+    FOLDER_SUITE.open(key, folder_nonce(data), encrypted)
+        .or_else(|_| suite.open(key, &data.folder_nonce[..suite.nonce_len()], encrypted))
+        .map_err(|_| anyhow!("Could not decrypt foldername."))
+}
+
+///
+/// Derive the key folder names are encrypted with.
+/// # Arguments
+/// - `data: &masterfile::MasterfileData`
+/// 
+/// Returns `Result<SecretBytes, anyhow::Error>`
+/// 
+pub fn folder_key(data: &masterfile::MasterfileData) -> Result<SecretBytes, anyhow::Error> {
+    Ok(SecretBytes::new(argon2::hash_raw(&data.master_key, &data.folder_salt, &functions::argon2_config())?))
+}
+
+///
+/// Encrypt a single foldername. The same name always gives the same
+/// encrypted name within a vault.
+/// # Arguments
+/// - `foldername: &str`
+/// - `key: &[u8]`
+///     - Key from `folder_key`
+/// - `data: &masterfile::MasterfileData`
+/// 
+/// Returns `String`
+/// 
+pub fn encrypt_foldername_with(
+    foldername: &str,
+    key: &[u8],
+    data: &masterfile::MasterfileData,
+) -> String {
+    let encoded = seal_foldername(foldername.as_bytes(), key, data)
+        .expect("Encryption failure");
+    format!("{}.encrypted", hex::encode(encoded))
+}
+
+///
+/// Decrypt a single foldername encrypted with `encrypt_foldername_with`.
+/// # Arguments
+/// - `encrypted_foldername: &str`
+/// - `key: &[u8]`
+///     - Key from `folder_key`
+/// - `data: &masterfile::MasterfileData`
+/// 
+/// Returns `Result<String, anyhow::Error>`
+/// 
+pub fn decrypt_foldername_with(
+    encrypted_foldername: &str,
+    key: &[u8],
+    data: &masterfile::MasterfileData,
+) -> Result<String, anyhow::Error> {
+    let encoded = encrypted_foldername.strip_suffix(".encrypted")
+        .ok_or_else(|| anyhow!("{} is not an encrypted folder.", encrypted_foldername))?;
+    let decoded = open_foldername(&hex::decode(encoded)?, key, data)?;
+    Ok(String::from_utf8(decoded)?)
+}
+
 ///
 /// Function for encrypting the foldername. Will use the password, folder_nonce,
 /// and folder_salt from the masterfile data structure to encrypt the foldername.
@@ -296,9 +944,8 @@ pub fn encrypt_foldername(
     source_path: &str, 
     data: &masterfile::MasterfileData,
 ) -> Result<(), anyhow::Error> {
-    // Create the hashing config and create the key
-    let argon2_config = functions::argon2_config();
-    let key = argon2::hash_raw(&data.master_key, &data.folder_salt, &argon2_config)?;
+    // Create the key
+    let key = folder_key(data)?;
 
     // Split the path and get the foldername
     let mut split_path = source_path.split('/').collect::<Vec<&str>>();
@@ -308,16 +955,10 @@ pub fn encrypt_foldername(
         foldername = split_path[path_size-2];
     }
 
-    // Prepare the generic arrays and aead
-    let key_ga = GenericArray::clone_from_slice(&key[..]);
-    let nonce_ga = GenericArray::clone_from_slice(&data.folder_nonce[..]);
-    let aead = XChaCha20Poly1305::new(&key_ga);
-
     // Encrypt the foldername
-    let encoded = aead.encrypt(&nonce_ga, foldername.as_bytes().as_ref()).expect("Encryption failure");
+    let encoded_str = encrypt_foldername_with(foldername, key.expose(), data);
 
     // Replace the foldername in the split path
-    let encoded_str = format!("{}.encrypted", hex::encode(encoded));
     if split_path[path_size-1].is_empty(){
         split_path[path_size-2] = &encoded_str;
     } else {
@@ -346,9 +987,8 @@ pub fn decrypt_foldername(
     encrypted_path: &str,
     data: &masterfile::MasterfileData,
 ) -> Result<(), anyhow::Error> {
-    // Create the hashing config and the key
-    let argon2_config = functions::argon2_config();
-    let key = argon2::hash_raw(&data.master_key, &data.folder_salt, &argon2_config)?;
+    // Create the key
+    let key = folder_key(data)?;
 
     // Split the path and get the encrypted foldername
     let mut split_path = encrypted_path.split('/').collect::<Vec<&str>>();
@@ -360,20 +1000,17 @@ pub fn decrypt_foldername(
 
     // Remove the '.encrypted' from the foldername
     encrypted_foldername = encrypted_foldername
-        .strip_suffix(".encrypted").unwrap().to_string();
+        .strip_suffix(".encrypted")
+        .ok_or_else(|| anyhow!("{} is not an encrypted folder.", encrypted_path))?
+        .to_string();
 
     // Get bytes from encrypted foldername
     // TODO: Consider replacing with .to_bytes
-    let to_decrypt = hex::decode(encrypted_foldername).unwrap();
-
-    // Prepare the generic arrays and aead
-    let key_ga = GenericArray::clone_from_slice(&key[..]);
-    let nonce_ga = GenericArray::clone_from_slice(&data.folder_nonce[..]);
-    let aead = XChaCha20Poly1305::new(&key_ga);
+    let to_decrypt = hex::decode(encrypted_foldername)?;
 
     // Decode the foldername and convert to utf8
-    let decoded = aead.decrypt(&nonce_ga, to_decrypt.as_ref()).expect("Encryption failure");
-    let decoded_str = std::str::from_utf8(&decoded).unwrap();
+    let decoded = open_foldername(&to_decrypt, key.expose(), data)?;
+    let decoded_str = std::str::from_utf8(&decoded)?;
 
     // Replace the encrypted foldername in the split path
     if split_path[path_size-1].is_empty(){
@@ -387,3 +1024,292 @@ pub fn decrypt_foldername(
     fs::rename(encrypted_path, dist_path)?;
     Ok(())
 }
+
+///
+/// Re-encrypt a file from the old master key to the new one. The new
+/// ciphertext is written to the temp path and decrypted again to check
+/// that it holds the same plaintext before this returns. The old file is
+/// left untouched.
+/// # Arguments
+/// - `encrypted_file_path: &str`
+///     - Path to the file encrypted with the old master key
+/// - `temp_file_path: &str`
+///     - Path to write the new ciphertext to
+/// - `dist_file_path: Option<&str>`
+///     - Final path of the new file. When `None` the filename is
+///       encrypted again with the new key next to the old file
+/// - `old_password: &[u8; 32]`
+///     - Old master key
+/// - `new_password: &[u8; 32]`
+///     - New master key
+/// - `settings: &masterfile::VaultSettings`
+///     - Settings of the vault
+/// 
+/// Returns `Result<String, anyhow::Error>`, the final path of the new file
+/// 
+pub fn rekey_file(
+    encrypted_file_path: &str,
+    temp_file_path: &str,
+    dist_file_path: Option<&str>,
+    old_password: &[u8; 32],
+    new_password: &[u8; 32],
+    settings: &masterfile::VaultSettings,
+) -> Result<String, anyhow::Error> {
+    // Read the old key and create the new one
+    let mut encrypted_file = File::open(encrypted_file_path)?;
+    let (old_file_key, header) = read_file_key(&mut encrypted_file, old_password)?;
+    let new_file_key = FileKey::generate(new_password, settings.cipher)?;
+    let dist_file_path = match dist_file_path {
+        Some(dist_file_path) => dist_file_path.to_string(),
+        None => {
+            let plaintext_path = decrypt_filename(encrypted_file_path,
+                old_file_key.suite, &old_file_key.key, &old_file_key.nonce)?;
+            encrypt_filename(&plaintext_path, new_file_key.suite, &new_file_key.key, &new_file_key.nonce)
+        }
+    };
+
+    // Keep the compression of the file, it was decided on the plaintext
+    let compress = header.as_ref()
+        .map(|header| header.flags & FLAG_ZSTD != 0)
+        .unwrap_or(false);
+
+    // Decrypt on one thread and encrypt the plaintext on this one,
+    // hashing the plaintext on the way through
+    let (pipe_reader, pipe_writer) = io::pipe()?;
+    let mut temp_file = File::create(temp_file_path)?;
+    let plaintext_hash = thread::scope(|scope| -> Result<Vec<u8>, anyhow::Error> {
+        let decryptor = scope.spawn(|| -> Result<Vec<u8>, anyhow::Error> {
+            let mut hashing_writer = HashingWriter::new(pipe_writer);
+            write_decrypted(&mut encrypted_file, &old_file_key, &header, &mut hashing_writer)?;
+            Ok(hashing_writer.finalize())
+        });
+        let encrypted = wrap_source(Box::new(pipe_reader), compress, settings)
+            .and_then(|(mut source, new_header)|
+                write_encrypted(&mut source, &mut temp_file, &new_file_key, &new_header));
+        let plaintext_hash = decryptor.join()
+            .map_err(|_| anyhow!("Decrypting {} failed.", encrypted_file_path))??;
+        encrypted?;
+        Ok(plaintext_hash)
+    })?;
+    temp_file.sync_all()?;
+
+    // Decrypt the new file again and compare the plaintext
+    let mut temp_file = File::open(temp_file_path)?;
+    let (check_file_key, check_header) = read_file_key(&mut temp_file, new_password)?;
+    let mut hashing_writer = HashingWriter::new(io::sink());
+    write_decrypted(&mut temp_file, &check_file_key, &check_header, &mut hashing_writer)?;
+    if hashing_writer.finalize() != plaintext_hash {
+        return Err(anyhow!("Verification of {} failed.", encrypted_file_path));
+    }
+    Ok(dist_file_path)
+}
+
+///
+/// Writer that hashes everything written through it with SHA-256.
+/// 
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter { inner, hasher: Sha256::new() }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        self.hasher.finalize().to_vec()
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+///
+/// Re-encrypt the name of a folder from the old folder key to the new
+/// one and rename it. Folders that already have a name under the new key
+/// are left alone, so an interrupted rekey can be resumed.
+/// # Arguments
+/// - `encrypted_path: &str`
+///     - Path to the encrypted folder
+/// - `old_data: &masterfile::MasterfileData`
+/// - `new_data: &masterfile::MasterfileData`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn rekey_foldername(
+    encrypted_path: &str,
+    old_data: &masterfile::MasterfileData,
+    new_data: &masterfile::MasterfileData,
+) -> Result<(), anyhow::Error> {
+    // Get the encrypted foldername as bytes
+    let encrypted_path = encrypted_path.trim_end_matches('/');
+    let (parent, encrypted_foldername) = encrypted_path.rsplit_once('/')
+        .ok_or_else(|| anyhow!("Invalid folder path {}.", encrypted_path))?;
+    let to_decrypt = hex::decode(encrypted_foldername.strip_suffix(".encrypted")
+        .ok_or_else(|| anyhow!("{} is not an encrypted folder.", encrypted_path))?)?;
+
+    // Skip folders that were already renamed
+    let new_key = folder_key(new_data)?;
+    if FOLDER_SUITE.open(new_key.expose(), folder_nonce(new_data), &to_decrypt).is_ok() {
+        return Ok(());
+    }
+
+    // Decrypt with the old key and encrypt with the new one
+    let old_key = folder_key(old_data)?;
+    let foldername = open_foldername(&to_decrypt, old_key.expose(), old_data)
+        .map_err(|_| anyhow!("Could not decrypt foldername of {}.", encrypted_path))?;
+    let encoded = seal_foldername(&foldername, new_key.expose(), new_data)?;
+
+    fs::rename(encrypted_path, format!("{}/{}.encrypted", parent, hex::encode(encoded)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    // Flags in the header of an encrypted file
+    fn header_flags(encrypted_file_path: &str) -> u8 {
+        let contents = fs::read(encrypted_file_path).unwrap();
+        assert_eq!(&contents[..4], FILE_MAGIC);
+        contents[5]
+    }
+
+    // Encrypt a file into a directory of its own, check the flags of the
+    // header and decrypt it back. Returns the length of the encrypted file
+    fn round_trip(
+        dir: &str,
+        name: &str,
+        contents: &[u8],
+        settings: &masterfile::VaultSettings,
+        flags: u8,
+    ) -> u64 {
+        let key = [7u8; 32];
+        let source_path = format!("{}/{}", dir, name);
+        let dist_dir = format!("{}/{}.{:?}", dir, name, (settings.compression, settings.padding));
+        fs::write(&source_path, contents).unwrap();
+        fs::create_dir_all(&dist_dir).unwrap();
+
+        let encrypted_path = encrypt_file_into(&source_path, &dist_dir, &key, settings).unwrap();
+        assert_eq!(header_flags(&encrypted_path), flags, "{}", name);
+        let encrypted_len = fs::metadata(&encrypted_path).unwrap().len();
+        decrypt_file(&encrypted_path, &key).unwrap();
+        assert!(!Path::new(&encrypted_path).exists());
+        assert_eq!(fs::read(format!("{}/{}", dist_dir, name)).unwrap(), contents, "{}", name);
+        encrypted_len
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let dir = testutil::test_dir("encryption-compressed");
+        let text = "compressible text ".repeat(10_000);
+        let compressed = masterfile::VaultSettings { compression: 3, ..Default::default() };
+
+        // Text is compressed, a format that is already compressed is not
+        let len = round_trip(&dir, "notes.txt", text.as_bytes(), &compressed, FLAG_ZSTD);
+        assert!(len < text.len() as u64 / 10);
+        let len = round_trip(&dir, "photo.jpg", text.as_bytes(), &compressed, 0);
+        assert!(len > text.len() as u64);
+
+        // Nor is data that does not shrink, an empty file or anything in a
+        // vault without compression
+        let mut noise = vec![0u8; 100_000];
+        OsRng.fill_bytes(&mut noise);
+        round_trip(&dir, "noise.bin", &noise, &compressed, 0);
+        round_trip(&dir, "empty.txt", b"", &compressed, 0);
+        round_trip(&dir, "notes.txt", text.as_bytes(), &Default::default(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn padded_round_trip() {
+        let dir = testutil::test_dir("encryption-padded");
+        for scheme in [padding::PADDING_PADME, padding::PADDING_POWER_OF_TWO] {
+            let padded = masterfile::VaultSettings { padding: scheme, ..Default::default() };
+
+            // Lengths that pad to the same size can not be told apart
+            let short = round_trip(&dir, "short.bin", &[1u8; 1000], &padded, FLAG_PADDED);
+            let long = round_trip(&dir, "long.bin", &[1u8; 1010], &padded, FLAG_PADDED);
+            assert_eq!(short, long);
+            let unpadded = round_trip(&dir, "short.bin", &[1u8; 1000], &Default::default(), 0);
+            assert!(unpadded < short);
+
+            // Data that ends in zeros keeps them
+            round_trip(&dir, "zeros.bin", &[0u8; 1500], &padded, FLAG_PADDED);
+            round_trip(&dir, "empty.bin", b"", &padded, FLAG_PADDED);
+        }
+
+        // Compressed data is padded after compression
+        let both = masterfile::VaultSettings { compression: 3, padding: padding::PADDING_PADME, ..Default::default() };
+        let text = "compressible text ".repeat(10_000);
+        round_trip(&dir, "notes.txt", text.as_bytes(), &both, FLAG_ZSTD | FLAG_PADDED);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn every_suite_round_trip() {
+        let dir = testutil::test_dir("encryption-suites");
+        let contents = (0..5000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        for suite in [CipherSuite::XChaCha20Poly1305, CipherSuite::Aes256Gcm, CipherSuite::Aes256GcmSiv] {
+            let settings = masterfile::VaultSettings { cipher: suite, compression: 3, padding: padding::PADDING_PADME, ..Default::default() };
+            round_trip(&dir, &format!("{}.txt", suite.name()), &contents, &settings, FLAG_ZSTD | FLAG_PADDED);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_file() {
+        let dir = testutil::test_dir("encryption-truncated");
+        let key = [7u8; 32];
+        let source_path = format!("{}/chunks.bin", dir);
+        fs::write(&source_path, vec![1u8; 4 * BUFFER_LEN]).unwrap();
+        let encrypted_path = encrypt_file_into(&source_path, &dir, &key, &Default::default()).unwrap();
+        let encrypted = fs::read(&encrypted_path).unwrap();
+        decrypt_file_into(&encrypted_path, Vec::new(), &key).unwrap();
+
+        // Files cut off at a chunk boundary, before the last chunk or
+        // right after the header, are refused
+        let last = encrypted.len() - 16;
+        for len in [last, last - 2 * (BUFFER_LEN + 16), last - 4 * (BUFFER_LEN + 16)] {
+            fs::write(&encrypted_path, &encrypted[..len]).unwrap();
+            assert!(decrypt_file_into(&encrypted_path, Vec::new(), &key).is_err(), "length {}", len);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn undecryptable_names() {
+        let key = [7u8; 32];
+        let nonce = [3u8; 19];
+        let suite = CipherSuite::XChaCha20Poly1305;
+        let encrypted_path = encrypt_filename("dir/notes.txt", suite, &key, &nonce);
+        assert_eq!(decrypt_filename(&encrypted_path, suite, &key, &nonce).unwrap(), "dir/notes.txt");
+
+        // A wrong key, a name that is not hex or not encrypted at all is an error
+        assert!(decrypt_filename(&encrypted_path, suite, &[8u8; 32], &nonce).is_err());
+        assert!(decrypt_filename("dir/notes.encrypted", suite, &key, &nonce).is_err());
+        assert!(decrypt_filename("dir/notes.txt", suite, &key, &nonce).is_err());
+
+        // Same for folders, which are left where they are
+        let (masterfile_path, data) = testutil::test_vault("encryption-names", &testutil::test_settings());
+        let dir = Path::new(&masterfile_path).parent().unwrap().to_string_lossy().to_string();
+        for name in ["plain", "notes.encrypted", "abcdef.encrypted"] {
+            let folder_path = format!("{}/{}", dir, name);
+            fs::create_dir(&folder_path).unwrap();
+            assert!(decrypt_foldername(&folder_path, &data).is_err(), "{}", name);
+            assert!(Path::new(&folder_path).is_dir());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,704 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use rand::{rngs::OsRng, RngCore,};
+use sha2::{Sha256, Digest};
+use std::{
+    fs,
+    fs::File,
+    io,
+    path::Path,
+    thread,
+};
+
+// Import functions from other files
+use crate::{
+    encryptionFunctions,
+    masterfile,
+    wipe,
+};
+
+// Layouts that can be selected for a vault
+pub const LAYOUT_TREE: u8 = 0;
+pub const LAYOUT_FLAT: u8 = 1;
+
+// Name of the directory holding the store at the top of a flat vault
+pub const STORE_DIR: &str = "d";
+
+// Kinds of entries in a directory listing
+const ENTRY_FILE: u8 = 0;
+const ENTRY_DIR: u8 = 1;
+
+///
+/// Data structure for a single entry of a directory listing
+/// # Data
+/// - `kind: u8`
+///     - 0: file, 1: directory
+/// - `id: [u8; 16]`
+///     - Id of the blob holding the file contents or the listing of the directory
+/// - `name: String`
+///     - Plaintext name of the file or directory
+/// 
+struct Entry {
+    kind: u8,
+    id: [u8; 16],
+    name: String,
+}
+
+///
+/// Get the id of the listing of the top directory. It is derived from the
+/// master key so it can be found again without storing it anywhere.
+/// # Arguments
+/// - `data: &masterfile::MasterfileData`
+///     - Data of the decrypted masterfile
+/// 
+/// Returns `[u8; 16]`
+/// 
+fn root_id(data: &masterfile::MasterfileData) -> [u8; 16] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"rusty-vault root listing");
+    hasher.update(data.master_key);
+    let mut id = [0u8; 16];
+    id.copy_from_slice(&hasher.finalize()[..16]);
+    id
+}
+
+///
+/// Get the path of a blob in the store. Blobs are sharded into
+/// directories named after the first byte of their id.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// - `id: &[u8; 16]`
+///     - Id of the blob
+/// 
+/// Returns `String`
+/// 
+fn blob_path(top: &str, id: &[u8; 16]) -> String {
+    let id = hex::encode(id);
+    format!("{}/{}/{}/{}", top, STORE_DIR, &id[..2], &id[2..])
+}
+
+///
+/// Get the path of the blob holding the listing of the top directory.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// - `data: &masterfile::MasterfileData`
+///     - Data of the decrypted masterfile
+/// 
+/// Returns `String`
+/// 
+pub fn root_blob_path(top: &str, data: &masterfile::MasterfileData) -> String {
+    blob_path(top, &root_id(data))
+}
+
+///
+/// Generate a random id for a new blob and create its shard directory.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// 
+/// Returns `Result<([u8; 16], String), anyhow::Error>`
+/// 
+fn new_blob(top: &str) -> Result<([u8; 16], String), anyhow::Error> {
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    let path = blob_path(top, &id);
+    fs::create_dir_all(Path::new(&path).parent().unwrap())?;
+    Ok((id, path))
+}
+
+///
+/// Serialize a directory listing. Each entry is written as
+/// `kind: u8, id: [u8; 16], name_len: u16 (BE), name`.
+/// # Arguments
+/// - `entries: &[Entry]`
+/// 
+/// Returns `Vec<u8>`
+/// 
+fn encode_listing(entries: &[Entry]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for entry in entries {
+        bytes.push(entry.kind);
+        bytes.extend_from_slice(&entry.id);
+        bytes.extend_from_slice(&(entry.name.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(entry.name.as_bytes());
+    }
+    bytes
+}
+
+///
+/// Parse a serialized directory listing, rejecting names that would
+/// escape the directory.
+/// # Arguments
+/// - `bytes: &[u8]`
+/// 
+/// Returns `Result<Vec<Entry>, anyhow::Error>`
+/// 
+fn decode_listing(bytes: &[u8]) -> Result<Vec<Entry>, anyhow::Error> {
+    let mut entries = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        if rest.len() < 19 {
+            return Err(anyhow!("Truncated directory listing."));
+        }
+        let kind = rest[0];
+        let mut id = [0u8; 16];
+        id.copy_from_slice(&rest[1..17]);
+        let len = u16::from_be_bytes([rest[17], rest[18]]) as usize;
+        if rest.len() < 19 + len {
+            return Err(anyhow!("Truncated directory listing."));
+        }
+        let name = String::from_utf8(rest[19..19 + len].to_vec())?;
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(anyhow!("Invalid name in directory listing."));
+        }
+        entries.push(Entry { kind, id, name });
+        rest = &rest[19 + len..];
+    }
+    Ok(entries)
+}
+
+///
+/// Decrypt and parse the listing stored in a blob.
+/// # Arguments
+/// - `top: &str`
+/// - `id: &[u8; 16]`
+/// - `data: &masterfile::MasterfileData`
+/// 
+/// Returns `Result<Vec<Entry>, anyhow::Error>`
+/// 
+fn read_listing(
+    top: &str,
+    id: &[u8; 16],
+    data: &masterfile::MasterfileData,
+) -> Result<Vec<Entry>, anyhow::Error> {
+    let mut bytes = Vec::new();
+    encryptionFunctions::decrypt_file_into(&blob_path(top, id), &mut bytes, &data.master_key)?;
+    decode_listing(&bytes)
+}
+
+///
+/// Encrypt a listing into the blob with the given path. The blob is written
+/// next to its final path first so an existing listing is replaced atomically.
+/// # Arguments
+/// - `path: &str`
+/// - `entries: &[Entry]`
+/// - `data: &masterfile::MasterfileData`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn write_listing(
+    path: &str,
+    entries: &[Entry],
+    data: &masterfile::MasterfileData,
+) -> Result<(), anyhow::Error> {
+    let temp_path = format!("{}.tmp", path);
+    encryptionFunctions::encrypt_data_to(&encode_listing(entries), &temp_path,
+        &data.master_key, &data.settings)?;
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+///
+/// Paths to remove once a lock is complete, that is once every listing up
+/// to the top of the vault has been written.
+/// # Data
+/// - `files: Vec<String>`
+///     - Plaintext files that were encrypted into blobs
+/// - `dirs: Vec<String>`
+///     - Plaintext directories, below the directories inside them
+/// - `blobs: Vec<String>`
+///     - Blobs of older copies of files that were locked again
+/// 
+#[derive(Default)]
+struct Pending {
+    files: Vec<String>,
+    dirs: Vec<String>,
+    blobs: Vec<String>,
+}
+
+impl Pending {
+    ///
+    /// Add the paths of a subdirectory to these
+    /// # Arguments
+    /// - `other: Pending`
+    /// 
+    fn append(&mut self, mut other: Pending) {
+        self.files.append(&mut other.files);
+        self.dirs.append(&mut other.dirs);
+        self.blobs.append(&mut other.blobs);
+    }
+
+    ///
+    /// Remove the plaintext and the older blobs, files first so that the
+    /// directories are empty when they are removed
+    /// 
+    /// Returns `Result<(), anyhow::Error>`
+    /// 
+    fn remove(self) -> Result<(), anyhow::Error> {
+        for path in &self.files {
+            wipe::remove_plaintext(path)?;
+        }
+        for path in &self.blobs {
+            fs::remove_file(path)?;
+        }
+        for path in &self.dirs {
+            fs::remove_dir(path)?;
+        }
+        Ok(())
+    }
+}
+
+///
+/// Encrypt the contents of a plaintext directory into the store and write
+/// its listing. Subdirectories are handled in their own threads and their
+/// listings are written before this one. Nothing is removed here, the
+/// plaintext is returned so it can be removed once the listing of the top
+/// directory is written, so an interrupted lock never loses data. Names
+/// already in the listing, left by an interrupted lock, are locked again
+/// into the same entry.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// - `dir: &str`
+///     - Path to the directory to lock
+/// - `listing_path: &str`
+///     - Path of the blob to write the listing of the directory to
+/// - `entries: Vec<Entry>`
+///     - Entries already in the listing of the directory
+/// - `data: &masterfile::MasterfileData`
+/// 
+/// Returns `Result<Pending, anyhow::Error>`
+/// 
+fn lock_dir(
+    top: &str,
+    dir: &str,
+    listing_path: &str,
+    mut entries: Vec<Entry>,
+    data: &masterfile::MasterfileData,
+) -> Result<Pending, anyhow::Error> {
+    let mut files = Vec::new();
+    let mut subdirs = Vec::new();
+
+    // Sort the contents of the directory, skipping the masterfile and
+    // the store itself at the top of the vault
+    for path_inv in fs::read_dir(dir)? {
+        let name = path_inv?.file_name().into_string()
+            .map_err(|name| anyhow!("Invalid name {} in {}.", name.to_string_lossy(), dir))?;
+        if dir == top && (name == "masterfile.e" || name == STORE_DIR) {
+            continue;
+        }
+        let path = format!("{}/{}", dir, name);
+        let kind = if Path::new(&path).is_dir() { ENTRY_DIR } else { ENTRY_FILE };
+        if entries.iter().any(|entry| entry.name == name && entry.kind != kind) {
+            return Err(anyhow!("{} is a file and a folder in the vault, rename one of them.", path));
+        }
+        if kind == ENTRY_DIR {
+            subdirs.push((name, path));
+        } else {
+            files.push((name, path));
+        }
+    }
+
+    // Encrypt the files into new blobs, replacing older copies
+    let mut pending = Pending::default();
+    for (name, path) in &files {
+        let (id, blob) = new_blob(top)?;
+        encryptionFunctions::encrypt_file_to(path, &blob, &data.master_key, &data.settings)?;
+        if let Some(index) = entries.iter().position(|entry| &entry.name == name) {
+            pending.blobs.push(blob_path(top, &entries.remove(index).id));
+        }
+        entries.push(Entry { kind: ENTRY_FILE, id, name: name.clone() });
+        pending.files.push(path.clone());
+    }
+
+    // Lock each subdirectory in its own thread, into its existing listing
+    // if it has one
+    let existing = subdirs.iter()
+        .map(|(name, _)| entries.iter().find(|entry| &entry.name == name).map(|entry| entry.id))
+        .collect::<Vec<_>>();
+    let results = thread::scope(|scope| {
+        let handles = subdirs.iter().zip(existing).map(|((name, path), existing)| {
+            scope.spawn(move || -> Result<(Entry, Pending), anyhow::Error> {
+                let (id, blob, entries) = match existing {
+                    Some(id) if Path::new(&blob_path(top, &id)).exists() =>
+                        (id, blob_path(top, &id), read_listing(top, &id, data)?),
+                    _ => {
+                        let (id, blob) = new_blob(top)?;
+                        (id, blob, Vec::new())
+                    }
+                };
+                let mut pending = lock_dir(top, path, &blob, entries, data)?;
+                pending.dirs.push(path.clone());
+                Ok((Entry { kind: ENTRY_DIR, id, name: name.clone() }, pending))
+            })
+        }).collect::<Vec<_>>();
+        handles.into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err(anyhow!("Locking thread panicked."))))
+            .collect::<Vec<_>>()
+    });
+
+    // Keep the listing complete even if a subdirectory failed, so
+    // that everything which did get locked can be found again
+    let mut error = None;
+    for result in results {
+        match result {
+            Ok((entry, subdir_pending)) => {
+                entries.retain(|existing| existing.name != entry.name);
+                entries.push(entry);
+                pending.append(subdir_pending);
+            }
+            Err(err) => error = Some(err),
+        }
+    }
+    write_listing(listing_path, &entries, data)?;
+    match error {
+        Some(err) => Err(err),
+        None => Ok(pending),
+    }
+}
+
+///
+/// Restore the contents of a directory from the store. Blobs are removed
+/// as they are decrypted and the listing last, so an interrupted unlock can
+/// be run again.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// - `dir: &str`
+///     - Path to the directory to restore into
+/// - `id: &[u8; 16]`
+///     - Id of the listing of the directory
+/// - `data: &masterfile::MasterfileData`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn unlock_dir(
+    top: &str,
+    dir: &str,
+    id: &[u8; 16],
+    data: &masterfile::MasterfileData,
+) -> Result<(), anyhow::Error> {
+    let entries = read_listing(top, id, data)?;
+
+    // Decrypt the files, skipping those restored by an earlier interrupted unlock
+    for entry in entries.iter().filter(|entry| entry.kind == ENTRY_FILE) {
+        let blob = blob_path(top, &entry.id);
+        let dist_path = format!("{}/{}", dir, entry.name);
+        if !Path::new(&blob).exists() {
+            if !Path::new(&dist_path).exists() {
+                println!("Missing contents of {}", dist_path);
+            }
+            continue;
+        }
+        let (dist_path, dist_file) = create_unique(dir, &entry.name)?;
+        if let Err(err) = encryptionFunctions::decrypt_file_into(&blob, dist_file, &data.master_key) {
+            fs::remove_file(&dist_path)?;
+            return Err(err);
+        }
+        fs::remove_file(blob)?;
+    }
+
+    // Restore each subdirectory in its own thread
+    let results = thread::scope(|scope| {
+        let handles = entries.iter().filter(|entry| entry.kind == ENTRY_DIR).map(|entry| {
+            scope.spawn(move || -> Result<(), anyhow::Error> {
+                let dist_path = format!("{}/{}", dir, entry.name);
+                fs::create_dir_all(&dist_path)?;
+                if Path::new(&blob_path(top, &entry.id)).exists() {
+                    unlock_dir(top, &dist_path, &entry.id, data)?;
+                }
+                Ok(())
+            })
+        }).collect::<Vec<_>>();
+        handles.into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err(anyhow!("Unlocking thread panicked."))))
+            .collect::<Vec<_>>()
+    });
+    for result in results {
+        result?;
+    }
+
+    fs::remove_file(blob_path(top, id))?;
+    Ok(())
+}
+
+///
+/// Create a new file for restored plaintext without replacing a file that
+/// is already there. When the name is taken a number is added to it.
+/// # Arguments
+/// - `dir: &str`
+///     - Directory to create the file in
+/// - `name: &str`
+///     - Name of the file in the listing
+/// 
+/// Returns `Result<(String, File), anyhow::Error>`
+/// 
+fn create_unique(dir: &str, name: &str) -> Result<(String, File), anyhow::Error> {
+    let mut dist_path = format!("{}/{}", dir, name);
+    for count in 1.. {
+        match fs::OpenOptions::new().write(true).create_new(true).open(&dist_path) {
+            Ok(file) => {
+                if count > 1 {
+                    println!("{}/{} already exists, restored as {}", dir, name, dist_path);
+                }
+                return Ok((dist_path, file));
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                dist_path = format!("{}/{} ({})", dir, name, count);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    unreachable!()
+}
+
+///
+/// Walk the tree kept in the store of a locked flat vault without restoring
+/// any of it.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// - `data: &masterfile::MasterfileData`
+/// - `parent: &D`
+///     - Handle of the directory the top directory is walked into
+/// - `on_dir: &mut dyn FnMut(&str, &D) -> Result<D, anyhow::Error>`
+///     - Called with the name of every directory and the handle of its
+///       parent, returns the handle of the directory
+/// - `on_file: &mut dyn FnMut(&str, &D, &str) -> Result<(), anyhow::Error>`
+///     - Called with the name of every file, the handle of its parent and
+///       the path of the blob holding its contents
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn walk<D>(
+    top: &str,
+    data: &masterfile::MasterfileData,
+    parent: &D,
+    on_dir: &mut dyn FnMut(&str, &D) -> Result<D, anyhow::Error>,
+    on_file: &mut dyn FnMut(&str, &D, &str) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    walk_dir(top, &root_id(data), data, parent, on_dir, on_file)
+}
+
+///
+/// Walk a directory of the store, see `walk`.
+/// # Arguments
+/// - `top: &str`
+/// - `id: &[u8; 16]`
+///     - Id of the listing of the directory
+/// - `data: &masterfile::MasterfileData`
+/// - `parent: &D`
+/// - `on_dir: &mut dyn FnMut(&str, &D) -> Result<D, anyhow::Error>`
+/// - `on_file: &mut dyn FnMut(&str, &D, &str) -> Result<(), anyhow::Error>`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn walk_dir<D>(
+    top: &str,
+    id: &[u8; 16],
+    data: &masterfile::MasterfileData,
+    parent: &D,
+    on_dir: &mut dyn FnMut(&str, &D) -> Result<D, anyhow::Error>,
+    on_file: &mut dyn FnMut(&str, &D, &str) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    for entry in read_listing(top, id, data)? {
+        if entry.kind == ENTRY_DIR {
+            let dir = on_dir(&entry.name, parent)?;
+            walk_dir(top, &entry.id, data, &dir, on_dir, on_file)?;
+        } else {
+            on_file(&entry.name, parent, &blob_path(top, &entry.id))?;
+        }
+    }
+    Ok(())
+}
+
+///
+/// Lock a flat vault. Every plaintext file and directory below the top
+/// directory is moved into the store, and removed once the whole tree is
+/// in the store. Entries already in the store are kept, so a vault with
+/// mixed status can be locked again.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// - `data: &masterfile::MasterfileData`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn lock_vault(
+    top: &str,
+    data: &masterfile::MasterfileData,
+) -> Result<(), anyhow::Error> {
+    let id = root_id(data);
+    let listing_path = blob_path(top, &id);
+    fs::create_dir_all(Path::new(&listing_path).parent().unwrap())?;
+
+    let entries = if Path::new(&listing_path).exists() {
+        read_listing(top, &id, data)?
+    } else {
+        Vec::new()
+    };
+
+    // Every listing is written, so the plaintext can be removed
+    lock_dir(top, top, &listing_path, entries, data)?.remove()
+}
+
+///
+/// Unlock a flat vault. Restores the plaintext tree from the store and
+/// removes the store once it is empty.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// - `data: &masterfile::MasterfileData`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn unlock_vault(
+    top: &str,
+    data: &masterfile::MasterfileData,
+) -> Result<(), anyhow::Error> {
+    let id = root_id(data);
+    if Path::new(&blob_path(top, &id)).exists() {
+        unlock_dir(top, top, &id, data)?;
+    }
+
+    // Remove the emptied shard directories and the store
+    let store = format!("{}/{}", top, STORE_DIR);
+    if Path::new(&store).exists() {
+        for shard in fs::read_dir(&store)? {
+            fs::remove_dir(shard?.path()).ok();
+        }
+        fs::remove_dir(&store).ok();
+    }
+    Ok(())
+}
+
+///
+/// Checks the encryption status of a flat vault and returns a u8 to
+/// signify the status, matching `functions::check_vault_status`.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the top dir of the vault
+/// 
+/// Returns `u8`
+/// 
+pub fn check_vault_status(path: &str) -> u8 {
+    // A missing top directory means the vault is offline
+    let paths = match fs::read_dir(path) {
+        Ok(paths) => paths,
+        Err(_) => return 4,
+    };
+    let store_exists = Path::new(&format!("{}/{}", path, STORE_DIR)).is_dir();
+    let plaintext = paths
+        .filter_map(|path_inv| path_inv.ok())
+        .map(|path_inv| path_inv.file_name().to_string_lossy().to_string())
+        .filter(|name| name != "masterfile.e" && name != STORE_DIR && name != ".DS_Store")
+        .count();
+
+    if store_exists && plaintext == 0 {
+        0
+    } else if !store_exists && plaintext > 0 {
+        1
+    } else if store_exists {
+        2
+    } else {
+        3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use std::os::unix::ffi::OsStrExt;
+
+    // Keys of a vault that only exists in memory
+    fn test_data() -> masterfile::MasterfileData {
+        let mut key_material = [0u8; masterfile::KEY_MATERIAL_LEN];
+        OsRng.fill_bytes(&mut key_material);
+        let settings = masterfile::VaultSettings { layout: LAYOUT_FLAT, ..Default::default() };
+        masterfile::data_from_material(&key_material, settings)
+    }
+
+    #[test]
+    fn listing_round_trip() {
+        let entries = vec![
+            Entry { kind: ENTRY_FILE, id: [1; 16], name: "notes.txt".to_string() },
+            Entry { kind: ENTRY_DIR, id: [2; 16], name: "photos ünïcode".to_string() },
+        ];
+        let decoded = decode_listing(&encode_listing(&entries)).unwrap();
+        assert_eq!(decoded.len(), 2);
+        for (entry, decoded) in entries.iter().zip(&decoded) {
+            assert_eq!((entry.kind, entry.id, &entry.name), (decoded.kind, decoded.id, &decoded.name));
+        }
+        assert!(decode_listing(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn listing_corruption() {
+        let bytes = encode_listing(&[Entry { kind: ENTRY_FILE, id: [1; 16], name: "name".to_string() }]);
+        for len in 1..bytes.len() {
+            assert!(decode_listing(&bytes[..len]).is_err(), "truncated to {}", len);
+        }
+        for name in ["", ".", "..", "a/b"] {
+            let bytes = encode_listing(&[Entry { kind: ENTRY_FILE, id: [1; 16], name: name.to_string() }]);
+            assert!(decode_listing(&bytes).is_err(), "{:?}", name);
+        }
+        let mut bytes = bytes;
+        let last = bytes.len() - 1;
+        bytes[last] = 0xff;
+        assert!(decode_listing(&bytes).is_err());
+    }
+
+    #[test]
+    fn lock_unlock_round_trip() {
+        let top = testutil::test_dir("flatstore-round-trip");
+        let data = test_data();
+        fs::create_dir_all(format!("{}/a/b/empty", top)).unwrap();
+        fs::write(format!("{}/top.txt", top), "top").unwrap();
+        fs::write(format!("{}/a/b/deep.bin", top), vec![7u8; 100_000]).unwrap();
+
+        lock_vault(&top, &data).unwrap();
+        assert_eq!(check_vault_status(&top), 0);
+        assert!(!Path::new(&format!("{}/a", top)).exists());
+
+        unlock_vault(&top, &data).unwrap();
+        assert_eq!(check_vault_status(&top), 1);
+        assert_eq!(fs::read(format!("{}/top.txt", top)).unwrap(), b"top");
+        assert_eq!(fs::read(format!("{}/a/b/deep.bin", top)).unwrap(), vec![7u8; 100_000]);
+        assert!(Path::new(&format!("{}/a/b/empty", top)).is_dir());
+
+        // A name that is not UTF-8 can not be stored in a listing
+        let invalid = std::ffi::OsStr::from_bytes(b"invalid\xff");
+        fs::write(Path::new(&top).join("a").join(invalid), "invalid").unwrap();
+        assert!(lock_vault(&top, &data).is_err());
+        assert!(Path::new(&top).join("a").join(invalid).exists());
+        fs::remove_dir_all(&top).unwrap();
+
+        // A vault that is not there is offline
+        assert_eq!(check_vault_status(&top), 4);
+    }
+
+    #[test]
+    fn tampered_listing() {
+        let top = testutil::test_dir("flatstore-tampered");
+        let data = test_data();
+        fs::write(format!("{}/file.txt", top), "contents").unwrap();
+        lock_vault(&top, &data).unwrap();
+
+        // A flipped byte in the root listing is caught before anything is restored
+        let root = root_blob_path(&top, &data);
+        let mut blob = fs::read(&root).unwrap();
+        let last = blob.len() - 1;
+        blob[last] ^= 1;
+        fs::write(&root, &blob).unwrap();
+        assert!(unlock_vault(&top, &data).is_err());
+        assert!(!Path::new(&format!("{}/file.txt", top)).exists());
+
+        // So is a listing cut short
+        fs::write(&root, &blob[..blob.len() / 2]).unwrap();
+        assert!(unlock_vault(&top, &data).is_err());
+        fs::remove_dir_all(&top).unwrap();
+    }
+}
@@ -1,13 +1,16 @@
 // Import external crates
 use std::{
     fs,
-    fs::File,
-    io::{self, Write, BufRead},
+    io::{self, Write},
     path::Path,
     thread,
-    sync::atomic::{
-        AtomicUsize,
-        Ordering,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        OnceLock,
     },
     time::Duration,
 };
@@ -16,14 +19,34 @@ use anyhow::anyhow;
 
 // Import functions from other files
 use crate::{
+    agent,
+    config,
+    cipher::CipherSuite,
     encryptionFunctions,
+    flatstore,
+    kdf,
     masterfile,
+    mirror,
+    padding,
+    recipients,
+    rekey,
+    secret::SecretString,
+    shares,
     vault::vault::Vault,
 };
 
 // Set static variable for later thread collection
 static GLOBAL_THREAD_COUNT: AtomicUsize = AtomicUsize::new(0);
 
+// Keyfile given on the command line, used instead of asking for one
+static KEYFILE_PATH: OnceLock<String> = OnceLock::new();
+
+// zstd level used for vaults created with compression enabled
+const DEFAULT_COMPRESSION_LEVEL: u8 = 3;
+
+// Levels of directories searched below a root for vaults that moved
+const LOCATE_DEPTH: usize = 4;
+
 ///
 /// Will get input from the user after displaying the passed string.
 /// # Arguments
@@ -69,7 +92,9 @@ fn clean_input(mut input: String) -> Result<String, anyhow::Error> {
 }
 
 ///
-/// Instantiate the argon2 config object
+/// Instantiate the argon2 config object used to derive the file and folder
+/// keys from the master key. Password slots use the parameters stored in
+/// the masterfile instead, see `kdf::KdfParams`.
 /// 
 /// Returns `argon2::Config<'a>`
 /// 
@@ -91,10 +116,10 @@ pub fn argon2_config<'a>() -> argon2::Config<'a> {
 /// - `output: &str`
 ///     - Will output this when asking for the password
 /// 
-/// Returns `String`
+/// Returns `SecretString`
 /// 
-pub fn get_password_input(output: &str) -> Result<String, anyhow::Error> {
-    let password1 = rpassword::prompt_password(output).unwrap();
+pub fn get_password_input(output: &str) -> Result<SecretString, anyhow::Error> {
+    let password1 = SecretString::new(rpassword::prompt_password(output)?);
     Ok(password1)
 }
 
@@ -105,19 +130,17 @@ pub fn get_password_input(output: &str) -> Result<String, anyhow::Error> {
 /// - `output: &str`
 ///     - Will output this when asking for the password
 /// 
-/// Returns `String`
+/// Returns `SecretString`
 /// 
-pub fn get_password_double(output: &str) -> Result<String, anyhow::Error> {
-    let mut _password1 = String::new();
+pub fn get_password_double(output: &str) -> Result<SecretString, anyhow::Error> {
     loop {
-        _password1 = rpassword::prompt_password(output).unwrap();
-        let password2 = rpassword::prompt_password("Confirm password: ").unwrap();
-        if _password1 == password2 {
-            break
+        let password1 = SecretString::new(rpassword::prompt_password(output)?);
+        let password2 = SecretString::new(rpassword::prompt_password("Confirm password: ")?);
+        if password1 == password2 {
+            return Ok(password1)
         }
         println!("Passwords do not match. Try again.");
     }
-    Ok(_password1)
 }
 
 ///
@@ -144,20 +167,6 @@ pub fn check_dir(path: &str) -> bool {
     fs::metadata(path).unwrap().file_type().is_dir()
 }
 
-///
-/// Given a Vector (Vec<u8>), will return an Array [u8] of 
-/// appropriate size.
-/// # Arguments 
-/// - `v: Vec<T>`
-///     - Vector of generic type to be moved into Array
-/// 
-/// Returns `[T; N]`
-/// 
-pub fn into_array<T, const N: usize>(v: Vec<T>) -> [T; N] {
-    v.try_into()
-        .unwrap_or_else(|v: Vec<T>| panic!("Expected a Vec of length {} but it was {}", N, v.len()))
-}
-
 ///
 /// Main function for directory recursion. Will scan each directory for files and 
 /// directories. If a file is found it will be encrypted or decrypted depending on the
@@ -166,8 +175,8 @@ pub fn into_array<T, const N: usize>(v: Vec<T>) -> [T; N] {
 /// # Arguments
 /// - `path: &String`
 ///     - The path of the directory to scan.
-/// - `data: &masterfile::MasterfileData`
-///     - The data of the decrypted masterfile. 
+/// - `data: &Arc<masterfile::MasterfileData>`
+///     - The data of the decrypted masterfile, shared with the spawned threads
 ///         - Masterkey for decryption/encryption
 ///         - Folder Nonce and Salt for directory name encryption/decryption
 /// - `force_encrypt: bool`
@@ -177,16 +186,16 @@ pub fn into_array<T, const N: usize>(v: Vec<T>) -> [T; N] {
 /// 
 pub fn dir_recur(
     path: &str, 
-    data: &masterfile::MasterfileData,
+    data: &Arc<masterfile::MasterfileData>,
     force_encrypt: bool,
 ) -> Result<(), anyhow::Error> {
     let paths = fs::read_dir(path).unwrap();
         for path_inv in paths {
             let x = path_inv?.path().into_os_string().into_string().unwrap();
             if check_dir(&x) {
-                // Clone data for moving to new thread
+                // Share the data with the new thread instead of copying the keys
                 let tx = x.clone();
-                let tdata = data.clone();
+                let tdata = Arc::clone(data);
                 let tbool = force_encrypt;
 
                 // Increment the global thread count
@@ -202,12 +211,12 @@ pub fn dir_recur(
                 // If the path is a file, decrypt or encrypt depending on the passed
                 // bool `force_encrypt`
                 if !x.ends_with("masterfile.e") && !x.ends_with(".DS_Store")
-                    && !x.ends_with("Icon") {
+                    && !x.ends_with("Icon") && !mirror::is_manifest(&x) {
                     if x.ends_with(".encrypted") && !force_encrypt {
                         encryptionFunctions::decrypt_file(&x, &data.master_key).ok();
                     }
                     else if !x.ends_with(".encrypted") && force_encrypt {
-                        encryptionFunctions::encrypt_file(&x, &data.master_key).ok();
+                        encryptionFunctions::encrypt_file(&x, &data.master_key, &data.settings).ok();
                     }
                 }
             }
@@ -250,40 +259,6 @@ fn folder_recur(
     Ok(())
 }
 
-///
-/// Check to see if the config file is present.
-/// If not, it will create it
-/// # Arguments
-/// - `config_path` string that points to the config file
-/// 
-pub fn check_config_file(config_path: &str) -> Result<(), anyhow::Error> {
-    if !Path::new(&config_path).exists() {
-        if !Path::new(&config_path.strip_suffix("/config").unwrap()).exists() {
-            fs::create_dir(&config_path.strip_suffix("/config").unwrap())?;
-            fs::File::create(&config_path)?;
-        }
-        else {
-            fs::File::create(&config_path)?;
-        }
-    }
-    Ok(())
-}
-
-///
-/// Allows for reading files by lines. Returns an iterator to read 
-/// over the lines of data.
-/// # Arguments
-/// - `filename: P`
-///     - Path of file to read
-/// 
-/// Returns `io::Result<io::Lines<io::BufReader<File>>>`
-/// 
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where P: AsRef<Path>, {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
-}
-
 ///
 /// Read the config file and returns a Vector of Vault objects
 /// created from the data.
@@ -291,32 +266,10 @@ where P: AsRef<Path>, {
 /// - `config_path: &str`
 ///     - Path to the config file
 /// 
-/// Returns `Vec<Vault>`
-/// 
-pub fn read_config_file(config_path: &str) -> Vec<Vault> {
-    // Instantiate the Vector
-    let mut vaults: Vec<Vault> = Vec::new();
-
-    if let Ok(lines) = read_lines(config_path) {
-        for line in lines.flatten() {
-             
-            // data is a string, split by comma
-            let datal = line.split(',').collect::<Vec<&str>>();
-
-            // Make sure data is appropriate length
-            if datal.len() > 1 {
-                // Create a new Vault object and push into the Vector
-                // datal[0] will be the name
-                // datal[1] will be the path 
-                vaults.push(
-                    Vault::new(String::from(datal[0]), 
-                    String::from(datal[1]),
-                    hex::decode(datal[2]).unwrap()),
-                )
-            }  
-        }
-    }
-    vaults
+/// Returns `Result<Vec<Vault>, anyhow::Error>`
+/// 
+pub fn read_config_file(config_path: &str) -> Result<Vec<Vault>, anyhow::Error> {
+    Ok(config::load(config_path)?.vaults)
 }
 
 ///
@@ -347,38 +300,140 @@ pub fn create_vault(
         path_to_create.to_lowercase() == "q" {
             return Ok(())
     }
-    
+
+    create_vault_at(vaults, config_path, &path_to_create)
+}
+
+///
+/// Ask for the name, password and settings of a new vault, create its
+/// masterfile in the given directory and add it to the config file.
+/// # Arguments
+/// - `vaults: &mut Vec<Vault>`
+///     - Vector containing the Vault objects
+/// - `config_path: &str`
+///     - Path to the config file
+/// - `path_to_create: &str`
+///     - Top directory of the new vault
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn create_vault_at(
+    vaults: &mut Vec<Vault>,
+    config_path: &str,
+    path_to_create: &str,
+) -> Result<(), anyhow::Error> {
     let name = get_input("Enter name for new vault: ")?;
     let password = get_password_double("Enter password for vault: ")?;
 
+    // Ask whether files in the vault should be compressed before encryption
+    let mut settings = masterfile::VaultSettings::default();
+    let compress = get_input("Compress files before encryption? [Y/N]")?;
+    if compress.to_lowercase() == "y" {
+        settings.compression = DEFAULT_COMPRESSION_LEVEL;
+    }
+
+    // Ask whether file sizes should be padded to hide their exact length
+    let pad = get_input("Pad file sizes to hide their exact length?
+[1] No padding
+[2] Padmé (small overhead)
+[3] Power of two (large overhead)")?;
+    settings.padding = match pad.as_str() {
+        "2" => padding::PADDING_PADME,
+        "3" => padding::PADDING_POWER_OF_TWO,
+        _ => padding::PADDING_NONE,
+    };
+
+    // Ask how the vault should be stored when locked
+    let layout = get_input("Choose the layout of the locked vault:
+[1] Tree (folders keep their place, names are encrypted)
+[2] Flat (hides the folder structure in a store of opaque files)")?;
+    settings.layout = if layout == "2" {
+        flatstore::LAYOUT_FLAT
+    } else {
+        flatstore::LAYOUT_TREE
+    };
+
+    // Ask which cipher suite the vault should be encrypted with
+    let suite = get_input("Choose the cipher of the vault:
+[1] XChaCha20-Poly1305
+[2] AES-256-GCM (fastest on CPUs with AES instructions)
+[3] AES-256-GCM-SIV (tolerates nonce reuse)")?;
+    settings.cipher = match suite.as_str() {
+        "2" => CipherSuite::Aes256Gcm,
+        "3" => CipherSuite::Aes256GcmSiv,
+        _ => CipherSuite::XChaCha20Poly1305,
+    };
+
+    // Ask how expensive deriving the key from the password should be
+    let profile = get_input("Choose the cost of unlocking the vault with the password:
+[1] Calibrate to this machine
+[2] Interactive (64 MiB)
+[3] Moderate (256 MiB)
+[4] Paranoid (1 GiB)")?;
+    settings.kdf = match profile.as_str() {
+        "2" => kdf::profile("interactive").unwrap(),
+        "3" => kdf::profile("moderate").unwrap(),
+        "4" => kdf::profile("paranoid").unwrap(),
+        _ => {
+            let target = get_input("Enter the target unlock time in seconds, or leave empty for 1: ")?;
+            let target = match target.is_empty() {
+                true => kdf::DEFAULT_TARGET,
+                false => target.parse::<f64>().ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or_else(|| anyhow!("{} is not a number of seconds.", target))?,
+            };
+            println!("Calibrating");
+            kdf::calibrate(target)?
+        }
+    };
+    println!("Password key derivation: {}", settings.kdf.describe());
+
+    // Ask for an optional keyfile that is needed together with the password
+    let keyfile_input = get_input("Enter path of a keyfile to require with the password, or leave empty for none: ")?;
+    let keyfile_path = if keyfile_input.is_empty() {
+        None
+    } else {
+        if !Path::new(&keyfile_input).exists() {
+            let create = get_input("Keyfile does not exist. Create a new random keyfile there? [Y/N]")?;
+            if create.to_lowercase() != "y" {
+                return Ok(())
+            }
+            masterfile::create_keyfile(&keyfile_input)?;
+        }
+        println!("Keep a copy of the keyfile. The vault can not be unlocked without it.");
+        Some(keyfile_input)
+    };
+
     // Get sha256 hash of password for storage
-    let hashed_password = hash_password_string(password.clone())?;
+    let hashed_password = hash_password_string(password.expose())?;
 
     // Format string with masterfile and add new vault to list
     if path_to_create.ends_with('/') {
         let master_file_path = format!("{}masterfile.e", &path_to_create);
         vaults.push(
-            Vault::new(name.clone(), master_file_path, hex::decode(hashed_password.clone())?)
+            Vault::new(name.clone(), master_file_path, hex::decode(hashed_password.clone())?)?
         );
     } else {
         let master_file_path = format!("{}/masterfile.e", &path_to_create);
         vaults.push(
-            Vault::new(name.clone(), master_file_path, hex::decode(hashed_password.clone())?)
+            Vault::new(name.clone(), master_file_path, hex::decode(hashed_password.clone())?)?
         );
     }
 
     // Create the masterfile with the password 
-    masterfile::create_masterfile(&path_to_create, &password)?;
+    let masterfile_data = masterfile::create_masterfile
+        (path_to_create, password.expose(), keyfile_path.as_deref(), &settings)?;
 
-    // Open the config file and write the vault data
-    let mut config_file = fs::OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open(&config_path)?;
-    config_file.write_all(format!("{},{}/masterfile.e,{}\n",
-        name, path_to_create, hashed_password).as_bytes())?;
+    // Offer a recovery phrase in case the password or keyfile is lost
+    let recovery = get_input("Generate a recovery phrase that can unlock the vault if the password is lost? [Y/N]")?;
+    if recovery.to_lowercase() == "y" {
+        let phrase = masterfile::set_recovery_slot
+            (&format!("{}/masterfile.e", path_to_create.trim_end_matches('/')), &masterfile_data)?;
+        print_recovery_phrase(&phrase)?;
+    }
 
-    Ok(())
+    // Write the vault data to the config file
+    write_vaults(vaults, config_path)
 }
 
 ///
@@ -397,21 +452,564 @@ pub fn unlock_lock_vault(
     force_encrypt: bool,
     stored_hash: Vec<u8>,
 ) -> Result<(), anyhow::Error> {
+    // Read in the data from the masterfile and store in a data structure
+    let masterfile_data = read_vault_data(&masterfile_path, &stored_hash)?;
+
+    // Go through the vault with the data
+    process_vault(&masterfile_path, &masterfile_data, force_encrypt)
+}
+
+///
+/// Crypto-shred a vault: lock it if any file is in plaintext, then destroy
+/// the masterfile with every key slot so the ciphertext left behind can
+/// never be decrypted. The agent forgets the keys as well.
+/// # Arguments
+/// - `vault: &Vault`
+///     - Vault to destroy
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn shred_vault(vault: &Vault) -> Result<(), anyhow::Error> {
+    if vault.status == 4 {
+        return Err(anyhow!("The vault [{}] is offline, its masterfile can not be destroyed.", vault.name));
+    }
+
+    // Nothing may be left in plaintext once the keys are gone
+    if vault.status == 1 || vault.status == 2 {
+        unlock_lock_vault(vault.master_file_path.clone(), true, vault.hashed_password.clone())?;
+        if check_vault_status(&vault.path) != 0 {
+            return Err(anyhow!("The vault [{}] could not be locked, nothing was destroyed.", vault.name));
+        }
+    }
+
+    masterfile::shred_masterfile(&vault.master_file_path)?;
+    let _ = agent::forget(&vault.master_file_path);
+    println!("Destroyed the keys of [{}], its files can no longer be decrypted.", vault.name);
+    Ok(())
+}
+
+///
+/// Ask for the vault password, check it against the stored hash, and
+/// read the data from the masterfile. Leaving the password empty unlocks
+/// the vault with an age identity file or SSH key instead. When an agent
+/// is running it is asked for the keys first, and given them once the
+/// vault is unlocked.
+/// # Arguments
+/// - `masterfile_path: &str`
+///     - Path to the masterfile
+/// - `stored_hash: &[u8]`
+///     - Hash of the password stored in the config file
+/// 
+/// Returns `Result<Arc<masterfile::MasterfileData>, anyhow::Error>`
+/// 
+pub fn read_vault_data(
+    masterfile_path: &str,
+    stored_hash: &[u8],
+) -> Result<Arc<masterfile::MasterfileData>, anyhow::Error> {
+    // Skip the password if the agent already holds the keys
+    if let Some(masterfile_data) = agent::fetch(masterfile_path) {
+        return Ok(masterfile_data.into_shared())
+    }
+
     // Get password and hash
-    let password = get_password_input("Enter vault password: ")?;
-    let hashed_password = hash_password_vec(password.clone())?;
+    let password = get_password_input("Enter vault password (leave empty to use an identity file): ")?;
+
+    // Unlock through a recipient slot with the identity file
+    let masterfile_data = if password.is_empty() {
+        let identity_path = get_input("Enter path of age identity or SSH private key: ")?;
+        let identities = recipients::load_identities(&identity_path)?;
+        masterfile::read_masterfile_with_identity(masterfile_path, &identities)?
+    } else {
+        let hashed_password = hash_password_vec(password.expose())?;
 
-    // Check if hash matches what is stored
-    if hashed_password != stored_hash {
-        return Err(anyhow!("Password does not match stored password"));
+        // Check if hash matches what is stored
+        if hashed_password != stored_hash {
+            return Err(anyhow!("Password does not match stored password"));
+        }
+        masterfile::read_masterfile
+            (masterfile_path, password.expose(), get_keyfile_path(masterfile_path)?.as_deref())?
+    };
+    agent::store(masterfile_path, &masterfile_data);
+    Ok(masterfile_data.into_shared())
+}
+
+///
+/// Get the keyfile for a vault that requires one, either from the command
+/// line or by asking for it.
+/// # Arguments
+/// - `masterfile_path: &str`
+///     - Path to the masterfile
+/// 
+/// Returns `Result<Option<String>, anyhow::Error>`
+/// 
+pub fn get_keyfile_path(masterfile_path: &str) -> Result<Option<String>, anyhow::Error> {
+    if !masterfile::requires_keyfile(masterfile_path)? {
+        return Ok(None)
+    }
+    match KEYFILE_PATH.get() {
+        Some(keyfile_path) => Ok(Some(keyfile_path.clone())),
+        None => Ok(Some(get_input("Enter path of the keyfile: ")?)),
+    }
+}
+
+///
+/// Show a recovery phrase so it can be written down and wait until the
+/// user is done, then clear the screen again.
+/// # Arguments
+/// - `phrase: &str`
+///     - Recovery phrase to show
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn print_recovery_phrase(phrase: &str) -> Result<(), anyhow::Error> {
+    println!("Recovery phrase. Write it down and keep it somewhere safe, anyone with it can unlock the vault:\n");
+    for (index, word) in phrase.split(' ').enumerate() {
+        println!("{:>2}. {}", index + 1, word);
+    }
+    println!();
+    get_input("Press enter once the phrase is written down")?;
+    print!("{}[2J", 27 as char);
+    Ok(())
+}
+
+///
+/// Recover a vault with its recovery phrase, or with Shamir shares of its
+/// keys, and set a new password. The new password slot requires the keyfile
+/// given on the command line, if any.
+/// # Arguments
+/// - `vault_path: &str`
+///     - Path to the top directory of the vault or its masterfile
+/// - `config_path: &str`
+///     - Path to the config file
+/// - `share_inputs: Option<Vec<String>>`
+///     - Shares or share files to recover with instead of the phrase.
+///       More are asked for until the threshold is reached
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn recover_vault(
+    vault_path: &str,
+    config_path: &str,
+    share_inputs: Option<Vec<String>>,
+) -> Result<(), anyhow::Error> {
+    let masterfile_path = if vault_path.ends_with("masterfile.e") {
+        vault_path.to_string()
+    } else {
+        format!("{}/masterfile.e", vault_path.trim_end_matches('/'))
+    };
+    if !masterfile::is_masterfile(&masterfile_path) {
+        return Err(anyhow!("{} is not a masterfile.", masterfile_path));
+    }
+
+    // Rebuild the keys from the shares or unlock the recovery slot
+    let masterfile_data = match share_inputs {
+        Some(share_inputs) => {
+            let mut vault_shares = Vec::new();
+            for share_input in share_inputs {
+                vault_shares.push(shares::parse_share(&shellexpand::tilde(&share_input))?);
+            }
+            loop {
+                if let Some((threshold, _, _)) = vault_shares.first() {
+                    if vault_shares.len() >= *threshold as usize {
+                        break;
+                    }
+                    println!("{} of {} shares entered.", vault_shares.len(), threshold);
+                }
+                let share_input = get_input("Enter a share or the path to a share file: ")?;
+                vault_shares.push(shares::parse_share(&share_input)?);
+            }
+            shares::combine_shares(&vault_shares, &masterfile_path)?
+        }
+        None => {
+            let phrase = get_password_input("Enter the recovery phrase: ")?;
+            masterfile::read_masterfile_with_recovery(&masterfile_path, phrase.expose())?
+        }
+    };
+
+    // Write the new password slot
+    let password = get_password_double("Enter new password for vault: ")?;
+    let keyfile_path = KEYFILE_PATH.get().map(|keyfile_path| keyfile_path.as_str());
+    masterfile::set_password_slot(&masterfile_path, &masterfile_data, password.expose(), keyfile_path)?;
+
+    // Update the stored hash of the password if the vault is in the config
+    update_stored_hash(config_path, &masterfile_path, &hash_password_string(password.expose())?)?;
+    println!("Password of the vault has been reset.");
+    Ok(())
+}
+
+///
+/// Search a directory tree for vaults, such as on a backup drive or in a
+/// synced folder, and offer to register the ones that are not in the
+/// config file yet. The password of each vault is checked against its
+/// masterfile before it is registered.
+/// # Arguments
+/// - `vaults: &mut Vec<Vault>`
+///     - Vector containing the Vault objects
+/// - `config_path: &str`
+///     - Path to the config file
+/// - `dir_path: &str`
+///     - Directory to search
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn discover_vaults(
+    vaults: &mut Vec<Vault>,
+    config_path: &str,
+    dir_path: &str,
+) -> Result<(), anyhow::Error> {
+    if !check_dir(dir_path) {
+        return Err(anyhow!("{} is not a directory.", dir_path));
+    }
+
+    // Search from the real path so vaults are registered with absolute paths
+    let dir_path = fs::canonicalize(dir_path)?;
+    println!("Searching {} for vaults", dir_path.display());
+    let mut found = Vec::new();
+    find_masterfiles(&dir_path, usize::MAX, &mut found);
+    if found.is_empty() {
+        println!("No vaults found.");
+        return Ok(())
+    }
+
+    for masterfile_path in found {
+        let top_dir_path = masterfile_path.strip_suffix("/masterfile.e").unwrap();
+
+        // Compare real paths so other spellings of a registered vault match
+        let real_path = fs::canonicalize(&masterfile_path)?;
+        let registered = vaults.iter().find(|vault| fs::canonicalize(&vault.master_file_path)
+            .is_ok_and(|path| path == real_path));
+        if let Some(vault) = registered {
+            println!("{} - registered as [{}]", top_dir_path, vault.name);
+            continue;
+        }
+        println!("{} - not registered", top_dir_path);
+        let register = get_input("Register this vault? [Y/N]")?;
+        if register.to_lowercase() != "y" {
+            continue;
+        }
+
+        // Suggest the name of the top directory
+        let default_name = Path::new(top_dir_path).file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let name = get_input(&format!("Enter name for the vault (leave empty for {}): ", default_name))?;
+        let name = if name.is_empty() { default_name } else { name };
+        if vaults.iter().any(|vault| vault.name == name) {
+            println!("A vault named [{}] is already registered, skipping.", name);
+            continue;
+        }
+
+        // Only register vaults the password actually opens
+        let password = get_password_input("Enter vault password (leave empty to use an identity file): ")?;
+        let opened = if password.is_empty() {
+            let identity_path = get_input("Enter path of age identity or SSH private key: ")?;
+            recipients::load_identities(&identity_path)
+                .and_then(|identities| masterfile::read_masterfile_with_identity(&masterfile_path, &identities))
+        } else {
+            get_keyfile_path(&masterfile_path).and_then(|keyfile_path|
+                masterfile::read_masterfile(&masterfile_path, password.expose(), keyfile_path.as_deref()))
+        };
+        if let Err(err) = opened {
+            println!("Could not open the vault, it was not registered: {}", err);
+            continue;
+        }
+
+        // Vaults created before UUIDs get one now, so they can be found if they move
+        let mut vault = Vault::new(name.clone(), masterfile_path.clone(), hash_password_vec(password.expose())?)?;
+        vault.uuid = masterfile::ensure_uuid(&masterfile_path)?;
+        vaults.push(vault);
+        write_vaults(vaults, config_path)?;
+        println!("Registered [{}].", name);
+    }
+    Ok(())
+}
+
+///
+/// Collect the masterfiles below a directory. Symbolic links are not
+/// followed and unreadable directories are skipped, and the search does
+/// not go into a vault once its masterfile is found.
+/// # Arguments
+/// - `dir_path: &Path`
+///     - Directory to search
+/// - `depth: usize`
+///     - Levels of directories below `dir_path` to search
+/// - `found: &mut Vec<String>`
+///     - Paths of the masterfiles found so far
+/// 
+fn find_masterfiles(dir_path: &Path, depth: usize, found: &mut Vec<String>) {
+    let masterfile_path = dir_path.join("masterfile.e");
+    if let Some(path) = masterfile_path.to_str() {
+        if masterfile_path.is_file() && masterfile::is_masterfile(path) {
+            found.push(path.to_string());
+            return;
+        }
+    }
+    if depth == 0 {
+        return;
+    }
+
+    let entries = match fs::read_dir(dir_path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let mut dirs = entries.filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    dirs.sort();
+    for dir in dirs {
+        find_masterfiles(&dir, depth - 1, found);
+    }
+}
+
+///
+/// Track the vaults by the UUID in their masterfile, and find the ones
+/// that moved, such as a vault on a drive that is mounted somewhere else.
+/// Vaults without a recorded UUID take the one of their masterfile, which
+/// is only read and never written here. A vault that is not at its last
+/// known path is searched for below the search roots and the usual mount
+/// points. Backups carry the same UUID, so the user picks one of the
+/// copies found, and the vault stays offline when none is picked.
+/// # Arguments
+/// - `vaults: &mut [Vault]`
+///     - Array of Vault objects
+/// - `search_roots: &[String]`
+///     - Directories to search besides the mount points
+/// 
+/// Returns `bool`, whether a vault changed and the config file should be
+/// written
+/// 
+pub fn locate_vaults(vaults: &mut [Vault], search_roots: &[String]) -> bool {
+    let mut changed = false;
+    let mut candidates: Option<Vec<String>> = None;
+
+    // Copies that are registered as vaults of their own are never offered
+    let registered = vaults.iter()
+        .map(|vault| vault.master_file_path.clone())
+        .collect::<Vec<_>>();
+    for vault in vaults.iter_mut() {
+        let found_uuid = masterfile::read_uuid(&vault.master_file_path).ok().flatten();
+        match &vault.uuid {
+            // Record the UUID of vaults registered before UUIDs
+            None => {
+                if found_uuid.is_some() {
+                    vault.uuid = found_uuid;
+                    changed = true;
+                }
+                continue;
+            }
+            Some(uuid) if found_uuid.as_ref() == Some(uuid) => continue,
+            Some(_) => (),
+        }
+
+        // Search the roots once for every vault that is missing
+        let candidates = candidates.get_or_insert_with(|| {
+            let mut found = Vec::new();
+            for root in search_roots.iter().map(|root| shellexpand::tilde(root).to_string())
+                .chain(mount_points()) {
+                find_masterfiles(Path::new(&root), LOCATE_DEPTH, &mut found);
+            }
+            found
+        });
+        let copies = candidates.iter()
+            .filter(|path| !registered.contains(*path))
+            .filter(|path| masterfile::read_uuid(path).ok().flatten() == vault.uuid)
+            .cloned()
+            .collect::<Vec<_>>();
+        match choose_copy(&vault.name, &copies) {
+            Some(master_file_path) => match vault.relocate(master_file_path.clone()) {
+                Ok(()) => {
+                    println!("Vault [{}] is now at {}", vault.name, master_file_path);
+                    changed = true;
+                }
+                Err(err) => {
+                    println!("Could not move vault [{}]: {}", vault.name, err);
+                    vault.status = 4;
+                }
+            },
+            None => vault.status = 4,
+        }
+    }
+    changed
+}
+
+///
+/// Ask the user which of the copies of a vault that moved to use.
+/// # Arguments
+/// - `name: &str`
+///     - Name of the vault
+/// - `copies: &[String]`
+///     - Masterfiles with the UUID of the vault
+/// 
+/// Returns `Option<String>`, the masterfile picked, `None` to keep the
+/// vault offline
+/// 
+fn choose_copy(name: &str, copies: &[String]) -> Option<String> {
+    if copies.is_empty() {
+        return None;
+    }
+    println!("Vault [{}] is not at its last known path. Copies with the same UUID were found:", name);
+    for (index, path) in copies.iter().enumerate() {
+        println!("{}: {}", index + 1, path);
+    }
+    let choice = get_input("Enter the number of the copy to use (leave empty to keep the vault offline): ")
+        .unwrap_or_default();
+    match choice.trim().parse::<usize>() {
+        Ok(index) if (1..=copies.len()).contains(&index) => Some(copies[index - 1].clone()),
+        _ => None,
+    }
+}
+
+///
+/// Get the directories removable media and network shares are usually
+/// mounted below
+/// 
+/// Returns `Vec<String>`
+/// 
+fn mount_points() -> Vec<String> {
+    let user = std::env::var("USER").unwrap_or_default();
+    vec![
+        format!("/media/{}", user),
+        format!("/run/media/{}", user),
+        "/mnt".to_string(),
+        "/Volumes".to_string(),
+    ]
+}
+
+///
+/// Replace the stored password hash of a vault in the config file.
+/// # Arguments
+/// - `config_path: &str`
+///     - Path to the config file
+/// - `masterfile_path: &str`
+///     - Path to the masterfile of the vault
+/// - `hashed_password: &str`
+///     - Hex encoded hash of the new password
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn update_stored_hash(
+    config_path: &str,
+    masterfile_path: &str,
+    hashed_password: &str,
+) -> Result<(), anyhow::Error> {
+    let mut config = config::load(config_path)?;
+    for vault in config.vaults.iter_mut().filter(|vault| vault.master_file_path == masterfile_path) {
+        vault.hashed_password = hex::decode(hashed_password)?;
+    }
+    config::save(config_path, &config)
+}
+
+///
+/// Rotate the keys of a vault. New keys are generated and every file and
+/// folder name is encrypted again with them, then the key slots are
+/// rewritten for the new keys and a new password. An interrupted rotation
+/// is resumed by running it again with the old password.
+/// # Arguments
+/// - `vault: &mut Vault`
+///     - Vault to rotate the keys of, its stored hash is updated
+/// - `config_path: &str`
+///     - Path to the config file
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn rekey_vault(
+    vault: &mut Vault,
+    config_path: &str,
+) -> Result<(), anyhow::Error> {
+    let masterfile_path = vault.master_file_path.clone();
+    let top_dir_path = masterfile_path.strip_suffix("/masterfile.e").unwrap().to_string();
+
+    // Only a vault that is fully locked or unlocked can be started on,
+    // a journal left without a pending rotation is from a finished one
+    let pending = masterfile::rekey_pending(&masterfile_path);
+    if pending {
+        println!("Resuming the unfinished key rotation of {}.", vault.name);
+    } else {
+        rekey::remove_journal(&top_dir_path)?;
+        if check_vault_status(&top_dir_path) == 2 {
+            return Err(anyhow!("Vault {} is partly locked. Lock or unlock it fully before rotating the keys.", vault.name));
+        }
+    }
+
+    let old_data = read_vault_data(&masterfile_path, &vault.hashed_password)?;
+    if let Some(checkout) = mirror::read_manifest(&top_dir_path, &old_data).ok().and_then(|manifest| manifest.checkout) {
+        return Err(anyhow!("Vault {} is open in {}. Close it before rotating the keys.", vault.name, checkout));
+    }
+    let password = get_password_double("Enter new password for vault: ")?;
+    let keyfile_path = get_input("Enter path of a keyfile for the new password (leave empty for none): ")?;
+    let keyfile_path = match keyfile_path.is_empty() {
+        true => None,
+        false => Some(shellexpand::tilde(&keyfile_path).to_string()),
+    };
+    if let Some(keyfile_path) = &keyfile_path {
+        if !Path::new(keyfile_path).exists() {
+            return Err(anyhow!("Keyfile {} does not exist.", keyfile_path));
+        }
+    }
+    let had_shares = masterfile::read_share_check(&masterfile_path)?.is_some();
+
+    // Record the new keys, then re-encrypt the files with them
+    let new_data = masterfile::start_rekey(&masterfile_path, &old_data, password.expose(), keyfile_path.as_deref())?.into_shared();
+    rekey::rekey_vault(&top_dir_path, &old_data, &new_data)?;
+
+    // Switch the masterfile over to the new keys
+    let phrase = masterfile::finish_rekey(&masterfile_path, &new_data, password.expose(), keyfile_path.as_deref())?;
+    rekey::remove_journal(&top_dir_path)?;
+    agent::store(&masterfile_path, &new_data);
+    update_stored_hash(config_path, &masterfile_path, &hash_password_string(password.expose())?)?;
+    vault.hashed_password = hash_password_vec(password.expose())?;
+
+    println!("Keys of {} have been rotated.", vault.name);
+    if had_shares {
+        println!("Earlier shares no longer work, split the keys again to hand out new ones.");
+    }
+    if let Some(phrase) = phrase {
+        println!("The recovery phrase was replaced.");
+        print_recovery_phrase(&phrase)?;
+    }
+    Ok(())
+}
+
+///
+/// Set the keyfile given on the command line.
+/// # Arguments
+/// - `keyfile_path: String`
+///     - Path to the keyfile
+/// 
+pub fn set_keyfile_path(keyfile_path: String) {
+    KEYFILE_PATH.get_or_init(|| keyfile_path);
+}
+
+///
+/// Lock or unlock a vault with already decrypted masterfile data.
+/// # Arguments
+/// - `masterfile_path: &str`
+///     - Path to the masterfile
+/// - `masterfile_data: &Arc<masterfile::MasterfileData>`
+///     - Data of the decrypted masterfile
+/// - `force_encrypt: bool`
+///     - Determines whether to encrypt or decrypt
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn process_vault(
+    masterfile_path: &str,
+    masterfile_data: &Arc<masterfile::MasterfileData>,
+    force_encrypt: bool,
+) -> Result<(), anyhow::Error> {
+    // Files are encrypted with two sets of keys until a rotation is finished
+    if masterfile::rekey_pending(masterfile_path) {
+        return Err(anyhow!("Key rotation of the vault was interrupted. Rotate the keys again to finish it."));
     }
-    // Read in the data from the masterfile and store in a data structure
-    let masterfile_data = 
-        masterfile::read_masterfile(&masterfile_path, 
-        &password)?;
 
     // Get the top of the directory tree
     let top_dir_path = masterfile_path.strip_suffix("/masterfile.e").unwrap().to_string();
+
+    // The files of a vault open in a working copy are changed by closing it
+    if let Some(checkout) = mirror::read_manifest(&top_dir_path, masterfile_data).ok().and_then(|manifest| manifest.checkout) {
+        return Err(anyhow!("The vault is open in {}. Close it first.", checkout));
+    }
     
     // This process tends to take some time so print the process steps
     // out in the terminal
@@ -421,8 +1019,39 @@ pub fn unlock_lock_vault(
         println!("Decrypting Files");
     }
 
+    // Flat vaults convert between the plaintext tree and the store
+    if masterfile_data.settings.layout == flatstore::LAYOUT_FLAT {
+        if force_encrypt {
+            flatstore::lock_vault(&top_dir_path, masterfile_data)?;
+        } else {
+            flatstore::unlock_vault(&top_dir_path, masterfile_data)?;
+        }
+        return Ok(())
+    }
+
+    process_tree(&top_dir_path, masterfile_data, force_encrypt)
+}
+
+///
+/// Lock or unlock the files and folder names below a directory of a vault
+/// with the tree layout. The name of the directory itself is left alone.
+/// # Arguments
+/// - `dir_path: &str`
+///     - Directory to process
+/// - `masterfile_data: &Arc<masterfile::MasterfileData>`
+///     - Data of the decrypted masterfile
+/// - `force_encrypt: bool`
+///     - Determines whether to encrypt or decrypt
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn process_tree(
+    dir_path: &str,
+    masterfile_data: &Arc<masterfile::MasterfileData>,
+    force_encrypt: bool,
+) -> Result<(), anyhow::Error> {
     // Recurse through the directory tree
-    dir_recur(&top_dir_path, &masterfile_data, force_encrypt)?;
+    dir_recur(dir_path, masterfile_data, force_encrypt)?;
 
     // Wait for all threads to be finished recurssing
     while GLOBAL_THREAD_COUNT.load(Ordering::SeqCst) != 0 {
@@ -437,7 +1066,7 @@ pub fn unlock_lock_vault(
     }
 
     // Encrypt/decrypt foldernames
-    folder_recur(&top_dir_path, &masterfile_data, force_encrypt)?;
+    folder_recur(dir_path, masterfile_data, force_encrypt)?;
 
     Ok(())
 }
@@ -452,8 +1081,18 @@ pub fn unlock_lock_vault(
 /// Returns `u8`
 /// 
 pub fn check_vault_status(path: &str) -> u8 {
-    // Initialize variables
-    let paths = fs::read_dir(path).unwrap();
+    // Flat vaults are checked by the presence of the store
+    if let Ok(settings) = masterfile::read_settings(&format!("{}/masterfile.e", path)) {
+        if settings.layout == flatstore::LAYOUT_FLAT {
+            return flatstore::check_vault_status(path);
+        }
+    }
+
+    // Initialize variables, a missing top directory means the vault is offline
+    let paths = match fs::read_dir(path) {
+        Ok(paths) => paths,
+        Err(_) => return 4,
+    };
     let mut en = 0; // Counter for encrypted files
     let mut de = 0; // Counter for decrypted files
 
@@ -466,9 +1105,11 @@ pub fn check_vault_status(path: &str) -> u8 {
                     en += 1;
                 }
 
-            // If file is plaintext increment the de counter
+            // If file is plaintext increment the de counter, the manifest
+            // of a mirror is always encrypted
             else if check_file(&x) && !x.ends_with("masterfile.e") 
-            && !x.ends_with(".DS_Store") && !x.ends_with(".encrypted") {
+            && !x.ends_with(".DS_Store") && !x.ends_with(".encrypted")
+            && !mirror::is_manifest(&x) {
                 de += 1;
             }
         }
@@ -505,24 +1146,14 @@ pub fn write_vaults(
     vaults: &[Vault],
     config_path: &str,
 ) -> Result<(), anyhow::Error> {
-    // Open the config file with options
-    let mut config_file = fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(&config_path)?;
-
-    // Write all the data in the vaults
-    for i in vaults {
-        config_file.write_all(format!
-            ("{},{},{}", i.name, i.master_file_path, 
-            hex::encode(i.hashed_password.clone()))
-            .as_bytes())?;
-    }
-    Ok(())
+    // Keep the global settings as they are
+    let mut config = config::load(config_path)?;
+    config.vaults = vaults.to_vec();
+    config::save(config_path, &config)
 }
 
 pub fn hash_password_string (
-    password: String,
+    password: &str,
 ) -> Result<String, anyhow::Error> {
     // Get hash of password
     let mut hasher = Sha256::new();
@@ -531,7 +1162,7 @@ pub fn hash_password_string (
 }
 
 pub fn hash_password_vec (
-    password: String,
+    password: &str,
 ) -> Result<Vec<u8>, anyhow::Error> {
     // Get hash of password
     let mut hasher = Sha256::new();
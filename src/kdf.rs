@@ -0,0 +1,207 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use std::time::{Duration, Instant};
+
+// Largest parameters accepted from a masterfile, so a damaged or hostile
+// masterfile can not make unlocking allocate or run without bound
+const MAX_MEM_COST: u32 = 4 * 1024 * 1024;
+const MAX_TIME_COST: u32 = 64;
+const MAX_LANES: u32 = 64;
+
+// Memory is not raised above this while calibrating, time is raised instead
+const MAX_CALIBRATED_MEM_COST: u32 = 1024 * 1024;
+
+// Lanes used by the profiles and by calibration
+const DEFAULT_LANES: u32 = 4;
+
+// Unlock time aimed for when calibrating without a target
+pub const DEFAULT_TARGET: Duration = Duration::from_secs(1);
+
+///
+/// Argon2id parameters used to derive the key of a password slot. They
+/// are stored in the masterfile so the key can be derived again on any
+/// machine.
+/// # Data
+/// - `mem_cost: u32`
+///     - Memory in KiB
+/// - `time_cost: u32`
+///     - Number of passes over the memory
+/// - `lanes: u32`
+///     - Degree of parallelism
+/// 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub mem_cost: u32,
+    pub time_cost: u32,
+    pub lanes: u32,
+}
+
+// Parameters of vaults created before they were stored in the masterfile
+impl Default for KdfParams {
+    fn default() -> KdfParams {
+        KdfParams { mem_cost: 16 * 1024, time_cost: 8, lanes: 8 }
+    }
+}
+
+// Named profiles that can be picked instead of calibrating
+pub const PROFILES: [(&str, KdfParams); 3] = [
+    ("interactive", KdfParams { mem_cost: 64 * 1024, time_cost: 2, lanes: DEFAULT_LANES }),
+    ("moderate", KdfParams { mem_cost: 256 * 1024, time_cost: 3, lanes: DEFAULT_LANES }),
+    ("paranoid", KdfParams { mem_cost: 1024 * 1024, time_cost: 4, lanes: DEFAULT_LANES }),
+];
+
+impl KdfParams {
+    ///
+    /// Build the argon2 config for these parameters
+    /// 
+    /// Returns `argon2::Config<'a>`
+    /// 
+    pub fn config<'a>(&self) -> argon2::Config<'a> {
+        argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            hash_length: 32,
+            lanes: self.lanes,
+            mem_cost: self.mem_cost,
+            time_cost: self.time_cost,
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// Serialize the parameters as `mem_cost, time_cost, lanes`, each a
+    /// u32 (BE)
+    /// 
+    /// Returns `[u8; 12]`
+    /// 
+    pub fn to_bytes(self) -> [u8; 12] {
+        let mut bytes = [0u8; 12];
+        bytes[..4].copy_from_slice(&self.mem_cost.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.time_cost.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.lanes.to_be_bytes());
+        bytes
+    }
+
+    ///
+    /// Parse serialized parameters, rejecting values argon2 can not use
+    /// or that are beyond the accepted limits.
+    /// # Arguments
+    /// - `bytes: &[u8]`
+    /// 
+    /// Returns `Result<KdfParams, anyhow::Error>`
+    /// 
+    pub fn from_bytes(bytes: &[u8]) -> Result<KdfParams, anyhow::Error> {
+        if bytes.len() != 12 {
+            return Err(anyhow!("Malformed key derivation parameters."));
+        }
+        let read_u32 = |offset: usize| u32::from_be_bytes(
+            [bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        let params = KdfParams { mem_cost: read_u32(0), time_cost: read_u32(4), lanes: read_u32(8) };
+
+        if params.lanes == 0 || params.lanes > MAX_LANES
+            || params.time_cost == 0 || params.time_cost > MAX_TIME_COST
+            || params.mem_cost < 8 * params.lanes || params.mem_cost > MAX_MEM_COST {
+            return Err(anyhow!("Unsupported key derivation parameters {}.", params.describe()));
+        }
+        Ok(params)
+    }
+
+    ///
+    /// Describe the parameters for display
+    /// 
+    /// Returns `String`
+    /// 
+    pub fn describe(&self) -> String {
+        format!("{} MiB, {} passes, {} lanes", self.mem_cost / 1024, self.time_cost, self.lanes)
+    }
+}
+
+///
+/// Look up a named profile.
+/// # Arguments
+/// - `name: &str`
+///     - Name of the profile, ignoring case
+/// 
+/// Returns `Option<KdfParams>`
+/// 
+pub fn profile(name: &str) -> Option<KdfParams> {
+    PROFILES.iter()
+        .find(|(profile_name, _)| profile_name.eq_ignore_ascii_case(name.trim()))
+        .map(|(_, params)| *params)
+}
+
+///
+/// Time a single key derivation with the given parameters.
+/// # Arguments
+/// - `params: &KdfParams`
+/// 
+/// Returns `Result<Duration, anyhow::Error>`
+/// 
+fn measure(params: &KdfParams) -> Result<Duration, anyhow::Error> {
+    let start = Instant::now();
+    argon2::hash_raw(b"rusty-vault calibration", &[0u8; 32], &params.config())?;
+    Ok(start.elapsed())
+}
+
+///
+/// Benchmark argon2 on this machine and choose parameters that take about
+/// the target time to derive a key. Memory is doubled first, as it is what
+/// makes guessing expensive, then passes are added to reach the target.
+/// # Arguments
+/// - `target: Duration`
+///     - Time a single unlock should take
+/// 
+/// Returns `Result<KdfParams, anyhow::Error>`
+/// 
+pub fn calibrate(target: Duration) -> Result<KdfParams, anyhow::Error> {
+    let mut params = KdfParams { mem_cost: 16 * 1024, time_cost: 1, lanes: DEFAULT_LANES };
+
+    // Grow the memory while a single pass stays well under the target
+    let mut elapsed = measure(&params)?;
+    while elapsed * 4 < target && params.mem_cost < MAX_CALIBRATED_MEM_COST {
+        params.mem_cost *= 2;
+        elapsed = measure(&params)?;
+    }
+
+    // Add passes, the time grows about linearly with them
+    let passes = target.as_secs_f64() / elapsed.as_secs_f64().max(f64::EPSILON);
+    params.time_cost = (passes.round() as u32).clamp(1, MAX_TIME_COST);
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params_round_trip() {
+        for (_, params) in PROFILES {
+            assert!(KdfParams::from_bytes(&params.to_bytes()).unwrap() == params);
+        }
+        let default = KdfParams::default();
+        assert!(KdfParams::from_bytes(&default.to_bytes()).unwrap() == default);
+        assert!(profile("moderate").is_some());
+        assert!(profile("unknown").is_none());
+    }
+
+    #[test]
+    fn rejected_params() {
+        let valid = KdfParams { mem_cost: 64, time_cost: 1, lanes: 8 };
+        assert!(KdfParams::from_bytes(&valid.to_bytes()).is_ok());
+        for params in [
+            KdfParams { lanes: 0, ..valid },
+            KdfParams { lanes: MAX_LANES + 1, mem_cost: 8 * (MAX_LANES + 1), ..valid },
+            KdfParams { time_cost: 0, ..valid },
+            KdfParams { time_cost: MAX_TIME_COST + 1, ..valid },
+            KdfParams { mem_cost: 63, ..valid },
+            KdfParams { mem_cost: MAX_MEM_COST + 1, ..valid },
+        ] {
+            assert!(KdfParams::from_bytes(&params.to_bytes()).is_err(), "{}", params.describe());
+        }
+
+        // Truncated or overlong records
+        let bytes = valid.to_bytes();
+        assert!(KdfParams::from_bytes(&bytes[..11]).is_err());
+        assert!(KdfParams::from_bytes(&[&bytes[..], &[0]].concat()).is_err());
+        assert!(KdfParams::from_bytes(&[]).is_err());
+    }
+}
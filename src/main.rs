@@ -11,16 +11,41 @@ encrypt/decrypt all files and folders therein.
 #![allow(non_snake_case)]
 
 // import functions from other files
+mod agent;
+mod autolock;
+mod checkout;
+mod cipher;
+mod cli;
+mod config;
 mod functions;
+mod cryptomator;
 mod masterfile;
 mod encryptionFunctions;
+mod flatstore;
+mod kdf;
+mod memory;
+mod mirror;
+mod padding;
+mod recipients;
+mod registry;
+mod rekey;
+mod secret;
+mod shares;
 mod vault;
+mod watch;
+mod wipe;
+#[cfg(test)]
+mod testutil;
 use vault::vault::Vault;
 
 // import external crates
+use anyhow::anyhow;
+use clap::Parser;
 use colored::Colorize;
 use std::{
     fs, 
+    path::Path,
+    time::Duration,
 };
 
 
@@ -52,7 +77,9 @@ fn main_menu(
 [2] Create Vault
 [3] Add Existing Vault
 [4] Delete Vault
-[5] Quit")?;
+[5] Manage Vault Keys
+[6] Cryptomator Vaults
+[7] Quit")?;
             if input == "1" {
                 // Call function to lock/unlock vaults and recheck the file status
                 vault_unlock_stage(vaults)?;
@@ -67,20 +94,33 @@ fn main_menu(
             else if input == "4" {
                 vault_remove_stage(vaults, config_file)?;
             }
-            else if input == "5" || input.to_lowercase() == "q" ||
+            else if input == "5" {
+                vault_keys_stage(vaults, config_file)?;
+                recheck_vault_status(vaults)?;
+            }
+            else if input == "6" {
+                cryptomator_stage(vaults, config_file)?;
+                recheck_vault_status(vaults)?;
+            }
+            else if input == "7" || input.to_lowercase() == "q" ||
                 input.to_lowercase() == "quit" {break}
         }
         else {
             let input = functions::get_input("[1] Create Vault
 [2] Add Existing Vault
-[3] Quit")?;
+[3] Cryptomator Vaults
+[4] Quit")?;
             if input == "1" {
                 functions::create_vault(vaults, config_file)?;
             }
             else if input == "2" {
                 add_existing_vault(vaults, config_file)?;
             }
-            else if input == "3" || input.to_lowercase() == "q" ||
+            else if input == "3" {
+                cryptomator_stage(vaults, config_file)?;
+                recheck_vault_status(vaults)?;
+            }
+            else if input == "4" || input.to_lowercase() == "q" ||
                 input.to_lowercase() == "quit" {break}
         }
     }
@@ -106,6 +146,7 @@ fn vault_unlock_stage(
     let LOCKED = "LOCKED".to_string().green();
     let UNLOCKED = "UNLOCKED".to_string().yellow();
     let MIXED = "MIXED".to_string().red();
+    let OFFLINE = "OFFLINE".to_string().dimmed();
     let UNKNOWN = "STATUS UNKNOWN".to_string().red();
 
     // Instantiate a container to hold an index and a reference to 
@@ -118,6 +159,7 @@ fn vault_unlock_stage(
         if i.status == 0 {println!("[{}] {} - {}", counter, i.name, LOCKED);}
         else if i.status == 1 {println!("[{}] {} - {}", counter, i.name, UNLOCKED);}
         else if i.status == 2 {println!("[{}] {} - {}", counter, i.name, MIXED);}
+        else if i.status == 4 {println!("[{}] {} - {}", counter, i.name, OFFLINE);}
         else {println!("[{}] {} - {}", counter, i.name, UNKNOWN);}
 
         // Save the vault to the temporary structure
@@ -164,7 +206,7 @@ fn vault_unlock_stage(
 ///     - Staticly lived reference to a Vault object
 /// - `index: String`
 ///     - The index for reference to a printed array.
-///     Saved as a string to make comparison to input value easier.
+///       Saved as a string to make comparison to input value easier.
 /// 
 struct VaultStage<'a>{
     pub vault_ref: &'a Vault,
@@ -215,6 +257,7 @@ fn vault_remove_stage(
     let LOCKED = "LOCKED".to_string().green();
     let UNLOCKED = "UNLOCKED".to_string().yellow();
     let MIXED = "MIXED".to_string().red();
+    let OFFLINE = "OFFLINE".to_string().dimmed();
     let UNKNOWN = "STATUS UNKNOWN".to_string().red();
 
     // Print vaults and their status
@@ -222,6 +265,7 @@ fn vault_remove_stage(
         if i.status == 0 {println!("[{}] {} - {}", counter, i.name, LOCKED);}
         else if i.status == 1 {println!("[{}] {} - {}", counter, i.name, UNLOCKED);}
         else if i.status == 2 {println!("[{}] {} - {}", counter, i.name, MIXED);}
+        else if i.status == 4 {println!("[{}] {} - {}", counter, i.name, OFFLINE);}
         else {println!("[{}] {} - {}", counter, i.name, UNKNOWN);}
         counter += 1;
     }
@@ -236,26 +280,61 @@ fn vault_remove_stage(
     }
 
     let index = input.parse::<i32>().unwrap() - 1;
-    let confirmation = functions::get_input(&format!
-        ("The vault {} will be unlocked if locked and deleted. Are you sure this is what you want? [Y/N]", 
-            vaults[index as usize].name)[..])?;
-    
-    if confirmation.to_lowercase() == "y" {
-        // Get a copy of the master file path
-        let master_file_path = vaults[index as usize].master_file_path.clone();
-        let hashed_password = vaults[index as usize].hashed_password.clone();
-
-        // Unlock the vault if locked
-        if vaults[index as usize].status == 0 {
-            functions::unlock_lock_vault(master_file_path.clone(), false, hashed_password)?;
+    let vault = vaults[index as usize].clone();
+
+    // Choose how much of the vault goes away with it
+    println!("[1] Forget - remove [{}] from the list and leave its files as they are", vault.name);
+    println!("[2] Decrypt and remove - unlock [{}] and delete its masterfile", vault.name);
+    println!("[3] Crypto-shred - destroy the keys of [{}] so its files can never be decrypted", vault.name);
+    let mode = functions::get_input("[4] Return to Main Menu")?;
+
+    match &mode[..] {
+        "1" => {
+            let confirmation = functions::get_input(&format!
+                ("The vault {} will be removed from the list. Are you sure this is what you want? [Y/N]",
+                    vault.name)[..])?;
+            if confirmation.to_lowercase() != "y" {
+                return Ok(())
+            }
         }
+        "2" => {
+            let confirmation = functions::get_input(&format!
+                ("The vault {} will be unlocked if locked and deleted. Are you sure this is what you want? [Y/N]",
+                    vault.name)[..])?;
+            if confirmation.to_lowercase() != "y" {
+                return Ok(())
+            }
 
-        // Remove the master file, remove the vault from the array, and 
-        // write the vaults array to the config file
-        fs::remove_file(master_file_path)?;
-        vaults.remove(index as usize);
-        functions::write_vaults(vaults, config_path)?;
+            // Unlock the vault if locked
+            if vault.status == 0 {
+                functions::unlock_lock_vault(vault.master_file_path.clone(), false, vault.hashed_password.clone())?;
+            }
+
+            // Remove the master file
+            fs::remove_file(&vault.master_file_path)?;
+        }
+        "3" => {
+            // Shares hold the keys themselves and outlive the masterfile
+            if masterfile::read_share_check(&vault.master_file_path).ok().flatten().is_some() {
+                println!("{}", "The keys of this vault were split into recovery shares. Destroy the shares as well, \
+                    enough of them can still decrypt the files.".red());
+            }
+            let confirmation = functions::get_input(&format!
+                ("The vault {} will be locked if unlocked and its keys destroyed. The files can NOT be recovered afterwards. \
+                    Type the name of the vault to confirm",
+                    vault.name)[..])?;
+            if confirmation != vault.name {
+                return Ok(())
+            }
+            functions::shred_vault(&vault)?;
+        }
+        _ => return Ok(()),
     }
+
+    // Remove the vault from the array, and write the vaults array to the
+    // config file
+    vaults.remove(index as usize);
+    functions::write_vaults(vaults, config_path)?;
     Ok(())
 }
 
@@ -286,13 +365,13 @@ fn add_existing_vault(
     }
 
     let name = functions::get_input("Enter name for new vault: ")?;
-    let password = functions::get_password_double("Enter Vault password: ")?;
+    let password = functions::get_password_double("Enter Vault password (leave empty if you unlock with an identity file): ")?;
 
-    // Check that the path is correct and the size of the file is correct (192 bytes)
-    if path_to_create.ends_with("masterfile.e") && fs::metadata(path_to_create.clone())?.len() == 192 {
+    // Check that the path is correct and the file is a masterfile
+    if path_to_create.ends_with("masterfile.e") && masterfile::is_masterfile(&path_to_create) {
         // Push the new info to the vaults array and write the array to the config file
         vaults.push(
-            Vault::new(name, path_to_create, functions::hash_password_vec(password)?)
+            Vault::new(name, path_to_create, functions::hash_password_vec(password.expose())?)?
         );
         functions::write_vaults(vaults, config_path)?;
     } else {
@@ -301,6 +380,194 @@ fn add_existing_vault(
     Ok(())
 }
 
+///
+/// Called by the main menu to stage managing the key slots of a vault.
+/// Recipients are age or SSH public keys whose identities can unlock
+/// the vault without the password. The recovery phrase and Shamir shares
+/// of the keys can reset it. Rotating the keys encrypts the vault again.
+/// # Arguments
+/// - `vaults: &mut [Vault]`
+///     - Array of Vault objects
+/// - `config_file: &str`
+///     - Path to the config file
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn vault_keys_stage(
+    vaults: &mut [Vault],
+    config_file: &str,
+) -> Result<(), anyhow::Error> {
+    // Clear the terminal and print the header
+    print!("{}[2J", 27 as char);
+    println!("##Manage Vault Keys##");
+
+    // Print the vaults and get the one to manage
+    for (index, i) in vaults.iter().enumerate() {
+        println!("[{}] {}", index + 1, i.name);
+    }
+    let input = functions::get_input(&format!("[{}] Return to Main Menu", vaults.len() + 1)[..])?;
+    let vault = match input.parse::<usize>() {
+        Ok(index) if index >= 1 && index <= vaults.len() => &mut vaults[index - 1],
+        _ => return Ok(()),
+    };
+
+    let input = functions::get_input("[1] List Recipients
+[2] Add Recipient
+[3] Remove Recipient
+[4] Create Recovery Phrase
+[5] Split Keys into Shares
+[6] Rotate Keys
+[7] Return to Main Menu")?;
+
+    if input == "1" {
+        let recipients = masterfile::list_recipients(&vault.master_file_path)?;
+        if recipients.is_empty() {
+            println!("Vault {} has no recipients.", vault.name);
+        }
+        for recipient in recipients {
+            println!("{}", recipient);
+        }
+        functions::get_input("Press enter to return to Main Menu")?;
+    } else if input == "2" {
+        // Check the key before asking for the password
+        let recipient = functions::get_input("Enter age or SSH public key of the recipient: ")?;
+        recipients::parse_recipient(&recipient)?;
+        let masterfile_data = functions::read_vault_data
+            (&vault.master_file_path, &vault.hashed_password)?;
+        masterfile::add_recipient(&vault.master_file_path, &masterfile_data, &recipient)?;
+        println!("Added recipient to {}.", vault.name);
+    } else if input == "3" {
+        let recipient = functions::get_input("Enter public key of the recipient to remove: ")?;
+        if masterfile::remove_recipient(&vault.master_file_path, &recipient)? {
+            println!("Removed recipient from {}.", vault.name);
+        } else {
+            println!("{} is not a recipient of {}.", recipient, vault.name);
+        }
+    } else if input == "4" {
+        // A new phrase replaces any earlier one
+        let masterfile_data = functions::read_vault_data
+            (&vault.master_file_path, &vault.hashed_password)?;
+        let phrase = masterfile::set_recovery_slot(&vault.master_file_path, &masterfile_data)?;
+        functions::print_recovery_phrase(&phrase)?;
+    } else if input == "5" {
+        let count = functions::get_input("Enter the number of shares to create: ")?.parse::<u8>()?;
+        let threshold = functions::get_input("Enter the number of shares needed to recover the vault: ")?.parse::<u8>()?;
+        let masterfile_data = functions::read_vault_data
+            (&vault.master_file_path, &vault.hashed_password)?;
+        let vault_shares = shares::split_key(&vault.master_file_path, &masterfile_data, threshold, count)?;
+
+        // Print the shares or write each of them to its own file
+        let output = functions::get_input("[1] Print Shares
+[2] Write Shares to Files")?;
+        if output == "2" {
+            let dist_path = functions::get_input("Enter directory to write the shares to: ")?;
+            shares::write_shares(&dist_path, &vault.name, &vault_shares)?;
+            println!("Wrote {} shares to {}.", count, dist_path);
+        } else {
+            for (index, share) in vault_shares.iter().enumerate() {
+                println!("Share {}: {}\n", index + 1, share);
+            }
+            functions::get_input("Press enter once the shares are handed out")?;
+            print!("{}[2J", 27 as char);
+        }
+    } else if input == "6" {
+        functions::rekey_vault(vault, config_file)?;
+        functions::get_input("Press enter to return to Main Menu")?;
+    }
+    Ok(())
+}
+
+///
+/// Called by the main menu to stage working with Cryptomator vaults.
+/// Cryptomator vaults can be listed, extracted, or converted into a new
+/// rusty-vault, and a rusty-vault can be exported as a Cryptomator vault.
+/// # Arguments
+/// - `vaults: &mut Vec<Vault>`
+///     - Array of Vault objects
+/// - `config_path: &str`
+///     - Path to the config file storing vault info
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn cryptomator_stage(
+    vaults: &mut Vec<Vault>,
+    config_path: &str,
+) -> Result<(), anyhow::Error> {
+    // Clear the terminal and print the header
+    print!("{}[2J", 27 as char);
+    println!("##Cryptomator Vaults##");
+
+    let input = functions::get_input("[1] List Cryptomator Vault
+[2] Extract Cryptomator Vault
+[3] Convert Cryptomator Vault to New Vault
+[4] Export Vault to Cryptomator
+[5] Return to Main Menu")?;
+
+    if input == "1" || input == "2" || input == "3" {
+        // Open the Cryptomator vault with its password
+        let cryptomator_path = functions::get_input("Enter path of the Cryptomator vault: ")?;
+        let password = functions::get_password_input("Enter Cryptomator vault password: ")?;
+        let cryptomator_vault = cryptomator::CryptomatorVault::open(&cryptomator_path, password.expose())?;
+
+        if input == "1" {
+            cryptomator::list(&cryptomator_vault, "", "")?;
+            functions::get_input("Press enter to return to Main Menu")?;
+        } else {
+            let dist_path = functions::get_input("Enter path to extract the files to: ")?;
+            println!("Extracting Files");
+            cryptomator::extract(&cryptomator_vault, "", &dist_path)?;
+
+            // Create a new vault around the extracted files
+            if input == "3" {
+                functions::create_vault_at(vaults, config_path, &dist_path)?;
+            }
+        }
+    } else if input == "4" {
+        // Print the vaults and get the one to export
+        for (index, i) in vaults.iter().enumerate() {
+            println!("[{}] {}", index + 1, i.name);
+        }
+        let index = functions::get_input("Enter the vault to export: ")?;
+        let vault = match index.parse::<usize>() {
+            Ok(index) if index >= 1 && index <= vaults.len() => &vaults[index - 1],
+            _ => return Ok(()),
+        };
+        let dist_path = functions::get_input("Enter path for the new Cryptomator vault: ")?;
+        let password = functions::get_password_double("Enter password for the Cryptomator vault: ")?;
+        let cryptomator_vault = cryptomator::CryptomatorVault::create(&dist_path, password.expose())?;
+        let top_dir_path = vault.master_file_path.strip_suffix("/masterfile.e").unwrap().to_string();
+
+        // A locked vault is decrypted file by file straight into the export
+        if vault.status == 0 {
+            let masterfile_data = functions::read_vault_data
+                (&vault.master_file_path, &vault.hashed_password)?;
+            cryptomator::export_locked(&cryptomator_vault, &top_dir_path, &masterfile_data, "")?;
+        } else if vault.status == 1 {
+            cryptomator::import_tree(&cryptomator_vault, &top_dir_path, "")?;
+        } else {
+            println!("Vault must be fully locked or unlocked to export.");
+        }
+    }
+    Ok(())
+}
+
+///
+/// Find a vault of the config file given on the command line.
+/// # Arguments
+/// - `vaults: &mut [Vault]`
+/// - `vault: &str`
+///     - Name of the vault, its top directory or the path to its masterfile
+/// 
+/// Returns `Result<&mut Vault, anyhow::Error>`
+/// 
+fn find_vault<'a>(vaults: &'a mut [Vault], vault: &str) -> Result<&'a mut Vault, anyhow::Error> {
+    let vault_path = shellexpand::tilde(vault).trim_end_matches('/').to_string();
+    vaults.iter_mut()
+        .find(|i| i.name == vault_path || i.path.trim_end_matches('/') == vault_path
+            || i.master_file_path == vault_path)
+        .ok_or_else(|| anyhow!("{} is not a vault in the config file.", vault_path))
+}
+
 ///
 /// Main function of the program. Will call for creation of the Vaults array and pass
 /// it to the main menu function when called.
@@ -308,14 +575,157 @@ fn add_existing_vault(
 /// Returns `Result<(), anyhow::Error>`
 /// 
 fn main() -> Result<(), anyhow::Error> {
-    // Expand the path to the config file 
-    let config_path = shellexpand::tilde("~/.rusty-vault/config").to_string();
+    // Keep keys and passwords out of core dumps and debuggers
+    memory::harden_process()?;
+
+    // Parse the command line arguments
+    let args = cli::Cli::parse();
+    if let Some(keyfile_path) = args.keyfile {
+        functions::set_keyfile_path(shellexpand::tilde(&keyfile_path).to_string());
+    }
+
+    // Find the config file, from the command line, the environment or
+    // the XDG config directory
+    let config_path = config::config_path(args.config.as_deref());
 
     // Verify the config file exists, if not it will be created
-    functions::check_config_file(&config_path)?;
+    config::check_config_file(&config_path)?;
+
+    // Read the global settings and create the vaults array
+    let config = config::load(&config_path)?;
+    let mut vaults: Vec<Vault> = config.vaults;
+
+    // Overwrite plaintext before removing it if asked to
+    wipe::set_passes(args.secure_delete.or(config.settings.secure_delete_passes).unwrap_or(0));
 
-    // Create the vaults array
-    let mut vaults: Vec<Vault> = functions::read_config_file(&config_path);
+    // Find vaults that moved since they were last seen
+    if functions::locate_vaults(&mut vaults, &config.settings.search_roots) {
+        config::save(&config_path, &config::Config { settings: config.settings.clone(), vaults: vaults.clone() })?;
+    }
+
+    // Run a command given on the command line instead of the menus
+    match args.command {
+        Some(cli::Command::Recover { vault, shares }) => {
+            return functions::recover_vault(&shellexpand::tilde(&vault), &config_path, shares)
+        }
+        Some(cli::Command::Rekey { vault }) => {
+            let vault = find_vault(&mut vaults, &vault)?;
+            return functions::rekey_vault(vault, &config_path)
+        }
+        Some(cli::Command::Agent { timeout, action }) => {
+            return match action {
+                None => {
+                    let timeout = timeout.or(config.settings.agent_timeout).unwrap_or(agent::DEFAULT_TIMEOUT);
+                    agent::run(Duration::from_secs(timeout))
+                }
+                Some(cli::AgentAction::Lock) => {
+                    agent::lock()?;
+                    println!("Agent locked, all keys forgotten.");
+                    Ok(())
+                }
+                Some(cli::AgentAction::Forget { vault }) => {
+                    let vault = find_vault(&mut vaults, &vault)?;
+                    if agent::forget(&vault.master_file_path)? {
+                        println!("Keys of [{}] forgotten.", vault.name);
+                    } else {
+                        println!("The agent does not hold the keys of [{}].", vault.name);
+                    }
+                    Ok(())
+                }
+            }
+        }
+        Some(cli::Command::Autolock { vault, idle, at, logout, off }) => {
+            let vault = find_vault(&mut vaults, &vault)?;
+            if off {
+                vault.autolock = autolock::AutoLock::default();
+            }
+            if let Some(minutes) = idle {
+                vault.autolock.idle_minutes = Some(minutes);
+            }
+            if let Some(at) = at {
+                vault.autolock.at = Some(autolock::parse_time(&at)?);
+            }
+            if logout {
+                vault.autolock.on_logout = true;
+            }
+            if vault.autolock.is_set() {
+                println!("Auto-lock of [{}]: {}", vault.name, vault.autolock);
+            } else {
+                println!("Auto-lock of [{}] is off.", vault.name);
+            }
+            return functions::write_vaults(&vaults, &config_path)
+        }
+        Some(cli::Command::Supervise) => {
+            return autolock::supervise(&config_path)
+        }
+        Some(cli::Command::Registry { action }) => {
+            match action {
+                cli::RegistryAction::Protect => {
+                    let passphrase = functions::get_password_double("Enter new registry passphrase: ")?;
+                    if passphrase.is_empty() {
+                        return Err(anyhow!("The registry passphrase can not be empty."));
+                    }
+                    registry::protect(&config_path, passphrase.expose())?;
+                    config::save(&config_path, &config::Config { settings: config.settings, vaults })?;
+                    println!("The registry is protected, the vaults are only listed after entering the passphrase.");
+                    if Path::new(&format!("{}.bak", config_path)).exists() {
+                        println!("##Warning##");
+                        println!("{}.bak still lists the vaults in plain text, delete it if it is no longer needed.", config_path);
+                    }
+                }
+                cli::RegistryAction::Unprotect => {
+                    registry::unprotect(&config_path);
+                    config::save(&config_path, &config::Config { settings: config.settings, vaults })?;
+                    println!("The registry is no longer protected.");
+                }
+            }
+            return Ok(())
+        }
+        Some(cli::Command::Discover { dir }) => {
+            return functions::discover_vaults(&mut vaults, &config_path, &shellexpand::tilde(&dir))
+        }
+        Some(cli::Command::Mirror { source, vault }) => {
+            let vault = find_vault(&mut vaults, &vault)?;
+            if vault.status == 1 || vault.status == 2 {
+                return Err(anyhow!("The vault [{}] is unlocked. Lock it before mirroring into it.", vault.name));
+            }
+            let masterfile_data = functions::read_vault_data(&vault.master_file_path, &vault.hashed_password)?;
+            let stats = mirror::sync(&shellexpand::tilde(&source), &vault.master_file_path, &masterfile_data)?;
+            println!("Mirrored into [{}]: {} encrypted, {} unchanged, {} removed",
+                vault.name, stats.encrypted, stats.unchanged, stats.removed);
+            return Ok(())
+        }
+        Some(cli::Command::Open { vault, to }) => {
+            let vault = find_vault(&mut vaults, &vault)?;
+            let dist_path = match to {
+                Some(to) => shellexpand::tilde(&to).to_string(),
+                None => checkout::default_dir(&vault.name)?,
+            };
+            let masterfile_data = functions::read_vault_data(&vault.master_file_path, &vault.hashed_password)?;
+            let count = checkout::open(&vault.master_file_path, &masterfile_data, &dist_path)?;
+            println!("Opened [{}] in {} with {} files. Run close when you are done.", vault.name, dist_path, count);
+            return Ok(())
+        }
+        Some(cli::Command::Close { vault, discard }) => {
+            let vault = find_vault(&mut vaults, &vault)?;
+            let masterfile_data = functions::read_vault_data(&vault.master_file_path, &vault.hashed_password)?;
+            if discard {
+                checkout::discard(&vault.master_file_path, &masterfile_data)?;
+                println!("Closed [{}] without keeping the changes in the working copy.", vault.name);
+                return Ok(())
+            }
+            let stats = checkout::close(&vault.master_file_path, &masterfile_data)?;
+            println!("Closed [{}]: {} encrypted, {} unchanged, {} removed",
+                vault.name, stats.encrypted, stats.unchanged, stats.removed);
+            return Ok(())
+        }
+        Some(cli::Command::Watch { vault }) => {
+            let vault = find_vault(&mut vaults, &vault)?;
+            let masterfile_data = functions::read_vault_data(&vault.master_file_path, &vault.hashed_password)?;
+            return watch::watch_vault(&vault.master_file_path, &masterfile_data)
+        }
+        None => (),
+    }
 
     // Check the vaults file status
     recheck_vault_status(&mut vaults)?;
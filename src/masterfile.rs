@@ -1,8 +1,12 @@
 // Import functions from external crates
+use anyhow::anyhow;
 use rand::{rngs::OsRng, RngCore,};
+use sha2::{Digest, Sha256};
 use std::{
     fs,
-    io::{Read, Write},
+    io::{self, Read, Write},
+    path::Path,
+    sync::Arc,
 };
 use chacha20poly1305::{
     aead::{Aead, NewAead, generic_array::GenericArray},
@@ -11,11 +15,67 @@ use chacha20poly1305::{
 use zeroize::Zeroize;
 
 // Import functions from files
-use crate::functions;
+use crate::{cipher, functions, kdf, memory, recipients, wipe};
 
-// TODO: Remove this
-#[allow(unused_imports)]
-#[allow(unused_assignments)]
+// Masterfiles written before the record format are exactly this many bytes
+pub const LEGACY_MASTERFILE_LEN: u64 = 192;
+
+// Magic and version at the start of every record based masterfile
+const MASTERFILE_MAGIC: &[u8; 4] = b"RVMF";
+const MASTERFILE_VERSION: u8 = 2;
+
+// Record tags. Every record is written as `tag: u8, len: u16 (BE), value`
+const TAG_PASSWORD_SLOT: u8 = 0x01;
+const TAG_RECIPIENT_SLOT: u8 = 0x02;
+const TAG_KEYFILE_PASSWORD_SLOT: u8 = 0x03;
+const TAG_RECOVERY_SLOT: u8 = 0x04;
+const TAG_SHARE_CHECK: u8 = 0x05;
+const TAG_REKEY_PENDING: u8 = 0x06;
+const TAG_COMPRESSION: u8 = 0x10;
+const TAG_PADDING: u8 = 0x11;
+const TAG_LAYOUT: u8 = 0x12;
+const TAG_KDF: u8 = 0x13;
+const TAG_CIPHER: u8 = 0x14;
+const TAG_UUID: u8 = 0x15;
+
+// Domain separation for the key derived from a recovery phrase
+const RECOVERY_KEY_CONTEXT: &[u8] = b"rusty-vault recovery key";
+
+
+// Passes of random data written over a masterfile that is destroyed
+const SHRED_PASSES: u32 = 3;
+
+// Length of the key material wrapped by a key slot
+// master_key (32) + folder_salt (32) + folder_nonce (24)
+pub const KEY_MATERIAL_LEN: usize = 88;
+
+///
+/// Define struct to hold the per vault settings stored in the masterfile.
+/// These are written unencrypted so they can be read without the password.
+/// # Data
+/// - `compression: u8`
+///     - zstd level used when encrypting files
+///         - 0: compression disabled
+///         - 1-19: zstd compression level
+/// - `padding: u8`
+///     - Padding scheme used to hide file lengths, see `padding::padded_length`
+/// - `layout: u8`
+///     - How the vault is stored when locked
+///         - 0: tree, files and folders are encrypted in place
+///         - 1: flat, everything is moved into a content addressed store
+/// - `kdf: kdf::KdfParams`
+///     - Argon2 parameters of the password slots
+/// - `cipher: cipher::CipherSuite`
+///     - AEAD used for the key slots, files and names
+/// 
+#[derive(Clone, Copy, Default)]
+pub struct VaultSettings {
+    pub compression: u8,
+    pub padding: u8,
+    pub layout: u8,
+    pub kdf: kdf::KdfParams,
+    pub cipher: cipher::CipherSuite,
+}
 
 ///
 /// Define struct to hold the decrypted data from the masterfile.
@@ -23,12 +83,400 @@ use crate::functions;
 /// - `master_key: [u8;32]`
 /// - `folder_salt: [u8; 32]`
 /// - `folder_nonce: [u8; 24]`
+/// - `settings: VaultSettings`
+/// 
+/// The keys are wiped when the data is dropped. It is not `Clone`, share it
+/// between threads with an `Arc` from `into_shared` instead of copying the
+/// keys.
 /// 
-#[derive(Clone)]
 pub struct MasterfileData {
     pub master_key: [u8; 32],
     pub folder_salt: [u8; 32],
     pub folder_nonce: [u8; 24],
+    pub settings: VaultSettings,
+    locked: bool,
+}
+
+impl MasterfileData {
+    ///
+    /// Move the data into an `Arc` to share it between threads, and lock
+    /// it in RAM now that it no longer moves.
+    /// 
+    /// Returns `Arc<MasterfileData>`
+    /// 
+    pub fn into_shared(self) -> Arc<MasterfileData> {
+        let mut shared = Arc::new(self);
+        if let Some(data) = Arc::get_mut(&mut shared) {
+            data.locked = memory::lock(data);
+        }
+        shared
+    }
+}
+
+impl Drop for MasterfileData {
+    fn drop(&mut self) {
+        self.master_key.zeroize();
+        self.folder_salt.zeroize();
+        self.folder_nonce.zeroize();
+        if self.locked {
+            memory::unlock(self);
+        }
+    }
+}
+
+///
+/// Build the path of the masterfile from the top directory of a vault.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the top directory of the vault
+/// 
+/// Returns `String`
+/// 
+fn masterfile_path(path: &str) -> String {
+    if path.ends_with('/') {
+        format!("{}masterfile.e", path)
+    } else {
+        format!("{}/masterfile.e", path)
+    }
+}
+
+///
+/// Write the contents of a masterfile next to it and move it into place,
+/// so an interrupted write never leaves a damaged masterfile behind.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// - `contents: &[u8]`
+///     - Full contents of the masterfile
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn replace_masterfile(path: &str, contents: &[u8]) -> Result<(), anyhow::Error> {
+    let temp_path = format!("{}.tmp", path);
+    let mut temp_file = fs::File::create(&temp_path)?;
+    temp_file.write_all(contents)?;
+    temp_file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+///
+/// Replace the records with any of the given tags by a single new record
+/// and write the masterfile.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// - `replaced_tags: &[u8]`
+///     - Tags of the records to drop
+/// - `tag: u8`
+///     - Tag of the new record
+/// - `value: &[u8]`
+///     - Value of the new record
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn replace_record(
+    path: &str,
+    replaced_tags: &[u8],
+    tag: u8,
+    value: &[u8],
+) -> Result<(), anyhow::Error> {
+    let contents = fs::read(path)?;
+    if !contents.starts_with(MASTERFILE_MAGIC) {
+        return Err(anyhow!("Key slots need a masterfile in the record format. Recreate the vault to use them."));
+    }
+
+    // Copy every record that is not replaced, then add the new one
+    let mut new_contents = Vec::new();
+    new_contents.extend_from_slice(&contents[..5]);
+    for (record_tag, record_value) in parse_records(&contents)? {
+        if !replaced_tags.contains(&record_tag) {
+            push_record(&mut new_contents, record_tag, record_value);
+        }
+    }
+    push_record(&mut new_contents, tag, value);
+    replace_masterfile(path, &new_contents)
+}
+
+///
+/// Append a single record to a masterfile buffer.
+/// # Arguments
+/// - `buffer: &mut Vec<u8>`
+///     - Buffer holding the masterfile contents
+/// - `tag: u8`
+///     - Tag of the record
+/// - `value: &[u8]`
+///     - Value of the record
+/// 
+fn push_record(buffer: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    buffer.push(tag);
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value);
+}
+
+///
+/// Split the contents of a record based masterfile into its records.
+/// # Arguments
+/// - `contents: &[u8]`
+///     - Full contents of the masterfile
+/// 
+/// Returns `Result<Vec<(u8, &[u8])>, anyhow::Error>`
+/// 
+fn parse_records(contents: &[u8]) -> Result<Vec<(u8, &[u8])>, anyhow::Error> {
+    if contents.len() < 5 || &contents[..4] != MASTERFILE_MAGIC {
+        return Err(anyhow!("Not a masterfile."));
+    }
+    if contents[4] != MASTERFILE_VERSION {
+        return Err(anyhow!("Unsupported masterfile version {}.", contents[4]));
+    }
+
+    let mut records = Vec::new();
+    let mut rest = &contents[5..];
+    while !rest.is_empty() {
+        if rest.len() < 3 {
+            return Err(anyhow!("Truncated masterfile record."));
+        }
+        let tag = rest[0];
+        let len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        if rest.len() < 3 + len {
+            return Err(anyhow!("Truncated masterfile record."));
+        }
+        records.push((tag, &rest[3..3 + len]));
+        rest = &rest[3 + len..];
+    }
+    Ok(records)
+}
+
+///
+/// Check whether the file at the path looks like a masterfile, either
+/// in the record format or the legacy fixed size format.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the file
+/// 
+/// Returns `bool`
+/// 
+pub fn is_masterfile(path: &str) -> bool {
+    match fs::read(path) {
+        Ok(contents) => {
+            contents.len() as u64 == LEGACY_MASTERFILE_LEN
+                || contents.starts_with(MASTERFILE_MAGIC)
+        }
+        Err(_) => false,
+    }
+}
+
+///
+/// Read the unencrypted vault settings from a masterfile. Legacy
+/// masterfiles return the default settings.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// 
+/// Returns `Result<VaultSettings, anyhow::Error>`
+/// 
+pub fn read_settings(path: &str) -> Result<VaultSettings, anyhow::Error> {
+    let contents = fs::read(path)?;
+    if !contents.starts_with(MASTERFILE_MAGIC) {
+        return Ok(VaultSettings::default());
+    }
+
+    let mut settings = VaultSettings::default();
+    for (tag, value) in parse_records(&contents)? {
+        if tag == TAG_COMPRESSION && value.len() == 1 {
+            settings.compression = value[0];
+        } else if tag == TAG_PADDING && value.len() == 1 {
+            settings.padding = value[0];
+        } else if tag == TAG_LAYOUT && value.len() == 1 {
+            settings.layout = value[0];
+        } else if tag == TAG_KDF {
+            settings.kdf = kdf::KdfParams::from_bytes(value)?;
+        } else if tag == TAG_CIPHER && value.len() == 1 {
+            settings.cipher = cipher::CipherSuite::from_id(value[0])?;
+        }
+    }
+    Ok(settings)
+}
+
+///
+/// Hash the contents of a keyfile into the secret mixed into the
+/// password slot. Any file can be used as a keyfile.
+/// # Arguments
+/// - `keyfile_path: &str`
+///     - Path to the keyfile
+/// 
+/// Returns `Result<[u8; 32], anyhow::Error>`
+/// 
+fn hash_keyfile(keyfile_path: &str) -> Result<[u8; 32], anyhow::Error> {
+    let mut keyfile = fs::File::open(keyfile_path)
+        .map_err(|err| anyhow!("Opening keyfile {}: {}", keyfile_path, err))?;
+    let mut hasher = Sha256::new();
+    if io::copy(&mut keyfile, &mut hasher)? == 0 {
+        return Err(anyhow!("Keyfile {} is empty.", keyfile_path));
+    }
+    Ok(hasher.finalize().into())
+}
+
+///
+/// Create a new keyfile filled with random bytes.
+/// # Arguments
+/// - `keyfile_path: &str`
+///     - Path of the keyfile to create, must not exist yet
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn create_keyfile(keyfile_path: &str) -> Result<(), anyhow::Error> {
+    let mut contents = [0u8; 64];
+    OsRng.fill_bytes(&mut contents);
+    let mut keyfile = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(keyfile_path)?;
+    keyfile.write_all(&contents)?;
+    contents.zeroize();
+    Ok(())
+}
+
+///
+/// Encrypt the key material under a key with a random nonce of the
+/// cipher suite of the vault.
+/// # Arguments
+/// - `suite: cipher::CipherSuite`
+/// - `key: &[u8]`
+/// - `key_material: &[u8]`
+/// - `aad: &[u8]`
+///     - Unencrypted data of the masterfile the slot depends on
+/// 
+/// Returns `Result<Vec<u8>, anyhow::Error>`, the nonce followed by the
+/// encrypted key material
+/// 
+fn seal_material(
+    suite: cipher::CipherSuite,
+    key: &[u8],
+    key_material: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, anyhow::Error> {
+    let mut sealed = vec![0u8; suite.nonce_len()];
+    OsRng.fill_bytes(&mut sealed);
+    let encrypted_material = suite.seal_with_aad(key, &sealed, key_material, aad)?;
+    sealed.extend_from_slice(&encrypted_material);
+    Ok(sealed)
+}
+
+///
+/// Decrypt key material written by `seal_material`.
+/// # Arguments
+/// - `suite: cipher::CipherSuite`
+/// - `key: &[u8]`
+/// - `sealed: &[u8]`
+///     - Nonce followed by the encrypted key material
+/// - `aad: &[u8]`
+///     - Data the slot was sealed with
+/// 
+/// Returns `Result<Vec<u8>, anyhow::Error>`
+/// 
+fn open_material(
+    suite: cipher::CipherSuite,
+    key: &[u8],
+    sealed: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, anyhow::Error> {
+    if sealed.len() != suite.nonce_len() + KEY_MATERIAL_LEN + 16 {
+        return Err(anyhow!("Malformed key slot."));
+    }
+    let (nonce, encrypted_material) = sealed.split_at(suite.nonce_len());
+    suite.open_with_aad(key, nonce, encrypted_material, aad)
+}
+
+///
+/// Get the associated data of the password and recovery slots. The argon2
+/// parameters and the cipher suite are stored unencrypted next to the
+/// slots, so they are bound to every slot and a slot no longer opens once
+/// they are changed.
+/// # Arguments
+/// - `settings: &VaultSettings`
+/// 
+/// Returns `[u8; 13]`
+/// 
+fn slot_aad(settings: &VaultSettings) -> [u8; 13] {
+    let mut aad = [0u8; 13];
+    aad[..12].copy_from_slice(&settings.kdf.to_bytes());
+    aad[12] = settings.cipher.id();
+    aad
+}
+
+///
+/// Encrypt the key material with a key derived from the password, and
+/// the keyfile when one is given.
+/// # Arguments
+/// - `password: &str`
+/// - `keyfile_hash: Option<&[u8; 32]>`
+///     - Hash of the keyfile, used as the argon2 secret
+/// - `key_material: &[u8]`
+/// - `settings: &VaultSettings`
+///     - Settings holding the argon2 parameters and the cipher suite
+/// 
+/// Returns `Result<Vec<u8>, anyhow::Error>`, the salt, nonce and encrypted
+/// key material of the slot
+/// 
+fn wrap_password_slot(
+    password: &str,
+    keyfile_hash: Option<&[u8; 32]>,
+    key_material: &[u8],
+    settings: &VaultSettings,
+) -> Result<Vec<u8>, anyhow::Error> {
+    // Create and fill the salt
+    let mut master_salt = [0u8; 32];
+    OsRng.fill_bytes(&mut master_salt);
+
+    // Derive the key and encrypt the key material
+    let mut argon2_config = settings.kdf.config();
+    if let Some(keyfile_hash) = keyfile_hash {
+        argon2_config.secret = keyfile_hash;
+    }
+    let mut key = argon2::hash_raw(password.as_bytes(), &master_salt, &argon2_config)?;
+    let sealed = seal_material(settings.cipher, &key, key_material, &slot_aad(settings));
+    key.zeroize();
+
+    // Build the slot from the salt, nonce and encrypted key material
+    let mut slot = master_salt.to_vec();
+    slot.extend_from_slice(&sealed?);
+    Ok(slot)
+}
+
+///
+/// Decrypt the key material of a password slot.
+/// # Arguments
+/// - `slot: &[u8]`
+///     - Value of the password slot record
+/// - `password: &str`
+/// - `keyfile_hash: Option<&[u8; 32]>`
+///     - Hash of the keyfile, used as the argon2 secret
+/// - `settings: &VaultSettings`
+///     - Settings holding the argon2 parameters and the cipher suite
+/// 
+/// Returns `Result<Vec<u8>, anyhow::Error>`
+/// 
+fn unwrap_password_slot(
+    slot: &[u8],
+    password: &str,
+    keyfile_hash: Option<&[u8; 32]>,
+    settings: &VaultSettings,
+) -> Result<Vec<u8>, anyhow::Error> {
+    if slot.len() < 32 {
+        return Err(anyhow!("Malformed password slot."));
+    }
+    let (masterfile_salt, sealed) = slot.split_at(32);
+
+    // Derive the key and decrypt the key material
+    let mut argon2_config = settings.kdf.config();
+    if let Some(keyfile_hash) = keyfile_hash {
+        argon2_config.secret = keyfile_hash;
+    }
+    let mut key = argon2::hash_raw(password.as_bytes(), masterfile_salt, &argon2_config)?;
+    let key_material = open_material(settings.cipher, &key, sealed, &slot_aad(settings));
+    key.zeroize();
+    key_material.map_err(|_| anyhow!("Could not decrypt masterfile."))
 }
 
 ///
@@ -38,66 +486,73 @@ pub struct MasterfileData {
 ///     - Path to the top directory of the vault to be created
 /// - `password: &String`
 ///     - Password to use when encryping the masterfile data
+/// - `keyfile_path: Option<&str>`
+///     - Keyfile required together with the password, if any
+/// - `settings: &VaultSettings`
+///     - Settings to record in the masterfile
 /// 
-/// Returns `Result<(), anyhow::Error>`
+/// Returns `Result<MasterfileData, anyhow::Error>`
 /// 
 pub fn create_masterfile(
-    path: &str, 
+    path: &str,
     password: &str,
-) -> Result<(), anyhow::Error> {
-    // Creates arrays for different data
-    let mut folder_salt = [0u8; 32];
-    let mut folder_nonce = [0u8; 24];
-    let mut master_salt = [0u8; 32];
-    let mut master_nonce = [0u8; 24];
-    let mut master_key = [0u8; 32];
+    keyfile_path: Option<&str>,
+    settings: &VaultSettings,
+) -> Result<MasterfileData, anyhow::Error> {
+    // Fill the key material with random bytes. It holds the
+    // master key, folder salt and folder nonce in that order
+    let mut key_material = [0u8; KEY_MATERIAL_LEN];
+    OsRng.fill_bytes(&mut key_material);
 
-    // Fills the array with random bytes
-    OsRng.fill_bytes(&mut folder_salt);
-    OsRng.fill_bytes(&mut folder_nonce);
-    OsRng.fill_bytes(&mut master_nonce);
-    OsRng.fill_bytes(&mut master_salt);
-    OsRng.fill_bytes(&mut master_key);
+    // Build the password slot, mixing in the keyfile when one is used
+    let mut keyfile_hash = keyfile_path.map(hash_keyfile).transpose()?;
+    let slot = wrap_password_slot(password, keyfile_hash.as_ref(), &key_material, settings)?;
+    let slot_tag = if keyfile_hash.is_some() {
+        TAG_KEYFILE_PASSWORD_SLOT
+    } else {
+        TAG_PASSWORD_SLOT
+    };
 
-    // Initialize variables for encryption
-    let argon2_config = functions::argon2_config();
-    let mut key = argon2::hash_raw(password.as_bytes(), &master_salt, &argon2_config)?;
-    let key_ga = GenericArray::clone_from_slice(&key[..]);
-    let nonce_ga = GenericArray::clone_from_slice(&master_nonce[..]);
-    let aead = XChaCha20Poly1305::new(&key_ga);
-    
-    // Create the masterfile and write encrypted data
-    if !path.ends_with('/') {
-        let mut masterfile = fs::File::create
-            (format!("{}/masterfile.e", path))?;
-
-        // Write master salt and nonce unencrypted
-        // for later decryption
-        masterfile.write_all(&master_salt)?;
-        masterfile.write_all(&master_nonce)?;
-        masterfile.write_all(&aead.encrypt(&nonce_ga, master_key.as_ref()).expect("Failure")[..])?;
-        masterfile.write_all(&aead.encrypt(&nonce_ga, folder_salt.as_ref()).expect("Failure")[..])?;
-        masterfile.write_all(&aead.encrypt(&nonce_ga, folder_nonce.as_ref()).expect("Failure")[..])?;
-    }
-    else {
-        let mut masterfile = fs::File::create
-            (format!("{}masterfile.e", path))?;
-        masterfile.write_all(&master_salt)?;
-        masterfile.write_all(&master_nonce)?;
-        masterfile.write_all(&aead.encrypt(&nonce_ga, master_key.as_ref()).expect("Failure")[..])?;
-        masterfile.write_all(&aead.encrypt(&nonce_ga, folder_salt.as_ref()).expect("Failure")[..])?;
-        masterfile.write_all(&aead.encrypt(&nonce_ga, folder_nonce.as_ref()).expect("Failure")[..])?;
-    }
+    // Build the masterfile contents
+    let mut contents = Vec::new();
+    contents.extend_from_slice(MASTERFILE_MAGIC);
+    contents.push(MASTERFILE_VERSION);
+    push_record(&mut contents, TAG_COMPRESSION, &[settings.compression]);
+    push_record(&mut contents, TAG_PADDING, &[settings.padding]);
+    push_record(&mut contents, TAG_LAYOUT, &[settings.layout]);
+    push_record(&mut contents, TAG_KDF, &settings.kdf.to_bytes());
+    push_record(&mut contents, TAG_CIPHER, &[settings.cipher.id()]);
+    push_record(&mut contents, TAG_UUID, &new_uuid());
+    push_record(&mut contents, slot_tag, &slot);
+
+    // Create the masterfile and write the contents
+    let mut masterfile = fs::File::create(masterfile_path(path))?;
+    masterfile.write_all(&contents)?;
 
     // Zerioize all sensitive variables in memory
-    folder_salt.zeroize();
-    folder_nonce.zeroize();
-    master_salt.zeroize();
-    master_nonce.zeroize();
-    master_key.zeroize();
-    key.zeroize();
+    let data = data_from_material(&key_material, *settings);
+    key_material.zeroize();
+    keyfile_hash.zeroize();
 
-    Ok(())
+    Ok(data)
+}
+
+///
+/// Check whether the password slot of the masterfile also needs a keyfile.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// 
+/// Returns `Result<bool, anyhow::Error>`
+/// 
+pub fn requires_keyfile(path: &str) -> Result<bool, anyhow::Error> {
+    let contents = fs::read(path)?;
+    if !contents.starts_with(MASTERFILE_MAGIC) {
+        return Ok(false);
+    }
+    Ok(parse_records(&contents)?
+        .iter()
+        .any(|(tag, _)| *tag == TAG_KEYFILE_PASSWORD_SLOT))
 }
 
 ///
@@ -107,11 +562,274 @@ pub fn create_masterfile(
 ///     - Path to the masterfile
 /// - `password: &String`
 ///     - Password to decrypt the masterfile data
+/// - `keyfile_path: Option<&str>`
+///     - Keyfile for vaults created with one
 /// 
 /// Returns `MasterfileData`
 /// 
 pub fn read_masterfile(
-    path: &str, 
+    path: &str,
+    password: &str,
+    keyfile_path: Option<&str>,
+) -> Result<MasterfileData, anyhow::Error> {
+    let contents = fs::read(path)?;
+    if !contents.starts_with(MASTERFILE_MAGIC) {
+        return read_legacy_masterfile(path, password);
+    }
+
+    // Find the password slot
+    let (slot_tag, slot) = parse_records(&contents)?
+        .into_iter()
+        .find(|(tag, _)| *tag == TAG_PASSWORD_SLOT || *tag == TAG_KEYFILE_PASSWORD_SLOT)
+        .ok_or_else(|| anyhow!("Masterfile has no password slot."))?;
+
+    // Hash the keyfile if the slot needs one
+    let mut keyfile_hash = if slot_tag == TAG_KEYFILE_PASSWORD_SLOT {
+        let keyfile_path = keyfile_path
+            .ok_or_else(|| anyhow!("Vault requires a keyfile."))?;
+        Some(hash_keyfile(keyfile_path)?)
+    } else {
+        None
+    };
+
+    // Decrypt the key material
+    let settings = read_settings(path)?;
+    let key_material = unwrap_password_slot(slot, password, keyfile_hash.as_ref(), &settings);
+    keyfile_hash.zeroize();
+    let mut key_material = key_material?;
+
+    // Clean up and return
+    let data = data_from_material(&key_material, settings);
+    key_material.zeroize();
+    Ok(data)
+}
+
+///
+/// Build the masterfile data from decrypted key material.
+/// # Arguments
+/// - `key_material: &[u8]`
+///     - master_key, folder_salt and folder_nonce in that order
+/// - `settings: VaultSettings`
+/// 
+/// Returns `MasterfileData`
+/// 
+pub fn data_from_material(key_material: &[u8], settings: VaultSettings) -> MasterfileData {
+    // Copy straight into the arrays, temporary vectors would not be wiped
+    let mut data = MasterfileData {
+        master_key: [0u8; 32],
+        folder_salt: [0u8; 32],
+        folder_nonce: [0u8; 24],
+        settings,
+        locked: false,
+    };
+    data.master_key.copy_from_slice(&key_material[..32]);
+    data.folder_salt.copy_from_slice(&key_material[32..64]);
+    data.folder_nonce.copy_from_slice(&key_material[64..KEY_MATERIAL_LEN]);
+    data
+}
+
+///
+/// Flatten the keys of the masterfile data into the key material
+/// wrapped by every key slot.
+/// # Arguments
+/// - `data: &MasterfileData`
+/// 
+/// Returns `[u8; KEY_MATERIAL_LEN]`
+/// 
+pub fn material_from_data(data: &MasterfileData) -> [u8; KEY_MATERIAL_LEN] {
+    let mut key_material = [0u8; KEY_MATERIAL_LEN];
+    key_material[..32].copy_from_slice(&data.master_key);
+    key_material[32..64].copy_from_slice(&data.folder_salt);
+    key_material[64..].copy_from_slice(&data.folder_nonce);
+    key_material
+}
+
+///
+/// Split a recipient slot into the public key it was written for and
+/// the age encrypted key material.
+/// # Arguments
+/// - `slot: &[u8]`
+///     - Value of the recipient slot record
+/// 
+/// Returns `Result<(String, &[u8]), anyhow::Error>`
+/// 
+fn split_recipient_slot(slot: &[u8]) -> Result<(String, &[u8]), anyhow::Error> {
+    if slot.len() < 2 {
+        return Err(anyhow!("Malformed recipient slot."));
+    }
+    let len = u16::from_be_bytes([slot[0], slot[1]]) as usize;
+    if slot.len() < 2 + len {
+        return Err(anyhow!("Malformed recipient slot."));
+    }
+    let recipient = String::from_utf8(slot[2..2 + len].to_vec())?;
+    Ok((recipient, &slot[2 + len..]))
+}
+
+///
+/// List the public keys that have a recipient slot in the masterfile.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// 
+/// Returns `Result<Vec<String>, anyhow::Error>`
+/// 
+pub fn list_recipients(path: &str) -> Result<Vec<String>, anyhow::Error> {
+    let contents = fs::read(path)?;
+    if !contents.starts_with(MASTERFILE_MAGIC) {
+        return Ok(Vec::new());
+    }
+
+    let mut recipients = Vec::new();
+    for (tag, value) in parse_records(&contents)? {
+        if tag == TAG_RECIPIENT_SLOT {
+            recipients.push(split_recipient_slot(value)?.0);
+        }
+    }
+    Ok(recipients)
+}
+
+///
+/// Add a recipient slot that wraps the key material to an age or SSH
+/// public key, so the holder of the matching identity can unlock the vault.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// - `data: &MasterfileData`
+///     - Data of the unlocked masterfile
+/// - `recipient: &str`
+///     - Public key to add
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn add_recipient(
+    path: &str,
+    data: &MasterfileData,
+    recipient: &str,
+) -> Result<(), anyhow::Error> {
+    let mut contents = fs::read(path)?;
+    if !contents.starts_with(MASTERFILE_MAGIC) {
+        return Err(anyhow!("Recipients need a masterfile in the record format. Recreate the vault to use them."));
+    }
+    let recipient = recipients::normalize_recipient(recipient);
+    if list_recipients(path)?.contains(&recipient) {
+        return Err(anyhow!("{} is already a recipient of the vault.", recipient));
+    }
+
+    // Build the slot and append it to the masterfile
+    push_record(&mut contents, TAG_RECIPIENT_SLOT, &build_recipient_slot(&recipient, data)?);
+    replace_masterfile(path, &contents)
+}
+
+///
+/// Build a recipient slot that wraps the key material to a public key.
+/// # Arguments
+/// - `recipient: &str`
+///     - Normalized public key
+/// - `data: &MasterfileData`
+/// 
+/// Returns `Result<Vec<u8>, anyhow::Error>`
+/// 
+fn build_recipient_slot(recipient: &str, data: &MasterfileData) -> Result<Vec<u8>, anyhow::Error> {
+    // Encrypt the key material to the recipient
+    let mut key_material = material_from_data(data);
+    let encrypted_material = recipients::encrypt_to(
+        recipients::parse_recipient(recipient)?.as_ref(), &key_material);
+    key_material.zeroize();
+
+    let mut slot = Vec::new();
+    slot.extend_from_slice(&(recipient.len() as u16).to_be_bytes());
+    slot.extend_from_slice(recipient.as_bytes());
+    slot.extend_from_slice(&encrypted_material?);
+    if slot.len() > u16::MAX as usize {
+        return Err(anyhow!("Public key is too large for a recipient slot."));
+    }
+    Ok(slot)
+}
+
+///
+/// Remove the recipient slot of a public key from the masterfile.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// - `recipient: &str`
+///     - Public key to remove
+/// 
+/// Returns `Result<bool, anyhow::Error>`, whether a slot was removed
+/// 
+pub fn remove_recipient(path: &str, recipient: &str) -> Result<bool, anyhow::Error> {
+    let contents = fs::read(path)?;
+    if !contents.starts_with(MASTERFILE_MAGIC) {
+        return Ok(false);
+    }
+    let recipient = recipients::normalize_recipient(recipient);
+
+    // Copy every record except the slot of the recipient
+    let mut new_contents = Vec::new();
+    new_contents.extend_from_slice(&contents[..5]);
+    let mut removed = false;
+    for (tag, value) in parse_records(&contents)? {
+        if tag == TAG_RECIPIENT_SLOT && split_recipient_slot(value)?.0 == recipient {
+            removed = true;
+            continue;
+        }
+        push_record(&mut new_contents, tag, value);
+    }
+
+    if removed {
+        replace_masterfile(path, &new_contents)?;
+    }
+    Ok(removed)
+}
+
+///
+/// Read the masterfile with an age identity instead of the password.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// - `identities: &[Box<dyn age::Identity>]`
+///     - Identities loaded from the identity file
+/// 
+/// Returns `Result<MasterfileData, anyhow::Error>`
+/// 
+pub fn read_masterfile_with_identity(
+    path: &str,
+    identities: &[Box<dyn age::Identity>],
+) -> Result<MasterfileData, anyhow::Error> {
+    let contents = fs::read(path)?;
+    if !contents.starts_with(MASTERFILE_MAGIC) {
+        return Err(anyhow!("Masterfile has no recipient slots."));
+    }
+
+    // Try every recipient slot until one matches the identities
+    for (tag, value) in parse_records(&contents)? {
+        if tag != TAG_RECIPIENT_SLOT {
+            continue;
+        }
+        let (_, encrypted_material) = split_recipient_slot(value)?;
+        if let Some(mut key_material) = recipients::decrypt_with(identities, encrypted_material)? {
+            if key_material.len() != KEY_MATERIAL_LEN {
+                return Err(anyhow!("Malformed recipient slot."));
+            }
+            let data = data_from_material(&key_material, read_settings(path)?);
+            key_material.zeroize();
+            return Ok(data);
+        }
+    }
+    Err(anyhow!("No recipient slot matches the identity."))
+}
+
+///
+/// Read a masterfile written in the legacy fixed size format.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// - `password: &str`
+///     - Password to decrypt the masterfile data
+/// 
+/// Returns `Result<MasterfileData, anyhow::Error>`
+/// 
+fn read_legacy_masterfile(
+    path: &str,
     password: &str,
 ) -> Result<MasterfileData, anyhow::Error> {
     
@@ -123,34 +841,559 @@ pub fn read_masterfile(
     let mut masterfile_nonce = [0u8; 24];
 
     // Open file
-    let mut masterfile = fs::File::open(&path).unwrap();
+    let mut masterfile = fs::File::open(path)?;
     
     // Read all data in 
-    masterfile.read_exact(&mut masterfile_salt).unwrap();
-    masterfile.read_exact(&mut masterfile_nonce).unwrap();
-    masterfile.read_exact(&mut encrypted_master_key).unwrap();
-    masterfile.read_exact(&mut encrypted_folder_salt).unwrap();
-    masterfile.read_exact(&mut encrypted_folder_nonce).unwrap();
+    masterfile.read_exact(&mut masterfile_salt)?;
+    masterfile.read_exact(&mut masterfile_nonce)?;
+    masterfile.read_exact(&mut encrypted_master_key)?;
+    masterfile.read_exact(&mut encrypted_folder_salt)?;
+    masterfile.read_exact(&mut encrypted_folder_nonce)?;
 
     // Initialize aead and nonce_ga
     let argon2_config = functions::argon2_config();
-    let mut key = argon2::hash_raw(password.as_bytes(), &masterfile_salt, &argon2_config).unwrap();
+    let mut key = argon2::hash_raw(password.as_bytes(), &masterfile_salt, &argon2_config)?;
     let key_ga = GenericArray::clone_from_slice(&key[..]);
     let nonce_ga = GenericArray::clone_from_slice(&masterfile_nonce[..]);
     let aead = XChaCha20Poly1305::new(&key_ga);
 
     // Decrypt data
-    let master_key = aead.decrypt(&nonce_ga, encrypted_master_key.as_ref()).unwrap();
-    let folder_salt = aead.decrypt(&nonce_ga, encrypted_folder_salt.as_ref()).unwrap();
-    let folder_nonce = aead.decrypt(&nonce_ga, encrypted_folder_nonce.as_ref()).unwrap();
-    
-    // Clean up and return
+    let master_key = aead.decrypt(&nonce_ga, encrypted_master_key.as_ref());
+    let folder_salt = aead.decrypt(&nonce_ga, encrypted_folder_salt.as_ref());
+    let folder_nonce = aead.decrypt(&nonce_ga, encrypted_folder_nonce.as_ref());
     key.zeroize();
     masterfile_salt.zeroize();
     masterfile_nonce.zeroize();
-    Ok(MasterfileData {
-        master_key: functions::into_array(master_key), 
-        folder_salt: functions::into_array(folder_salt), 
-        folder_nonce: functions::into_array(folder_nonce),
-    })
+
+    // A wrong password fails every part, a damaged file only some of them
+    let (mut master_key, mut folder_salt, mut folder_nonce) = match (master_key, folder_salt, folder_nonce) {
+        (Ok(master_key), Ok(folder_salt), Ok(folder_nonce)) => (master_key, folder_salt, folder_nonce),
+        (master_key, folder_salt, folder_nonce) => {
+            for mut part in [master_key, folder_salt, folder_nonce].into_iter().flatten() {
+                part.zeroize();
+            }
+            return Err(anyhow!("Could not decrypt masterfile."));
+        }
+    };
+    let mut key_material = [0u8; KEY_MATERIAL_LEN];
+    key_material[..32].copy_from_slice(&master_key);
+    key_material[32..64].copy_from_slice(&folder_salt);
+    key_material[64..].copy_from_slice(&folder_nonce);
+    let data = data_from_material(&key_material, VaultSettings::default());
+    
+    // Clean up and return
+    master_key.zeroize();
+    folder_salt.zeroize();
+    folder_nonce.zeroize();
+    key_material.zeroize();
+    Ok(data)
+}
+
+///
+/// Replace the password slot of the masterfile with one for a new
+/// password, and optionally a keyfile.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// - `data: &MasterfileData`
+///     - Data of the unlocked masterfile
+/// - `password: &str`
+///     - New password
+/// - `keyfile_path: Option<&str>`
+///     - Keyfile required together with the new password, if any
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn set_password_slot(
+    path: &str,
+    data: &MasterfileData,
+    password: &str,
+    keyfile_path: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let mut keyfile_hash = keyfile_path.map(hash_keyfile).transpose()?;
+    let mut key_material = material_from_data(data);
+    let slot = wrap_password_slot(password, keyfile_hash.as_ref(), &key_material, &data.settings);
+    key_material.zeroize();
+    let slot_tag = if keyfile_hash.is_some() {
+        TAG_KEYFILE_PASSWORD_SLOT
+    } else {
+        TAG_PASSWORD_SLOT
+    };
+    keyfile_hash.zeroize();
+    replace_record(path, &[TAG_PASSWORD_SLOT, TAG_KEYFILE_PASSWORD_SLOT], slot_tag, &slot?)
+}
+
+///
+/// Derive the key of the recovery slot from the entropy of the phrase.
+/// # Arguments
+/// - `entropy: &[u8]`
+///     - Entropy encoded by the recovery phrase
+/// 
+/// Returns `[u8; 32]`
+/// 
+fn recovery_key(entropy: &[u8]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(RECOVERY_KEY_CONTEXT)
+        .chain_update(entropy)
+        .finalize()
+        .into()
+}
+
+///
+/// Generate a new recovery key, write it into the recovery slot of the
+/// masterfile and return it as a 24 word BIP39 phrase. An existing
+/// recovery slot is replaced.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// - `data: &MasterfileData`
+///     - Data of the unlocked masterfile
+/// 
+/// Returns `Result<String, anyhow::Error>`, the recovery phrase
+/// 
+pub fn set_recovery_slot(
+    path: &str,
+    data: &MasterfileData,
+) -> Result<String, anyhow::Error> {
+    let (slot, phrase) = build_recovery_slot(data)?;
+    replace_record(path, &[TAG_RECOVERY_SLOT], TAG_RECOVERY_SLOT, &slot)?;
+    Ok(phrase)
+}
+
+///
+/// Build a recovery slot with a new random recovery key.
+/// # Arguments
+/// - `data: &MasterfileData`
+/// 
+/// Returns `Result<(Vec<u8>, String), anyhow::Error>`, the slot and the
+/// recovery phrase
+/// 
+fn build_recovery_slot(data: &MasterfileData) -> Result<(Vec<u8>, String), anyhow::Error> {
+    // 256 bits of entropy encode to 24 words
+    let mut entropy = [0u8; 32];
+    OsRng.fill_bytes(&mut entropy);
+
+    // Encrypt the key material with the recovery key
+    let mut key_material = material_from_data(data);
+    let mut key = recovery_key(&entropy);
+    let slot = seal_material(data.settings.cipher, &key, &key_material, &slot_aad(&data.settings));
+    key.zeroize();
+    key_material.zeroize();
+    let slot = slot?;
+
+    let phrase = bip39::Mnemonic::from_entropy(&entropy)
+        .map_err(|err| anyhow!("Encoding recovery phrase: {}", err))?
+        .to_string();
+    entropy.zeroize();
+    Ok((slot, phrase))
+}
+
+///
+/// Read the masterfile with the recovery phrase instead of the password.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// - `phrase: &str`
+///     - 24 word recovery phrase
+/// 
+/// Returns `Result<MasterfileData, anyhow::Error>`
+/// 
+pub fn read_masterfile_with_recovery(
+    path: &str,
+    phrase: &str,
+) -> Result<MasterfileData, anyhow::Error> {
+    let contents = fs::read(path)?;
+    if !contents.starts_with(MASTERFILE_MAGIC) {
+        return Err(anyhow!("Masterfile has no recovery slot."));
+    }
+    let slot = parse_records(&contents)?
+        .into_iter()
+        .find(|(tag, _)| *tag == TAG_RECOVERY_SLOT)
+        .map(|(_, value)| value)
+        .ok_or_else(|| anyhow!("Masterfile has no recovery slot."))?;
+
+    // Decode the phrase back into the entropy of the recovery key
+    let phrase = phrase.to_lowercase().split_whitespace().collect::<Vec<&str>>().join(" ");
+    let mnemonic = bip39::Mnemonic::parse_normalized(&phrase)
+        .map_err(|err| anyhow!("Invalid recovery phrase: {}", err))?;
+    let mut entropy = mnemonic.to_entropy();
+
+    // Decrypt the key material
+    let settings = read_settings(path)?;
+    let mut key = recovery_key(&entropy);
+    let key_material = open_material(settings.cipher, &key, slot, &slot_aad(&settings))
+        .map_err(|_| anyhow!("Recovery phrase does not match the vault."));
+    key.zeroize();
+    entropy.zeroize();
+    let mut key_material = key_material?;
+
+    let data = data_from_material(&key_material, settings);
+    key_material.zeroize();
+    Ok(data)
+}
+
+///
+/// Record the fingerprint of the keys that were split into shares, so
+/// shares can be checked against this vault before they are used.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// - `fingerprint: &[u8]`
+///     - Fingerprint stored in the shares
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn set_share_check(path: &str, fingerprint: &[u8]) -> Result<(), anyhow::Error> {
+    replace_record(path, &[TAG_SHARE_CHECK], TAG_SHARE_CHECK, fingerprint)
+}
+
+///
+/// Read the fingerprint recorded when the keys were split into shares.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// 
+/// Returns `Result<Option<Vec<u8>>, anyhow::Error>`
+/// 
+pub fn read_share_check(path: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let contents = fs::read(path)?;
+    if !contents.starts_with(MASTERFILE_MAGIC) {
+        return Ok(None);
+    }
+    Ok(parse_records(&contents)?
+        .into_iter()
+        .find(|(tag, _)| *tag == TAG_SHARE_CHECK)
+        .map(|(_, value)| value.to_vec()))
+}
+
+///
+/// Destroy a masterfile and with it every key slot, so the files of the
+/// vault can never be decrypted again. The masterfile is overwritten with
+/// random data before it is removed. A temporary masterfile left by an
+/// interrupted write is destroyed the same way.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn shred_masterfile(path: &str) -> Result<(), anyhow::Error> {
+    let temp_path = format!("{}.tmp", path);
+    if Path::new(&temp_path).exists() {
+        wipe::shred_file(&temp_path, SHRED_PASSES)?;
+    }
+    wipe::shred_file(path, SHRED_PASSES)
+}
+
+///
+/// Generate a random (version 4) UUID for a new masterfile
+/// 
+/// Returns `[u8; 16]`
+/// 
+fn new_uuid() -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    OsRng.fill_bytes(&mut uuid);
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid
+}
+
+///
+/// Format a UUID in its usual hyphenated form
+/// # Arguments
+/// - `uuid: &[u8]`
+/// 
+/// Returns `String`
+/// 
+fn format_uuid(uuid: &[u8]) -> String {
+    let encoded = hex::encode(uuid);
+    format!("{}-{}-{}-{}-{}", &encoded[..8], &encoded[8..12], &encoded[12..16], &encoded[16..20], &encoded[20..])
+}
+
+///
+/// Read the UUID that identifies the vault wherever it is mounted.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// 
+/// Returns `Result<Option<String>, anyhow::Error>`, `None` for masterfiles
+/// without one
+/// 
+pub fn read_uuid(path: &str) -> Result<Option<String>, anyhow::Error> {
+    let contents = fs::read(path)?;
+    if !contents.starts_with(MASTERFILE_MAGIC) {
+        return Ok(None);
+    }
+    Ok(parse_records(&contents)?
+        .into_iter()
+        .find(|(tag, value)| *tag == TAG_UUID && value.len() == 16)
+        .map(|(_, value)| format_uuid(value)))
+}
+
+///
+/// Get the UUID of the vault, giving masterfiles created before UUIDs
+/// were recorded a new one. Only called when the user registers a vault,
+/// so a masterfile is never changed just by being seen.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// 
+/// Returns `Result<Option<String>, anyhow::Error>`, `None` for masterfiles
+/// in the legacy format, which can not hold one
+/// 
+pub fn ensure_uuid(path: &str) -> Result<Option<String>, anyhow::Error> {
+    if let Some(uuid) = read_uuid(path)? {
+        return Ok(Some(uuid));
+    }
+    if !fs::read(path)?.starts_with(MASTERFILE_MAGIC) {
+        return Ok(None);
+    }
+    let uuid = new_uuid();
+    replace_record(path, &[TAG_UUID], TAG_UUID, &uuid)?;
+    Ok(Some(format_uuid(&uuid)))
+}
+
+///
+/// Check whether a rekey of the vault was started and not finished.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// 
+/// Returns `bool`
+/// 
+pub fn rekey_pending(path: &str) -> bool {
+    match fs::read(path) {
+        Ok(contents) if contents.starts_with(MASTERFILE_MAGIC) => parse_records(&contents)
+            .map(|records| records.iter().any(|(tag, _)| *tag == TAG_REKEY_PENDING))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+///
+/// Start a rekey by generating new keys and recording them in the
+/// masterfile, wrapped like a password slot for the new password, so the
+/// old password can not reveal them. If a rekey was already started, the
+/// recorded keys are returned so it can be resumed with the same new
+/// password.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// - `old_data: &MasterfileData`
+///     - Data unlocked with the current password
+/// - `password: &str`
+///     - New password
+/// - `keyfile_path: Option<&str>`
+///     - Keyfile required together with the new password, if any
+/// 
+/// Returns `Result<MasterfileData, anyhow::Error>`, the new keys
+/// 
+pub fn start_rekey(
+    path: &str,
+    old_data: &MasterfileData,
+    password: &str,
+    keyfile_path: Option<&str>,
+) -> Result<MasterfileData, anyhow::Error> {
+    let contents = fs::read(path)?;
+    if !contents.starts_with(MASTERFILE_MAGIC) {
+        return Err(anyhow!("Rekey needs a masterfile in the record format. Recreate the vault to use it."));
+    }
+    let mut keyfile_hash = keyfile_path.map(hash_keyfile).transpose()?;
+
+    // Resume with the keys of the unfinished rekey
+    let pending = parse_records(&contents)?
+        .into_iter()
+        .find(|(tag, _)| *tag == TAG_REKEY_PENDING)
+        .map(|(_, value)| value.to_vec());
+    if let Some(pending) = pending {
+        let key_material = unwrap_password_slot(&pending, password, keyfile_hash.as_ref(), &old_data.settings)
+            .map_err(|_| anyhow!("The key rotation was started with another new password or keyfile. Enter those to resume it."));
+        keyfile_hash.zeroize();
+        let mut key_material = key_material?;
+        let data = data_from_material(&key_material, old_data.settings);
+        key_material.zeroize();
+        return Ok(data);
+    }
+
+    // Generate the new keys and record them before anything is re-encrypted
+    let mut key_material = [0u8; KEY_MATERIAL_LEN];
+    OsRng.fill_bytes(&mut key_material);
+    let record = wrap_password_slot(password, keyfile_hash.as_ref(), &key_material, &old_data.settings);
+    keyfile_hash.zeroize();
+    let data = data_from_material(&key_material, old_data.settings);
+    key_material.zeroize();
+    replace_record(path, &[TAG_REKEY_PENDING], TAG_REKEY_PENDING, &record?)?;
+    Ok(data)
+}
+
+///
+/// Finish a rekey once every file is encrypted with the new keys. In a
+/// single write the password slot is replaced, the recipient slots are
+/// wrapped to the new keys, a new recovery phrase replaces an existing
+/// one, and the old shares and the rekey record are dropped.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the masterfile
+/// - `new_data: &MasterfileData`
+///     - New keys returned by `start_rekey`
+/// - `password: &str`
+///     - New password
+/// - `keyfile_path: Option<&str>`
+///     - Keyfile required together with the new password, if any
+/// 
+/// Returns `Result<Option<String>, anyhow::Error>`, the new recovery
+/// phrase if the vault had one
+/// 
+pub fn finish_rekey(
+    path: &str,
+    new_data: &MasterfileData,
+    password: &str,
+    keyfile_path: Option<&str>,
+) -> Result<Option<String>, anyhow::Error> {
+    let contents = fs::read(path)?;
+
+    // Build the new password slot
+    let mut keyfile_hash = keyfile_path.map(hash_keyfile).transpose()?;
+    let mut key_material = material_from_data(new_data);
+    let slot = wrap_password_slot(password, keyfile_hash.as_ref(), &key_material, &new_data.settings);
+    key_material.zeroize();
+    let slot_tag = if keyfile_hash.is_some() {
+        TAG_KEYFILE_PASSWORD_SLOT
+    } else {
+        TAG_PASSWORD_SLOT
+    };
+    keyfile_hash.zeroize();
+
+    // Copy the settings and build the key slots again for the new keys
+    let mut new_contents = Vec::new();
+    new_contents.extend_from_slice(&contents[..5]);
+    let mut phrase = None;
+    for (tag, value) in parse_records(&contents)? {
+        match tag {
+            TAG_PASSWORD_SLOT | TAG_KEYFILE_PASSWORD_SLOT | TAG_SHARE_CHECK | TAG_REKEY_PENDING => (),
+            TAG_RECIPIENT_SLOT => {
+                let (recipient, _) = split_recipient_slot(value)?;
+                push_record(&mut new_contents, TAG_RECIPIENT_SLOT, &build_recipient_slot(&recipient, new_data)?);
+            }
+            TAG_RECOVERY_SLOT => {
+                let (recovery_slot, recovery_phrase) = build_recovery_slot(new_data)?;
+                push_record(&mut new_contents, TAG_RECOVERY_SLOT, &recovery_slot);
+                phrase = Some(recovery_phrase);
+            }
+            _ => push_record(&mut new_contents, tag, value),
+        }
+    }
+    push_record(&mut new_contents, slot_tag, &slot?);
+    replace_masterfile(path, &new_contents)?;
+    Ok(phrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, TEST_KDF};
+
+    fn test_masterfile(name: &str, settings: &VaultSettings) -> String {
+        testutil::test_vault(&format!("masterfile-{}", name), settings).0
+    }
+
+    #[test]
+    fn kdf_record_round_trip() {
+        let settings = VaultSettings { compression: 3, padding: 1, ..testutil::test_settings() };
+        let path = test_masterfile("kdf-round-trip", &settings);
+        let read = read_settings(&path).unwrap();
+        assert!(read.kdf == TEST_KDF);
+        assert_eq!((read.compression, read.padding), (3, 1));
+        let data = read_masterfile(&path, "password", None).unwrap();
+        assert!(data.settings.kdf == TEST_KDF);
+        assert!(read_masterfile(&path, "wrong", None).is_err());
+    }
+
+    #[test]
+    fn malformed_kdf_record() {
+        let settings = testutil::test_settings();
+        let path = test_masterfile("kdf-malformed", &settings);
+
+        // A record of the wrong length or with parameters beyond the limits
+        replace_record(&path, &[TAG_KDF], TAG_KDF, &TEST_KDF.to_bytes()[..8]).unwrap();
+        assert!(read_settings(&path).is_err());
+        let huge = kdf::KdfParams { mem_cost: u32::MAX, ..TEST_KDF };
+        replace_record(&path, &[TAG_KDF], TAG_KDF, &huge.to_bytes()).unwrap();
+        assert!(read_settings(&path).is_err());
+        assert!(read_masterfile(&path, "password", None).is_err());
+    }
+
+    #[test]
+    fn kdf_record_is_authenticated() {
+        let settings = testutil::test_settings();
+        let key_material = [7u8; KEY_MATERIAL_LEN];
+        let slot = wrap_password_slot("password", None, &key_material, &settings).unwrap();
+        assert_eq!(unwrap_password_slot(&slot, "password", None, &settings).unwrap(), key_material);
+
+        // Other parameters that derive the same key still do not open the slot
+        let (salt, sealed) = slot.split_at(32);
+        let key = argon2::hash_raw(b"password", salt, &TEST_KDF.config()).unwrap();
+        let other = VaultSettings { kdf: kdf::KdfParams { mem_cost: 16, ..TEST_KDF }, ..settings };
+        assert!(open_material(settings.cipher, &key, sealed, &slot_aad(&other)).is_err());
+
+        // Nor does a slot sealed without them
+        let mut unbound = salt.to_vec();
+        unbound.extend_from_slice(&seal_material(settings.cipher, &key, &key_material, &[]).unwrap());
+        assert!(unwrap_password_slot(&unbound, "password", None, &settings).is_err());
+    }
+
+    #[test]
+    fn tampered_kdf_record() {
+        let (path, data) = testutil::test_vault("masterfile-kdf-tampered", &testutil::test_settings());
+        let phrase = set_recovery_slot(&path, &data).unwrap();
+
+        // Neither the password nor the recovery phrase open the vault
+        let stronger = kdf::KdfParams { time_cost: 2, ..TEST_KDF };
+        replace_record(&path, &[TAG_KDF], TAG_KDF, &stronger.to_bytes()).unwrap();
+        assert!(read_masterfile(&path, "password", None).is_err());
+        assert!(read_masterfile_with_recovery(&path, &phrase).is_err());
+        replace_record(&path, &[TAG_KDF], TAG_KDF, &TEST_KDF.to_bytes()).unwrap();
+        assert!(read_masterfile(&path, "password", None).is_ok());
+        assert!(read_masterfile_with_recovery(&path, &phrase).is_ok());
+    }
+
+    #[test]
+    fn truncated_masterfile() {
+        let path = test_masterfile("truncated", &testutil::test_settings());
+        let contents = fs::read(&path).unwrap();
+        assert!(parse_records(&contents).is_ok());
+        for len in [3, 6, 7, contents.len() - 1] {
+            assert!(parse_records(&contents[..len]).is_err(), "length {}", len);
+        }
+
+        // The header alone holds no password slot
+        fs::write(&path, &contents[..5]).unwrap();
+        assert!(read_masterfile(&path, "password", None).is_err());
+    }
+
+    #[test]
+    fn legacy_masterfile() {
+        let path = format!("{}/masterfile.e", testutil::test_dir("masterfile-legacy"));
+        let (salt, nonce) = ([1u8; 32], [2u8; 24]);
+        let key = argon2::hash_raw(b"password", &salt, &functions::argon2_config()).unwrap();
+        let aead = XChaCha20Poly1305::new(GenericArray::from_slice(&key));
+        let mut contents = [&salt[..], &nonce[..]].concat();
+        for part in [&[3u8; 32][..], &[4u8; 32], &[5u8; 24]] {
+            contents.extend(aead.encrypt(GenericArray::from_slice(&nonce), part).unwrap());
+        }
+        assert_eq!(contents.len() as u64, LEGACY_MASTERFILE_LEN);
+        fs::write(&path, &contents).unwrap();
+        assert!(is_masterfile(&path));
+
+        let data = read_masterfile(&path, "password", None).unwrap();
+        assert_eq!((data.master_key, data.folder_salt, data.folder_nonce), ([3u8; 32], [4u8; 32], [5u8; 24]));
+
+        // A wrong password or a damaged file is an error, not a panic
+        assert!(read_masterfile(&path, "wrong", None).is_err());
+        contents[100] ^= 1;
+        fs::write(&path, &contents).unwrap();
+        assert!(read_masterfile(&path, "password", None).is_err());
+        fs::write(&path, &contents[..100]).unwrap();
+        assert!(read_masterfile(&path, "password", None).is_err());
+    }
 }
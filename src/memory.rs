@@ -0,0 +1,204 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::{Mutex, Once, OnceLock},
+};
+
+// Only warn once when memory can not be locked
+static LOCK_WARNING: Once = Once::new();
+
+// Number of locked values on every locked page, by the address of the page.
+// Locks do not stack, so a page is only unlocked once no value on it is
+// locked any more
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+// Size of a page of memory, read once
+static PAGE_SIZE: OnceLock<usize> = OnceLock::new();
+
+///
+/// Keep secrets out of core dumps and other processes. Core dumps are
+/// disabled with `setrlimit`, and on Linux the process is marked as not
+/// dumpable, which also stops other processes of the user from attaching
+/// with ptrace.
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn harden_process() -> Result<(), anyhow::Error> {
+    #[cfg(unix)]
+    {
+        let no_core = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &no_core) } != 0 {
+            return Err(anyhow!("Could not disable core dumps: {}", std::io::Error::last_os_error()));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+            return Err(anyhow!("Could not disable ptrace attachment: {}", std::io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+///
+/// Lock the pages holding a value in RAM so they are never swapped to
+/// disk. The value must not move while it is locked, so only lock values
+/// behind a pointer such as a `Box`, an `Arc` or a `String` buffer. A
+/// warning is printed the first time locking fails.
+/// # Arguments
+/// - `value: &T`
+/// 
+/// Returns `bool`, whether the pages were locked
+/// 
+pub fn lock<T: ?Sized>(value: &T) -> bool {
+    lock_region(value as *const T as *const u8, mem::size_of_val(value))
+}
+
+///
+/// Unlock the pages of a value locked with `lock`.
+/// # Arguments
+/// - `value: &T`
+/// 
+pub fn unlock<T: ?Sized>(value: &T) {
+    unlock_region(value as *const T as *const u8, mem::size_of_val(value))
+}
+
+///
+/// Lock a region of memory, warning once if it fails.
+/// # Arguments
+/// - `ptr: *const u8`
+/// - `len: usize`
+/// 
+/// Returns `bool`
+/// 
+fn lock_region(ptr: *const u8, len: usize) -> bool {
+    if len == 0 {
+        return false;
+    }
+
+    #[cfg(unix)]
+    {
+        let mut locked_pages = LOCKED_PAGES.lock().unwrap_or_else(|err| err.into_inner());
+        if unsafe { libc::mlock(ptr as *const libc::c_void, len) } == 0 {
+            for page in pages(ptr, len) {
+                *locked_pages.entry(page).or_insert(0) += 1;
+            }
+            return true;
+        }
+        LOCK_WARNING.call_once(|| {
+            println!("##Warning##");
+            println!("Could not lock keys in memory ({}), they may be swapped to disk.",
+                std::io::Error::last_os_error());
+            if let Some(limit) = memlock_limit() {
+                println!("The memlock limit is {} KiB, raise it with `ulimit -l`.", limit / 1024);
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    LOCK_WARNING.call_once(|| {
+        let _ = ptr;
+        println!("##Warning##");
+        println!("Locking keys in memory is not supported here, they may be swapped to disk.");
+    });
+    false
+}
+
+///
+/// Unlock a region of memory locked with `lock_region`. Pages that still
+/// hold another locked value stay locked.
+/// # Arguments
+/// - `ptr: *const u8`
+/// - `len: usize`
+/// 
+fn unlock_region(ptr: *const u8, len: usize) {
+    #[cfg(unix)]
+    if len != 0 {
+        let mut locked_pages = LOCKED_PAGES.lock().unwrap_or_else(|err| err.into_inner());
+        for page in pages(ptr, len) {
+            let Some(count) = locked_pages.get_mut(&page) else {
+                continue;
+            };
+            *count -= 1;
+            if *count == 0 {
+                locked_pages.remove(&page);
+                unsafe { libc::munlock(page as *const libc::c_void, page_size()) };
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (ptr, len);
+}
+
+///
+/// Get the addresses of the pages a region of memory lies on
+/// # Arguments
+/// - `ptr: *const u8`
+/// - `len: usize`
+/// 
+/// Returns `impl Iterator<Item = usize>`
+/// 
+#[cfg(unix)]
+fn pages(ptr: *const u8, len: usize) -> impl Iterator<Item = usize> {
+    let page_size = page_size();
+    let first = ptr as usize / page_size * page_size;
+    let last = (ptr as usize + len - 1) / page_size * page_size;
+    (first..=last).step_by(page_size)
+}
+
+///
+/// Get the size of a page of memory
+/// 
+/// Returns `usize`
+/// 
+#[cfg(unix)]
+fn page_size() -> usize {
+    *PAGE_SIZE.get_or_init(|| match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    })
+}
+
+///
+/// Get the soft limit of memory the process may lock
+/// 
+/// Returns `Option<libc::rlim_t>`, `None` if it is unlimited or unknown
+/// 
+#[cfg(unix)]
+fn memlock_limit() -> Option<libc::rlim_t> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    Some(limit.rlim_cur)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_locked(value: &[u8]) -> bool {
+        let page = value.as_ptr() as usize / page_size() * page_size();
+        LOCKED_PAGES.lock().unwrap().contains_key(&page)
+    }
+
+    #[test]
+    fn shared_pages_stay_locked() {
+        // Two secrets on a page no other test uses
+        let buffer = vec![0u8; 2 * page_size()].into_boxed_slice();
+        let start = buffer.as_ptr().align_offset(page_size());
+        let (first, second) = buffer[start..start + 64].split_at(32);
+        if !lock(first) {
+            println!("Memory can not be locked here, skipping.");
+            return;
+        }
+        assert!(lock(second));
+        unlock(first);
+        assert!(is_locked(second));
+        unlock(second);
+        assert!(!is_locked(second));
+    }
+}
@@ -0,0 +1,562 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    path::Path,
+    time::UNIX_EPOCH,
+};
+use zeroize::Zeroize;
+
+// Import functions from other files
+use crate::{
+    encryptionFunctions,
+    flatstore,
+    masterfile::MasterfileData,
+    secret::SecretBytes,
+};
+
+// Name of the encrypted manifest kept at the top of a mirror
+pub const MANIFEST_NAME: &str = "mirror.manifest";
+
+///
+/// Encrypted list of the files in a mirror.
+/// # Data
+/// - `checkout: Option<String>`
+///     - Working copy the vault is open in, which is the only plaintext
+///       tree it can be synced with until it is closed
+/// - `files: BTreeMap<String, ManifestEntry>`
+///     - Files by their plaintext path, relative to the top
+/// 
+#[derive(Default, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkout: Option<String>,
+    #[serde(default)]
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+///
+/// Plaintext file as it was last encrypted into the mirror.
+/// # Data
+/// - `mirror: String`
+///     - Path of the encrypted copy, relative to the top of the mirror
+/// - `size: u64`
+/// - `modified: u128`
+///     - Modification time in nanoseconds since the epoch
+/// 
+#[derive(Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub mirror: String,
+    pub size: u64,
+    pub modified: u128,
+}
+
+///
+/// Counts of what a sync did, printed when it is finished.
+/// # Data
+/// - `encrypted: usize`
+///     - Files that were new or changed
+/// - `unchanged: usize`
+/// - `removed: usize`
+///     - Files and folders removed from the mirror
+/// 
+#[derive(Default)]
+pub struct SyncStats {
+    pub encrypted: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+///
+/// Encrypt a plaintext directory tree into a vault, leaving the plaintext
+/// untouched. The vault holds an encrypted copy of every file under an
+/// encrypted name, so it can be stored somewhere that is not trusted.
+/// Only files whose size or modification time changed since the last sync
+/// are encrypted again, and copies of files that are no longer in the
+/// plaintext tree are removed from the vault. The paths of the encrypted
+/// copies are kept in an encrypted manifest at the top of the vault, and
+/// only copies listed there are ever removed. A vault that already holds
+/// other files can not become a mirror, and an empty plaintext tree is
+/// never synced into a mirror that has files. A vault that is open in a
+/// working copy only syncs with that working copy.
+/// # Arguments
+/// - `source_path: &str`
+///     - Top of the plaintext tree
+/// - `masterfile_path: &str`
+///     - Path to the masterfile of the vault holding the mirror
+/// - `masterfile_data: &MasterfileData`
+///     - Data of the decrypted masterfile
+/// 
+/// Returns `Result<SyncStats, anyhow::Error>`
+/// 
+pub fn sync(
+    source_path: &str,
+    masterfile_path: &str,
+    masterfile_data: &MasterfileData,
+) -> Result<SyncStats, anyhow::Error> {
+    let top = masterfile_path.strip_suffix("/masterfile.e")
+        .ok_or_else(|| anyhow!("{} is not a masterfile.", masterfile_path))?;
+    if masterfile_data.settings.layout == flatstore::LAYOUT_FLAT {
+        return Err(anyhow!("Mirrors need a vault with the tree layout."));
+    }
+
+    // Neither tree may be inside the other
+    let source = fs::canonicalize(source_path)?;
+    let mirror = fs::canonicalize(top)?;
+    if source.starts_with(&mirror) || mirror.starts_with(&source) {
+        return Err(anyhow!("The plaintext directory and the vault can not be inside each other."));
+    }
+    let source = source.to_str()
+        .ok_or_else(|| anyhow!("Invalid path {}.", source_path))?;
+
+    // A vault without a manifest must be empty, its files are not known to
+    // the mirror and would be removed as if they were deleted copies
+    if !Path::new(&join(top, MANIFEST_NAME)).exists() && !is_empty_vault(top)? {
+        return Err(anyhow!("The vault already holds files that are not part of a mirror. Mirror into an empty vault."));
+    }
+    let old_manifest = read_manifest(top, masterfile_data)?;
+
+    // An empty tree is more likely an unmounted drive or a lost working copy
+    // than every file deleted on purpose
+    if !old_manifest.files.is_empty() && fs::read_dir(source)?.next().is_none() {
+        return Err(anyhow!("{} is empty, syncing it would remove every file from the vault.", source));
+    }
+    if let Some(checkout) = old_manifest.checkout.as_ref().filter(|checkout| *checkout != source) {
+        return Err(anyhow!("The vault is open in {}. Close it first.", checkout));
+    }
+    let mut syncer = Syncer {
+        source,
+        top,
+        data: masterfile_data,
+        folder_key: encryptionFunctions::folder_key(masterfile_data)?,
+        old_manifest: old_manifest.files,
+        manifest: BTreeMap::new(),
+        folders: BTreeSet::new(),
+        stats: SyncStats::default(),
+    };
+    syncer.sync_dir("", "")?;
+    let Syncer { old_manifest: old_files, manifest, folders, mut stats, .. } = syncer;
+
+    // The manifest is written before old copies are removed, so an
+    // interrupted sync never loses track of a file in the mirror
+    let manifest = Manifest { checkout: old_manifest.checkout, files: manifest };
+    write_manifest(top, &manifest, masterfile_data)?;
+    let kept = manifest.files.values()
+        .map(|entry| entry.mirror.clone())
+        .collect::<BTreeSet<String>>();
+    prune(top, &old_files, &kept, &folders, &mut stats)?;
+    Ok(stats)
+}
+
+///
+/// Check whether a vault holds nothing but its masterfile.
+/// # Arguments
+/// - `top: &str`
+///     - Top of the vault
+/// 
+/// Returns `Result<bool, anyhow::Error>`
+/// 
+fn is_empty_vault(top: &str) -> Result<bool, anyhow::Error> {
+    for entry in fs::read_dir(top)? {
+        let name = entry?.file_name();
+        if name != "masterfile.e" && name != ".DS_Store" {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+///
+/// State of a sync while the plaintext tree is walked.
+/// # Data
+/// - `source: &str`
+///     - Top of the plaintext tree
+/// - `top: &str`
+///     - Top of the mirror
+/// - `data: &MasterfileData`
+/// - `folder_key: SecretBytes`
+/// - `old_manifest: BTreeMap<String, ManifestEntry>`
+///     - Manifest of the last sync, by plaintext path
+/// - `manifest: BTreeMap<String, ManifestEntry>`
+///     - Manifest being built
+/// - `folders: BTreeSet<String>`
+///     - Encrypted folders that belong in the mirror
+/// - `stats: SyncStats`
+/// 
+struct Syncer<'a> {
+    source: &'a str,
+    top: &'a str,
+    data: &'a MasterfileData,
+    folder_key: SecretBytes,
+    old_manifest: BTreeMap<String, ManifestEntry>,
+    manifest: BTreeMap<String, ManifestEntry>,
+    folders: BTreeSet<String>,
+    stats: SyncStats,
+}
+
+impl Syncer<'_> {
+    ///
+    /// Mirror a plaintext directory and everything below it. Symbolic links
+    /// are skipped.
+    /// # Arguments
+    /// - `relative: &str`
+    ///     - Plaintext path of the directory, relative to the top
+    /// - `mirror_relative: &str`
+    ///     - Encrypted path of the directory, relative to the top
+    /// 
+    /// Returns `Result<(), anyhow::Error>`
+    /// 
+    fn sync_dir(&mut self, relative: &str, mirror_relative: &str) -> Result<(), anyhow::Error> {
+        let mut entries = fs::read_dir(join(self.source, relative))?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = entry.file_name().into_string()
+                .map_err(|name| anyhow!("Invalid file name {:?} in {}.", name, join(self.source, relative)))?;
+            let file_relative = join(relative, &name);
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                let folder = join(mirror_relative, &encryptionFunctions::encrypt_foldername_with(&name, self.folder_key.expose(), self.data));
+                fs::create_dir_all(join(self.top, &folder))?;
+                self.folders.insert(folder.clone());
+                self.sync_dir(&file_relative, &folder)?;
+            } else if file_type.is_file() {
+                self.sync_file(&file_relative, mirror_relative, &entry.metadata()?)?;
+            } else {
+                println!("Skipping {}, only files and folders are mirrored.", join(self.source, &file_relative));
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Encrypt a plaintext file into the mirror unless its copy is current.
+    /// # Arguments
+    /// - `relative: &str`
+    ///     - Plaintext path of the file, relative to the top
+    /// - `mirror_dir: &str`
+    ///     - Encrypted path of its directory, relative to the top
+    /// - `metadata: &fs::Metadata`
+    /// 
+    /// Returns `Result<(), anyhow::Error>`
+    /// 
+    fn sync_file(&mut self, relative: &str, mirror_dir: &str, metadata: &fs::Metadata) -> Result<(), anyhow::Error> {
+        let size = metadata.len();
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+
+        // Keep the copy if the file did not change and the copy is still there
+        if let Some(old) = self.old_manifest.get(relative) {
+            if old.size == size && old.modified == modified && Path::new(&join(self.top, &old.mirror)).is_file() {
+                self.manifest.insert(relative.to_string(),
+                    ManifestEntry { mirror: old.mirror.clone(), size, modified });
+                self.stats.unchanged += 1;
+                return Ok(());
+            }
+        }
+
+        let dist_path = encryptionFunctions::encrypt_file_into(&join(self.source, relative),
+            &join(self.top, mirror_dir), &self.data.master_key, &self.data.settings)?;
+        let mirror = dist_path.strip_prefix(self.top).unwrap_or(&dist_path).trim_start_matches('/');
+        self.manifest.insert(relative.to_string(),
+            ManifestEntry { mirror: mirror.to_string(), size, modified });
+        self.stats.encrypted += 1;
+        Ok(())
+    }
+}
+
+///
+/// Remove the copies listed in the manifest of the last sync that are not
+/// part of this sync, such as copies of files that changed or were
+/// deleted, and the folders that are left empty by it. Nothing that is not
+/// listed in the manifest is ever removed.
+/// # Arguments
+/// - `top: &str`
+///     - Top of the mirror
+/// - `old_files: &BTreeMap<String, ManifestEntry>`
+///     - Manifest of the last sync
+/// - `kept: &BTreeSet<String>`
+///     - Paths of the copies to keep, relative to the top
+/// - `folders: &BTreeSet<String>`
+///     - Encrypted folders that belong in the mirror, relative to the top
+/// - `stats: &mut SyncStats`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn prune(
+    top: &str,
+    old_files: &BTreeMap<String, ManifestEntry>,
+    kept: &BTreeSet<String>,
+    folders: &BTreeSet<String>,
+    stats: &mut SyncStats,
+) -> Result<(), anyhow::Error> {
+    let mut emptied = BTreeSet::new();
+    for entry in old_files.values().filter(|entry| !kept.contains(&entry.mirror)) {
+        let path = join(top, &entry.mirror);
+        if Path::new(&path).is_file() {
+            fs::remove_file(&path)?;
+            stats.removed += 1;
+        }
+        let mut parent = Path::new(&entry.mirror).parent();
+        while let Some(folder) = parent.filter(|folder| !folder.as_os_str().is_empty()) {
+            emptied.insert(folder.to_string_lossy().to_string());
+            parent = folder.parent();
+        }
+    }
+
+    // Deepest folders first, only folders that are empty now go away
+    for folder in emptied.iter().rev().filter(|folder| !folders.contains(*folder)) {
+        let path = join(top, folder);
+        if fs::read_dir(&path).map(|mut entries| entries.next().is_none()).unwrap_or(false) {
+            fs::remove_dir(&path)?;
+            stats.removed += 1;
+        }
+    }
+    Ok(())
+}
+
+///
+/// Check whether a path is the manifest at the top of a vault, which is
+/// left alone when the vault is locked or unlocked.
+/// # Arguments
+/// - `path: &str`
+/// 
+/// Returns `bool`
+/// 
+pub fn is_manifest(path: &str) -> bool {
+    let path = Path::new(path);
+    path.file_name().is_some_and(|name| name == MANIFEST_NAME)
+        && path.parent().is_some_and(|top| top.join("masterfile.e").is_file())
+}
+
+///
+/// Read the manifest of a mirror, which is empty before the first sync.
+/// # Arguments
+/// - `top: &str`
+/// - `data: &MasterfileData`
+/// 
+/// Returns `Result<Manifest, anyhow::Error>`
+/// 
+pub fn read_manifest(top: &str, data: &MasterfileData) -> Result<Manifest, anyhow::Error> {
+    let manifest_path = join(top, MANIFEST_NAME);
+    if !Path::new(&manifest_path).exists() {
+        return Ok(Manifest::default());
+    }
+    let mut contents = Vec::new();
+    let manifest = encryptionFunctions::decrypt_file_into(&manifest_path, &mut contents, &data.master_key)
+        .and_then(|()| Ok(serde_json::from_slice(&contents)?));
+    contents.zeroize();
+    manifest.map_err(|err| anyhow!("Could not read the mirror manifest {}: {}", manifest_path, err))
+}
+
+///
+/// Move the manifest of a mirror over to new keys after the files were
+/// re-encrypted with them, pointing every entry to the new encrypted name
+/// of its copy. A manifest that already opens with the new keys is left
+/// as it is, so an interrupted rekey can run this again.
+/// # Arguments
+/// - `top: &str`
+/// - `renamed: &HashMap<String, String>`
+///     - New paths of the re-encrypted files by their old paths, relative
+///       to the top and before any folder was renamed
+/// - `old_data: &MasterfileData`
+/// - `new_data: &MasterfileData`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn rekey_manifest(
+    top: &str,
+    renamed: &HashMap<String, String>,
+    old_data: &MasterfileData,
+    new_data: &MasterfileData,
+) -> Result<(), anyhow::Error> {
+    if !Path::new(&join(top, MANIFEST_NAME)).exists() || read_manifest(top, new_data).is_ok() {
+        return Ok(());
+    }
+    let mut manifest = read_manifest(top, old_data)?;
+    let old_key = encryptionFunctions::folder_key(old_data)?;
+    let new_key = encryptionFunctions::folder_key(new_data)?;
+    for entry in manifest.files.values_mut() {
+        let mirror = renamed.get(&entry.mirror).unwrap_or(&entry.mirror);
+        let (folders, name) = mirror.rsplit_once('/').unwrap_or(("", mirror));
+        let mut path = String::new();
+        for folder in folders.split('/').filter(|folder| !folder.is_empty()) {
+            let foldername = encryptionFunctions::decrypt_foldername_with(folder, old_key.expose(), old_data)?;
+            path = join(&path, &encryptionFunctions::encrypt_foldername_with(&foldername, new_key.expose(), new_data));
+        }
+        entry.mirror = join(&path, name);
+    }
+    write_manifest(top, &manifest, new_data)
+}
+
+///
+/// Encrypt the manifest of a mirror and move it into place
+/// # Arguments
+/// - `top: &str`
+/// - `manifest: &Manifest`
+/// - `data: &MasterfileData`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn write_manifest(
+    top: &str,
+    manifest: &Manifest,
+    data: &MasterfileData,
+) -> Result<(), anyhow::Error> {
+    let manifest_path = join(top, MANIFEST_NAME);
+    let temp_path = format!("{}.tmp", manifest_path);
+    let mut contents = serde_json::to_vec(manifest)?;
+    let result = encryptionFunctions::encrypt_data_to(&contents, &temp_path, &data.master_key, &data.settings);
+    contents.zeroize();
+    result?;
+    fs::rename(&temp_path, &manifest_path)?;
+    Ok(())
+}
+
+///
+/// Join a relative path onto a directory, where an empty relative path is
+/// the directory itself
+/// # Arguments
+/// - `dir: &str`
+/// - `relative: &str`
+/// 
+/// Returns `String`
+/// 
+pub fn join(dir: &str, relative: &str) -> String {
+    match (dir.is_empty(), relative.is_empty()) {
+        (_, true) => dir.to_string(),
+        (true, false) => relative.to_string(),
+        (false, false) => format!("{}/{}", dir.trim_end_matches('/'), relative),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    // Plaintext tree next to an empty vault
+    fn test_dirs(name: &str) -> (String, String, MasterfileData) {
+        let (masterfile_path, data) = testutil::test_vault(&format!("mirror-{}", name), &testutil::test_settings());
+        let top = masterfile_path.trim_end_matches("/masterfile.e").to_string();
+        let source = format!("{}-source", top);
+        let _ = fs::remove_dir_all(&source);
+        fs::create_dir_all(format!("{}/sub/deeper", source)).unwrap();
+        fs::write(format!("{}/a.txt", source), "a").unwrap();
+        fs::write(format!("{}/sub/b.txt", source), "b").unwrap();
+        fs::write(format!("{}/sub/deeper/c.txt", source), "c").unwrap();
+        (source, top, data)
+    }
+
+    // Plaintext of every file in the mirror, by its path in the manifest
+    fn mirrored(top: &str, data: &MasterfileData) -> BTreeMap<String, Vec<u8>> {
+        read_manifest(top, data).unwrap().files.into_iter()
+            .map(|(path, entry)| {
+                let mut contents = Vec::new();
+                encryptionFunctions::decrypt_file_into(&join(top, &entry.mirror), &mut contents, &data.master_key).unwrap();
+                (path, contents)
+            })
+            .collect()
+    }
+
+    fn counts(stats: SyncStats) -> (usize, usize, usize) {
+        (stats.encrypted, stats.unchanged, stats.removed)
+    }
+
+    #[test]
+    fn sync_round_trip() {
+        let (source, top, data) = test_dirs("round-trip");
+        let masterfile_path = join(&top, "masterfile.e");
+        assert_eq!(counts(sync(&source, &masterfile_path, &data).unwrap()), (3, 0, 0));
+        assert_eq!(counts(sync(&source, &masterfile_path, &data).unwrap()), (0, 3, 0));
+        assert_eq!(mirrored(&top, &data), BTreeMap::from([
+            ("a.txt".to_string(), b"a".to_vec()),
+            ("sub/b.txt".to_string(), b"b".to_vec()),
+            ("sub/deeper/c.txt".to_string(), b"c".to_vec()),
+        ]));
+
+        // A changed size is encrypted again and a new file is added
+        fs::write(format!("{}/a.txt", source), "changed").unwrap();
+        fs::write(format!("{}/new.txt", source), "new").unwrap();
+        assert_eq!(counts(sync(&source, &masterfile_path, &data).unwrap()), (2, 2, 1));
+        let files = mirrored(&top, &data);
+        assert_eq!(files["a.txt"], b"changed");
+        assert_eq!(files["new.txt"], b"new");
+    }
+
+    #[test]
+    fn prune_only_manifest_files() {
+        let (source, top, data) = test_dirs("prune");
+        let masterfile_path = join(&top, "masterfile.e");
+        sync(&source, &masterfile_path, &data).unwrap();
+
+        // Files added to the vault by someone else are left alone
+        let foreign = join(&top, "foreign.encrypted");
+        fs::write(&foreign, "foreign").unwrap();
+        fs::remove_dir_all(format!("{}/sub", source)).unwrap();
+        assert_eq!(counts(sync(&source, &masterfile_path, &data).unwrap()), (0, 1, 4));
+        assert!(Path::new(&foreign).exists());
+        let mut names = fs::read_dir(&top).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| !name.ends_with(".encrypted") || name == "foreign.encrypted")
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["foreign.encrypted", "masterfile.e", MANIFEST_NAME]);
+        assert_eq!(mirrored(&top, &data).len(), 1);
+    }
+
+    #[test]
+    fn refused_syncs() {
+        let (source, top, data) = test_dirs("refused");
+        let masterfile_path = join(&top, "masterfile.e");
+
+        // A vault with files of its own never becomes a mirror
+        let own = join(&top, "own.txt");
+        fs::write(&own, "own").unwrap();
+        assert!(sync(&source, &masterfile_path, &data).is_err());
+        fs::remove_file(&own).unwrap();
+        sync(&source, &masterfile_path, &data).unwrap();
+
+        // An emptied plaintext tree does not wipe the mirror
+        let empty = format!("{}-empty", source);
+        fs::create_dir_all(&empty).unwrap();
+        assert!(sync(&empty, &masterfile_path, &data).is_err());
+        assert_eq!(mirrored(&top, &data).len(), 3);
+
+        // Neither tree may be inside the other
+        assert!(sync(&top, &masterfile_path, &data).is_err());
+    }
+
+    #[test]
+    fn tampered_manifest() {
+        let (source, top, data) = test_dirs("tampered");
+        let masterfile_path = join(&top, "masterfile.e");
+        sync(&source, &masterfile_path, &data).unwrap();
+
+        // A manifest that can not be read stops the sync instead of starting over
+        let manifest_path = join(&top, MANIFEST_NAME);
+        let mut contents = fs::read(&manifest_path).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 1;
+        fs::write(&manifest_path, &contents).unwrap();
+        assert!(read_manifest(&top, &data).is_err());
+        assert!(sync(&source, &masterfile_path, &data).is_err());
+
+        fs::write(&manifest_path, &contents[..contents.len() / 2]).unwrap();
+        assert!(read_manifest(&top, &data).is_err());
+        assert!(sync(&source, &masterfile_path, &data).is_err());
+    }
+
+    #[test]
+    fn manifest_paths() {
+        let (_, top, _) = test_dirs("paths");
+        assert!(is_manifest(&join(&top, MANIFEST_NAME)));
+        assert!(!is_manifest(&join(&top, &format!("sub/{}", MANIFEST_NAME))));
+        assert!(!is_manifest(&join(&top, &format!("my.{}", MANIFEST_NAME))));
+        assert_eq!(join("", "a"), "a");
+        assert_eq!(join("a/", "b"), "a/b");
+        assert_eq!(join("a", ""), "a");
+    }
+}
@@ -0,0 +1,269 @@
+// Import functions from external crates
+use std::io::{self, Read, Write};
+
+// Padding schemes that can be selected for a vault
+pub const PADDING_NONE: u8 = 0;
+pub const PADDING_PADME: u8 = 1;
+pub const PADDING_POWER_OF_TWO: u8 = 2;
+
+// Byte marking the start of the padding. Everything after it is zero.
+const PADDING_MARKER: u8 = 0x80;
+
+///
+/// Get the length a stream of the given length is padded to.
+/// # Arguments
+/// - `scheme: u8`
+///     - Padding scheme of the vault
+///         - 0: no padding
+///         - 1: Padmé, leaks at most O(log log n) bits of the length
+///         - 2: next power of two
+/// - `len: u64`
+///     - Length of the stream including the padding marker
+/// 
+/// Returns `u64`
+/// 
+pub fn padded_length(scheme: u8, len: u64) -> u64 {
+    match scheme {
+        PADDING_PADME => padme(len),
+        PADDING_POWER_OF_TWO => len.next_power_of_two(),
+        _ => len,
+    }
+}
+
+///
+/// Padmé padding as described in "Reducing Metadata Leakage from Encrypted
+/// Files and Communication with PURBs". Rounds the length so that only the
+/// top bits of its exponent remain significant.
+/// # Arguments
+/// - `len: u64`
+///     - Length to pad
+/// 
+/// Returns `u64`
+/// 
+fn padme(len: u64) -> u64 {
+    if len < 2 {
+        return len;
+    }
+    let exponent = 63 - len.leading_zeros() as u64;
+    let exponent_bits = 64 - exponent.leading_zeros() as u64;
+    let last_bits = exponent - exponent_bits;
+    let bit_mask = (1u64 << last_bits) - 1;
+    (len + bit_mask) & !bit_mask
+}
+
+///
+/// Reader that appends padding once the inner reader is exhausted.
+/// The padding is a single marker byte followed by zeros up to the
+/// length chosen by the padding scheme.
+/// 
+pub struct PaddingReader<R: Read> {
+    inner: R,
+    scheme: u8,
+    read_count: u64,
+    remaining_padding: Option<u64>,
+}
+
+impl<R: Read> PaddingReader<R> {
+    ///
+    /// Initialization for the PaddingReader object
+    /// # Arguments
+    /// - `inner: R`
+    ///     - Reader producing the data to pad
+    /// - `scheme: u8`
+    ///     - Padding scheme of the vault
+    /// 
+    /// Returns `PaddingReader<R>`
+    /// 
+    pub fn new(inner: R, scheme: u8) -> PaddingReader<R> {
+        PaddingReader {
+            inner,
+            scheme,
+            read_count: 0,
+            remaining_padding: None,
+        }
+    }
+}
+
+impl<R: Read> Read for PaddingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Pass data through until the inner reader is exhausted
+        if self.remaining_padding.is_none() {
+            let read_count = self.inner.read(buf)?;
+            if read_count != 0 {
+                self.read_count += read_count as u64;
+                return Ok(read_count);
+            }
+
+            // Write the marker and work out how many zeros follow it
+            let total = padded_length(self.scheme, self.read_count + 1);
+            self.remaining_padding = Some(total - self.read_count - 1);
+            buf[0] = PADDING_MARKER;
+            return Ok(1);
+        }
+
+        // Write out the zeros
+        let remaining = self.remaining_padding.unwrap_or(0);
+        let count = remaining.min(buf.len() as u64) as usize;
+        buf[..count].fill(0);
+        self.remaining_padding = Some(remaining - count as u64);
+        Ok(count)
+    }
+}
+
+///
+/// Writer that strips the padding written by `PaddingReader`. The last
+/// non-zero byte and the zeros after it are held back, so when the stream
+/// ends the marker and the padding are never written out. `finish` must be
+/// called at the end to check that the padding was there.
+/// 
+pub struct PaddingStripper<W: Write> {
+    inner: W,
+    held_byte: Option<u8>,
+    held_zeros: u64,
+}
+
+impl<W: Write> PaddingStripper<W> {
+    ///
+    /// Initialization for the PaddingStripper object
+    /// # Arguments
+    /// - `inner: W`
+    ///     - Writer receiving the data without the padding
+    /// 
+    /// Returns `PaddingStripper<W>`
+    /// 
+    pub fn new(inner: W) -> PaddingStripper<W> {
+        PaddingStripper {
+            inner,
+            held_byte: None,
+            held_zeros: 0,
+        }
+    }
+
+    ///
+    /// End the stream and get the inner writer back. The stream must end
+    /// with the marker and its zeros, anything else was cut short.
+    /// 
+    /// Returns `io::Result<W>`
+    /// 
+    pub fn finish(self) -> io::Result<W> {
+        if self.held_byte != Some(PADDING_MARKER) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Padding is missing, the data was cut short."));
+        }
+        Ok(self.inner)
+    }
+
+    ///
+    /// Write out the held back bytes, used once a later non-zero byte
+    /// shows they were not padding.
+    /// 
+    fn release_held(&mut self) -> io::Result<()> {
+        if let Some(byte) = self.held_byte.take() {
+            self.inner.write_all(&[byte])?;
+        }
+        let zeros = [0u8; 512];
+        while self.held_zeros > 0 {
+            let count = self.held_zeros.min(zeros.len() as u64) as usize;
+            self.inner.write_all(&zeros[..count])?;
+            self.held_zeros -= count as u64;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for PaddingStripper<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match buf.iter().rposition(|byte| *byte != 0) {
+            // Only zeros, they might be padding so hold them back
+            None => self.held_zeros += buf.len() as u64,
+            Some(last) => {
+                self.release_held()?;
+                self.inner.write_all(&buf[..last])?;
+                self.held_byte = Some(buf[last]);
+                self.held_zeros = (buf.len() - last - 1) as u64;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pad a buffer and strip the padding again
+    fn round_trip(data: &[u8], scheme: u8) -> (usize, Vec<u8>) {
+        let mut padded = Vec::new();
+        PaddingReader::new(data, scheme).read_to_end(&mut padded).unwrap();
+        let mut stripped = Vec::new();
+        let mut stripper = PaddingStripper::new(&mut stripped);
+        // Write in small pieces so held back bytes cross write calls
+        for chunk in padded.chunks(7) {
+            stripper.write_all(chunk).unwrap();
+        }
+        stripper.finish().unwrap();
+        (padded.len(), stripped)
+    }
+
+    #[test]
+    fn padme_lengths() {
+        assert_eq!(padme(0), 0);
+        assert_eq!(padme(1), 1);
+        assert_eq!(padme(9), 10);
+        assert_eq!(padme(1000), 1024);
+        for len in 2..20_000u64 {
+            let padded = padme(len);
+            assert!(padded >= len);
+            // Padmé never adds more than 12% for lengths of this size
+            assert!(padded - len <= len / 8, "{} padded to {}", len, padded);
+            assert_eq!(padme(padded), padded);
+        }
+    }
+
+    #[test]
+    fn padded_length_schemes() {
+        assert_eq!(padded_length(PADDING_NONE, 1001), 1001);
+        assert_eq!(padded_length(PADDING_PADME, 1001), 1024);
+        assert_eq!(padded_length(PADDING_POWER_OF_TWO, 1025), 2048);
+    }
+
+    #[test]
+    fn round_trip_every_scheme() {
+        let inputs: [Vec<u8>; 6] = [
+            Vec::new(),
+            vec![0],
+            vec![PADDING_MARKER],
+            vec![0u8; 3000],
+            [vec![1u8; 600], vec![0u8; 700]].concat(),
+            (0..5000u32).map(|i| (i % 251) as u8).collect(),
+        ];
+        for scheme in [PADDING_NONE, PADDING_PADME, PADDING_POWER_OF_TWO] {
+            for input in &inputs {
+                let (padded_len, stripped) = round_trip(input, scheme);
+                assert_eq!(padded_len as u64, padded_length(scheme, input.len() as u64 + 1));
+                assert_eq!(&stripped, input);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_padding() {
+        let data = b"plaintext\0\0";
+        let mut padded = Vec::new();
+        PaddingReader::new(&data[..], PADDING_POWER_OF_TWO).read_to_end(&mut padded).unwrap();
+
+        // A stream cut off inside the data, or without any padding, is refused
+        for len in [data.len() - 1, data.len(), 0] {
+            let mut stripper = PaddingStripper::new(Vec::new());
+            stripper.write_all(&padded[..len]).unwrap();
+            assert!(stripper.finish().is_err(), "length {}", len);
+        }
+    }
+}
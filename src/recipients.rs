@@ -0,0 +1,150 @@
+// Import functions from external crates
+use age::secrecy::SecretString;
+use anyhow::anyhow;
+use std::{
+    fs,
+    io::{BufReader, Cursor, Read, Write},
+    iter,
+    str::FromStr,
+};
+
+///
+/// Callbacks handed to age so passphrase protected SSH keys can be
+/// unlocked with a prompt in the terminal.
+/// 
+#[derive(Clone)]
+struct TerminalCallbacks;
+
+impl age::Callbacks for TerminalCallbacks {
+    fn display_message(&self, message: &str) {
+        println!("{}", message);
+    }
+
+    fn confirm(&self, _message: &str, _yes_string: &str, _no_string: Option<&str>) -> Option<bool> {
+        None
+    }
+
+    fn request_public_string(&self, _description: &str) -> Option<String> {
+        None
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<SecretString> {
+        rpassword::prompt_password(format!("{} ", description))
+            .ok()
+            .map(SecretString::from)
+    }
+}
+
+///
+/// Parse a public key into an age recipient. Accepts native age X25519
+/// recipients (`age1...`) and SSH ed25519 or RSA public keys.
+/// # Arguments
+/// - `recipient: &str`
+///     - Public key as printed by `age-keygen` or found in `id_ed25519.pub`
+/// 
+/// Returns `Result<Box<dyn age::Recipient + Send>, anyhow::Error>`
+/// 
+pub fn parse_recipient(recipient: &str) -> Result<Box<dyn age::Recipient + Send>, anyhow::Error> {
+    let recipient = recipient.trim();
+    if let Ok(x25519) = age::x25519::Recipient::from_str(recipient) {
+        return Ok(Box::new(x25519));
+    }
+    match age::ssh::Recipient::from_str(recipient) {
+        Ok(ssh) => Ok(Box::new(ssh)),
+        Err(_) => Err(anyhow!("{} is not an age or SSH public key.", recipient)),
+    }
+}
+
+///
+/// Normalize a public key so the same key is always stored and compared
+/// the same way. SSH keys lose their trailing comment.
+/// # Arguments
+/// - `recipient: &str`
+/// 
+/// Returns `String`
+/// 
+pub fn normalize_recipient(recipient: &str) -> String {
+    let recipient = recipient.trim();
+    if recipient.starts_with("ssh-") {
+        recipient.split_whitespace().take(2).collect::<Vec<&str>>().join(" ")
+    } else {
+        recipient.to_string()
+    }
+}
+
+///
+/// Load the identities from an age identity file or an SSH private key.
+/// # Arguments
+/// - `path: &str`
+///     - Path to the identity file
+/// 
+/// Returns `Result<Vec<Box<dyn age::Identity>>, anyhow::Error>`
+/// 
+pub fn load_identities(path: &str) -> Result<Vec<Box<dyn age::Identity>>, anyhow::Error> {
+    let contents = fs::read(path)?;
+
+    // Native age identity files hold one or more AGE-SECRET-KEY lines
+    if let Ok(identity_file) = age::IdentityFile::from_buffer(BufReader::new(Cursor::new(&contents))) {
+        if let Ok(identities) = identity_file.into_identities() {
+            if !identities.is_empty() {
+                return Ok(identities
+                    .into_iter()
+                    .map(|identity| identity as Box<dyn age::Identity>)
+                    .collect());
+            }
+        }
+    }
+
+    // Otherwise try to read it as an SSH private key
+    match age::ssh::Identity::from_buffer(BufReader::new(Cursor::new(&contents)), Some(path.to_string())) {
+        Ok(age::ssh::Identity::Unsupported(_)) | Err(_) => {
+            Err(anyhow!("{} is not a supported age identity or SSH key.", path))
+        }
+        Ok(identity) => Ok(vec![Box::new(identity.with_callbacks(TerminalCallbacks))]),
+    }
+}
+
+///
+/// Encrypt data to a single recipient in the binary age format.
+/// # Arguments
+/// - `recipient: &dyn age::Recipient`
+/// - `plaintext: &[u8]`
+/// 
+/// Returns `Result<Vec<u8>, anyhow::Error>`
+/// 
+pub fn encrypt_to(
+    recipient: &dyn age::Recipient,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, anyhow::Error> {
+    let encryptor = age::Encryptor::with_recipients(iter::once(recipient))?;
+    let mut ciphertext = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut ciphertext)?;
+    writer.write_all(plaintext)?;
+    writer.finish()?;
+    Ok(ciphertext)
+}
+
+///
+/// Decrypt age data with any of the given identities. Returns `None`
+/// when none of the identities match a recipient of the data.
+/// # Arguments
+/// - `identities: &[Box<dyn age::Identity>]`
+/// - `ciphertext: &[u8]`
+/// 
+/// Returns `Result<Option<Vec<u8>>, anyhow::Error>`
+/// 
+pub fn decrypt_with(
+    identities: &[Box<dyn age::Identity>],
+    ciphertext: &[u8],
+) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let decryptor = age::Decryptor::new_buffered(ciphertext)?;
+    match decryptor.decrypt(identities.iter().map(|identity| identity.as_ref())) {
+        Ok(mut reader) => {
+            let mut plaintext = Vec::new();
+            reader.read_to_end(&mut plaintext)?;
+            Ok(Some(plaintext))
+        }
+        Err(age::DecryptError::NoMatchingKeys) => Ok(None),
+        Err(err) => Err(anyhow!("Decrypting recipient slot: {}", err)),
+    }
+}
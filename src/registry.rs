@@ -0,0 +1,226 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+use zeroize::Zeroize;
+
+// Import functions from other files
+use crate::{
+    agent,
+    cipher::CipherSuite,
+    functions,
+    kdf::{self, KdfParams},
+    secret::SecretBytes,
+};
+
+// Cipher of the sealed registry
+const REGISTRY_CIPHER: CipherSuite = CipherSuite::XChaCha20Poly1305;
+
+// Registry keys unlocked by this process, by the path of their config file,
+// so the passphrase is only asked once
+static UNLOCKED: Mutex<BTreeMap<String, Arc<RegistryKey>>> = Mutex::new(BTreeMap::new());
+
+///
+/// Encrypted contents of the config file, stored in place of the vault
+/// list when the registry is protected. Every field is hex encoded.
+/// # Data
+/// - `kdf: String`
+///     - Argon2id parameters the key was derived with
+/// - `salt: String`
+/// - `nonce: String`
+/// - `ciphertext: String`
+///     - The config file as it would be written without protection
+/// 
+#[derive(Serialize, Deserialize)]
+pub struct SealedRegistry {
+    kdf: String,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+///
+/// Key of an unlocked registry with what is needed to seal it again.
+/// # Data
+/// - `key: SecretBytes`
+///     - Key derived from the registry passphrase
+/// - `kdf: KdfParams`
+/// - `salt: [u8; 32]`
+/// 
+struct RegistryKey {
+    key: SecretBytes,
+    kdf: KdfParams,
+    salt: [u8; 32],
+}
+
+///
+/// Check whether the registry of a config file was unlocked or protected
+/// by this process, so it has to be sealed when it is written.
+/// # Arguments
+/// - `config_path: &str`
+/// 
+/// Returns `bool`
+/// 
+pub fn is_protected(config_path: &str) -> bool {
+    UNLOCKED.lock().unwrap().contains_key(config_path)
+}
+
+///
+/// Protect the registry of a config file under a new passphrase. The
+/// registry is sealed the next time the config file is written.
+/// # Arguments
+/// - `config_path: &str`
+/// - `passphrase: &str`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn protect(config_path: &str, passphrase: &str) -> Result<(), anyhow::Error> {
+    let kdf = kdf::profile("interactive").unwrap();
+    let mut salt = [0u8; 32];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(passphrase, &kdf, &salt)?;
+    agent::store_registry_key(config_path, key.expose());
+    remember(config_path, key, kdf, salt);
+    Ok(())
+}
+
+///
+/// Stop protecting the registry of a config file, it is written in plain
+/// text the next time. The agent forgets the key as well.
+/// # Arguments
+/// - `config_path: &str`
+/// 
+pub fn unprotect(config_path: &str) {
+    UNLOCKED.lock().unwrap().remove(config_path);
+    agent::forget_registry_key(config_path);
+}
+
+///
+/// Encrypt the contents of the config file with the key of its registry.
+/// # Arguments
+/// - `config_path: &str`
+/// - `plaintext: &[u8]`
+///     - Contents of the config file without protection
+/// 
+/// Returns `Result<SealedRegistry, anyhow::Error>`
+/// 
+pub fn seal(config_path: &str, plaintext: &[u8]) -> Result<SealedRegistry, anyhow::Error> {
+    let registry_key = UNLOCKED.lock().unwrap().get(config_path).cloned()
+        .ok_or_else(|| anyhow!("The registry of {} is not unlocked.", config_path))?;
+    let mut nonce = vec![0u8; REGISTRY_CIPHER.nonce_len()];
+    OsRng.fill_bytes(&mut nonce);
+    let aad = registry_aad(&registry_key.kdf, &registry_key.salt);
+    let ciphertext = REGISTRY_CIPHER.seal_with_aad(registry_key.key.expose(), &nonce, plaintext, &aad)?;
+    Ok(SealedRegistry {
+        kdf: hex::encode(registry_key.kdf.to_bytes()),
+        salt: hex::encode(registry_key.salt),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+
+///
+/// Decrypt a sealed registry. The key is taken from this process or the
+/// agent when they hold it, otherwise the registry passphrase is asked
+/// for. The key is then kept for the rest of the process and handed to
+/// the agent.
+/// # Arguments
+/// - `config_path: &str`
+/// - `sealed: &SealedRegistry`
+/// 
+/// Returns `Result<Vec<u8>, anyhow::Error>`, the contents of the config file
+/// 
+pub fn open(config_path: &str, sealed: &SealedRegistry) -> Result<Vec<u8>, anyhow::Error> {
+    let kdf = KdfParams::from_bytes(&hex::decode(&sealed.kdf)?)?;
+    let salt: [u8; 32] = hex::decode(&sealed.salt)?.try_into()
+        .map_err(|_| anyhow!("Malformed registry in {}.", config_path))?;
+    let nonce = hex::decode(&sealed.nonce)?;
+    let ciphertext = hex::decode(&sealed.ciphertext)?;
+    let aad = registry_aad(&kdf, &salt);
+
+    // Try the keys that are already at hand first
+    let cached = UNLOCKED.lock().unwrap().get(config_path).map(|registry_key| registry_key.key.expose().to_vec());
+    for mut key in cached.into_iter().chain(agent::fetch_registry_key(config_path).map(|key| key.expose().to_vec())) {
+        if let Ok(plaintext) = REGISTRY_CIPHER.open_with_aad(&key, &nonce, &ciphertext, &aad) {
+            remember(config_path, SecretBytes::new(key), kdf, salt);
+            return Ok(plaintext);
+        }
+        key.zeroize();
+    }
+
+    let passphrase = functions::get_password_input("Enter registry passphrase: ")?;
+    let key = derive_key(passphrase.expose(), &kdf, &salt)?;
+    let plaintext = REGISTRY_CIPHER.open_with_aad(key.expose(), &nonce, &ciphertext, &aad)
+        .map_err(|_| anyhow!("Registry passphrase does not match."))?;
+    agent::store_registry_key(config_path, key.expose());
+    remember(config_path, key, kdf, salt);
+    Ok(plaintext)
+}
+
+///
+/// Get the associated data of a sealed registry. The argon2 parameters and
+/// the salt are stored next to the ciphertext and written back when the
+/// registry is sealed again, so they are bound to it and a registry whose
+/// parameters were changed no longer opens.
+/// # Arguments
+/// - `kdf: &KdfParams`
+/// - `salt: &[u8; 32]`
+/// 
+/// Returns `Vec<u8>`
+/// 
+fn registry_aad(kdf: &KdfParams, salt: &[u8; 32]) -> Vec<u8> {
+    [&kdf.to_bytes()[..], &salt[..]].concat()
+}
+
+///
+/// Keep the key of an unlocked registry for the rest of the process.
+/// # Arguments
+/// - `config_path: &str`
+/// - `key: SecretBytes`
+/// - `kdf: KdfParams`
+/// - `salt: [u8; 32]`
+/// 
+fn remember(config_path: &str, key: SecretBytes, kdf: KdfParams, salt: [u8; 32]) {
+    UNLOCKED.lock().unwrap().insert(config_path.to_string(), Arc::new(RegistryKey { key, kdf, salt }));
+}
+
+///
+/// Derive the registry key from the passphrase.
+/// # Arguments
+/// - `passphrase: &str`
+/// - `kdf: &KdfParams`
+/// - `salt: &[u8; 32]`
+/// 
+/// Returns `Result<SecretBytes, anyhow::Error>`
+/// 
+fn derive_key(passphrase: &str, kdf: &KdfParams, salt: &[u8; 32]) -> Result<SecretBytes, anyhow::Error> {
+    Ok(SecretBytes::new(argon2::hash_raw(passphrase.as_bytes(), salt, &kdf.config())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kdf_and_salt_are_authenticated() {
+        let config_path = "/nonexistent/registry-authenticated/config";
+        let kdf = kdf::profile("interactive").unwrap();
+        let key = [1u8; 32];
+        remember(config_path, SecretBytes::new(key.to_vec()), kdf, [2u8; 32]);
+        let sealed = seal(config_path, b"contents").unwrap();
+        assert_eq!(open(config_path, &sealed).unwrap(), b"contents");
+
+        // The key at hand does not open the registry once either was changed
+        let nonce = hex::decode(&sealed.nonce).unwrap();
+        let ciphertext = hex::decode(&sealed.ciphertext).unwrap();
+        let other = KdfParams { time_cost: kdf.time_cost + 1, ..kdf };
+        for aad in [registry_aad(&other, &[2u8; 32]), registry_aad(&kdf, &[3u8; 32]), Vec::new()] {
+            assert!(REGISTRY_CIPHER.open_with_aad(&key, &nonce, &ciphertext, &aad).is_err());
+        }
+        unprotect(config_path);
+    }
+}
@@ -0,0 +1,410 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Write,
+    path::Path,
+};
+
+// Import functions from other files
+use crate::{
+    encryptionFunctions,
+    flatstore,
+    masterfile::MasterfileData,
+    mirror,
+};
+
+// Name of the journal kept at the top of a vault during a rekey
+pub const JOURNAL_NAME: &str = "rekey.journal";
+
+// Suffix of the new ciphertext while it is being written
+const TEMP_SUFFIX: &str = ".rekey";
+
+// Journal line written once every file is done and the folders are next
+const FOLDERS_MARKER: &str = "folders";
+
+///
+/// Journal of a rekey in progress. Every file that was re-encrypted and
+/// verified is recorded as `temp\tfinal\told`, relative to the top of the
+/// vault, before it is moved into place, so an interrupted rekey can
+/// finish the move and skip the file when it is resumed.
+/// # Data
+/// - `top: String`
+///     - Path to the top directory of the vault
+/// - `done: HashSet<String>`
+///     - Final paths of the files already re-encrypted
+/// - `renamed: HashMap<String, String>`
+///     - Final paths of the re-encrypted files by their old paths, both
+///       relative to the top
+/// - `folders: bool`
+///     - Whether every file is done and the folders are being renamed
+/// 
+struct Journal {
+    top: String,
+    done: HashSet<String>,
+    renamed: HashMap<String, String>,
+    folders: bool,
+}
+
+impl Journal {
+    ///
+    /// Open the journal of a vault, finishing the moves of the files it
+    /// records and removing new ciphertext that was never recorded.
+    /// # Arguments
+    /// - `top: &str`
+    ///     - Path to the top directory of the vault
+    /// 
+    /// Returns `Result<Journal, anyhow::Error>`
+    /// 
+    fn open(top: &str) -> Result<Journal, anyhow::Error> {
+        let mut journal = Journal { top: top.to_string(), done: HashSet::new(), renamed: HashMap::new(), folders: false };
+        let contents = match fs::read_to_string(journal.path()) {
+            Ok(contents) => contents,
+            Err(_) => return Ok(journal),
+        };
+
+        for line in contents.lines() {
+            if line == FOLDERS_MARKER {
+                journal.folders = true;
+                continue;
+            }
+            // A torn last line was never followed by a move
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            if parts.len() != 3 {
+                continue;
+            }
+            let (temp, dist, old) = (journal.full(parts[0]), journal.full(parts[1]), journal.full(parts[2]));
+            if Path::new(&temp).exists() {
+                fs::rename(&temp, &dist)?;
+            }
+            if old != dist && Path::new(&old).exists() {
+                fs::remove_file(&old)?;
+            }
+            journal.done.insert(dist);
+            journal.renamed.insert(parts[2].to_string(), parts[1].to_string());
+        }
+        Ok(journal)
+    }
+
+    ///
+    /// Get the path of the journal file
+    /// 
+    /// Returns `String`
+    /// 
+    fn path(&self) -> String {
+        format!("{}/{}", self.top, JOURNAL_NAME)
+    }
+
+    ///
+    /// Get the full path of a path recorded relative to the top
+    /// 
+    /// Returns `String`
+    /// 
+    fn full(&self, relative: &str) -> String {
+        format!("{}/{}", self.top, relative)
+    }
+
+    ///
+    /// Get the path relative to the top to record in the journal
+    /// 
+    /// Returns `String`
+    /// 
+    fn relative<'a>(&self, path: &'a str) -> &'a str {
+        path.strip_prefix(&self.top).unwrap_or(path).trim_start_matches('/')
+    }
+
+    ///
+    /// Append a line to the journal and flush it to disk
+    /// # Arguments
+    /// - `line: &str`
+    /// 
+    /// Returns `Result<(), anyhow::Error>`
+    /// 
+    fn append(&self, line: &str) -> Result<(), anyhow::Error> {
+        let mut journal_file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path())?;
+        journal_file.write_all(format!("{}\n", line).as_bytes())?;
+        journal_file.sync_all()?;
+        Ok(())
+    }
+
+    ///
+    /// Re-encrypt a single file into its temp path, record it, then move
+    /// it into place and remove the old ciphertext.
+    /// # Arguments
+    /// - `path: &str`
+    ///     - Path to the file encrypted with the old keys
+    /// - `dist_path: Option<&str>`
+    ///     - Final path, `None` to encrypt the filename again
+    /// - `old_data: &MasterfileData`
+    /// - `new_data: &MasterfileData`
+    /// 
+    /// Returns `Result<(), anyhow::Error>`
+    /// 
+    fn rekey_file(
+        &mut self,
+        path: &str,
+        dist_path: Option<&str>,
+        old_data: &MasterfileData,
+        new_data: &MasterfileData,
+    ) -> Result<(), anyhow::Error> {
+        let temp_path = format!("{}{}", path, TEMP_SUFFIX);
+        let dist_path = encryptionFunctions::rekey_file(path, &temp_path, dist_path,
+            &old_data.master_key, &new_data.master_key, &new_data.settings)?;
+
+        // The new file is verified, so record it before anything is replaced
+        self.append(&format!("{}\t{}\t{}",
+            self.relative(&temp_path), self.relative(&dist_path), self.relative(path)))?;
+        fs::rename(&temp_path, &dist_path)?;
+        if dist_path != path {
+            fs::remove_file(path)?;
+        }
+        self.renamed.insert(self.relative(path).to_string(), self.relative(&dist_path).to_string());
+        self.done.insert(dist_path);
+        Ok(())
+    }
+}
+
+///
+/// Re-encrypt every file and folder name of a vault from the old keys to
+/// the new ones. Each file is verified before its old ciphertext is
+/// removed, and the work is journaled so an interrupted rekey can be run
+/// again with the same keys.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// - `old_data: &MasterfileData`
+///     - Data unlocked with the current password
+/// - `new_data: &MasterfileData`
+///     - New keys returned by `masterfile::start_rekey`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn rekey_vault(
+    top: &str,
+    old_data: &MasterfileData,
+    new_data: &MasterfileData,
+) -> Result<(), anyhow::Error> {
+    let mut journal = Journal::open(top)?;
+
+    if old_data.settings.layout == flatstore::LAYOUT_FLAT {
+        return rekey_store(&mut journal, old_data, new_data);
+    }
+
+    if !journal.folders {
+        println!("Re-encrypting Files");
+        rekey_tree(&mut journal, top, old_data, new_data)?;
+        journal.append(FOLDERS_MARKER)?;
+    }
+    println!("Re-encrypting Foldernames");
+    rekey_folders(top, old_data, new_data)?;
+    mirror::rekey_manifest(top, &journal.renamed, old_data, new_data)
+}
+
+///
+/// Remove the journal once the rekey is finished.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn remove_journal(top: &str) -> Result<(), anyhow::Error> {
+    let path = format!("{}/{}", top, JOURNAL_NAME);
+    if Path::new(&path).exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+///
+/// Re-encrypt the files of a directory tree, recursing into subdirectories.
+/// # Arguments
+/// - `journal: &mut Journal`
+/// - `dir: &str`
+///     - Directory to re-encrypt
+/// - `old_data: &MasterfileData`
+/// - `new_data: &MasterfileData`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn rekey_tree(
+    journal: &mut Journal,
+    dir: &str,
+    old_data: &MasterfileData,
+    new_data: &MasterfileData,
+) -> Result<(), anyhow::Error> {
+    let mut paths = Vec::new();
+    for path_inv in fs::read_dir(dir)? {
+        let path = path_inv?.path().into_os_string().into_string()
+            .map_err(|_| anyhow!("Invalid path in {}.", dir))?;
+
+        // Remove ciphertext of a file that was not recorded before the interruption
+        if path.ends_with(&format!(".encrypted{}", TEMP_SUFFIX)) {
+            fs::remove_file(&path)?;
+        } else {
+            paths.push(path);
+        }
+    }
+
+    for path in paths {
+        if Path::new(&path).is_dir() {
+            rekey_tree(journal, &path, old_data, new_data)?;
+        } else if path.ends_with(".encrypted") && !journal.done.contains(&path) {
+            journal.rekey_file(&path, None, old_data, new_data)?;
+        }
+    }
+    Ok(())
+}
+
+///
+/// Re-encrypt the folder names of a directory tree, deepest first.
+/// # Arguments
+/// - `dir: &str`
+///     - Directory whose subdirectories are renamed
+/// - `old_data: &MasterfileData`
+/// - `new_data: &MasterfileData`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn rekey_folders(
+    dir: &str,
+    old_data: &MasterfileData,
+    new_data: &MasterfileData,
+) -> Result<(), anyhow::Error> {
+    for path_inv in fs::read_dir(dir)? {
+        let path = path_inv?.path().into_os_string().into_string()
+            .map_err(|_| anyhow!("Invalid path in {}.", dir))?;
+        if Path::new(&path).is_dir() {
+            rekey_folders(&path, old_data, new_data)?;
+            if path.ends_with(".encrypted") {
+                encryptionFunctions::rekey_foldername(&path, old_data, new_data)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+///
+/// Re-encrypt every blob of a flat vault in place. Blob ids are random, so
+/// only the listing of the top directory moves, to the id derived from the
+/// new master key.
+/// # Arguments
+/// - `journal: &mut Journal`
+/// - `old_data: &MasterfileData`
+/// - `new_data: &MasterfileData`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn rekey_store(
+    journal: &mut Journal,
+    old_data: &MasterfileData,
+    new_data: &MasterfileData,
+) -> Result<(), anyhow::Error> {
+    let store = format!("{}/{}", journal.top, flatstore::STORE_DIR);
+    if !Path::new(&store).is_dir() {
+        return Ok(())
+    }
+    let old_root = flatstore::root_blob_path(&journal.top, old_data);
+    let new_root = flatstore::root_blob_path(&journal.top, new_data);
+
+    // List the blobs first, the shards change while they are re-encrypted
+    let mut blobs = Vec::new();
+    for shard in fs::read_dir(&store)? {
+        for blob in fs::read_dir(shard?.path())? {
+            let blob = blob?.path().into_os_string().into_string()
+                .map_err(|_| anyhow!("Invalid path in {}.", store))?;
+
+            // Remove ciphertext of a blob that was not recorded before the interruption
+            if blob.ends_with(TEMP_SUFFIX) {
+                fs::remove_file(&blob)?;
+            } else {
+                blobs.push(blob);
+            }
+        }
+    }
+
+    println!("Re-encrypting Files");
+    for blob in blobs {
+        if journal.done.contains(&blob) || blob == new_root {
+            continue;
+        } else if blob == old_root {
+            fs::create_dir_all(Path::new(&new_root).parent().unwrap())?;
+            journal.rekey_file(&blob, Some(&new_root), old_data, new_data)?;
+        } else {
+            journal.rekey_file(&blob, Some(&blob), old_data, new_data)?;
+        }
+    }
+
+    // The shard of the old listing is left empty unless other blobs share it
+    fs::remove_dir(Path::new(&old_root).parent().unwrap()).ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{functions, masterfile, testutil};
+    use std::sync::Arc;
+
+    // Encrypted files below a directory, in a stable order
+    fn encrypted_files(dir: &str) -> Vec<String> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path().to_string_lossy().to_string();
+            if Path::new(&path).is_dir() {
+                files.extend(encrypted_files(&path));
+            } else if path.ends_with(".encrypted") {
+                files.push(path);
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn interrupted_rekey_resumes() {
+        let (masterfile_path, old_data) = testutil::test_vault("rekey-resume", &testutil::test_settings());
+        let top = masterfile_path.trim_end_matches("/masterfile.e").to_string();
+        let plaintext = [("a.txt", "a"), ("sub/b.txt", "b"), ("sub/deeper/c.txt", "c")];
+        fs::create_dir_all(format!("{}/sub/deeper", top)).unwrap();
+        for (name, contents) in plaintext {
+            fs::write(format!("{}/{}", top, name), contents).unwrap();
+        }
+        let old_data = Arc::new(old_data);
+        functions::process_vault(&masterfile_path, &old_data, true).unwrap();
+        let new_data = masterfile::start_rekey(&masterfile_path, &old_data, "new password", None).unwrap();
+
+        // One file was moved into place, one was recorded but not moved and
+        // one was written but never recorded when the rekey stopped
+        let files = encrypted_files(&top);
+        assert_eq!(files.len(), 3);
+        let mut journal = Journal::open(&top).unwrap();
+        journal.rekey_file(&files[0], None, &old_data, &new_data).unwrap();
+        let temp_path = format!("{}{}", files[1], TEMP_SUFFIX);
+        let dist_path = encryptionFunctions::rekey_file(&files[1], &temp_path, None,
+            &old_data.master_key, &new_data.master_key, &new_data.settings).unwrap();
+        journal.append(&format!("{}\t{}\t{}",
+            journal.relative(&temp_path), journal.relative(&dist_path), journal.relative(&files[1]))).unwrap();
+        fs::write(format!("{}{}", files[2], TEMP_SUFFIX), "partial").unwrap();
+
+        // Running it again with the same keys finishes the rotation
+        rekey_vault(&top, &old_data, &new_data).unwrap();
+        masterfile::finish_rekey(&masterfile_path, &new_data, "new password", None).unwrap();
+        remove_journal(&top).unwrap();
+        assert_eq!(encrypted_files(&top).len(), 3);
+        assert!(encrypted_files(&top).iter().all(|path| !files.contains(path)));
+
+        assert!(masterfile::read_masterfile(&masterfile_path, "password", None).is_err());
+        let data = masterfile::read_masterfile(&masterfile_path, "new password", None).unwrap();
+        functions::process_vault(&masterfile_path, &Arc::new(data), false).unwrap();
+        for (name, contents) in plaintext {
+            assert_eq!(fs::read_to_string(format!("{}/{}", top, name)).unwrap(), contents);
+        }
+        assert!(!Path::new(&format!("{}/{}", top, JOURNAL_NAME)).exists());
+        fs::remove_dir_all(&top).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+// Import functions from external crates
+use std::mem;
+use zeroize::Zeroize;
+
+// Import functions from other files
+use crate::memory;
+
+///
+/// Password or other secret text that is wiped from memory when dropped.
+/// It is deliberately neither `Clone` nor `Debug`, so it can not be copied
+/// or printed by accident. Use `expose` to borrow the text where it is
+/// needed. The text is locked in RAM while it is held.
+/// # Data
+/// - `secret: String`
+/// - `locked: bool`
+///     - Whether the buffer of the text is locked in RAM
+/// 
+pub struct SecretString {
+    secret: String,
+    locked: bool,
+}
+
+impl SecretString {
+    ///
+    /// Take ownership of the text, it is wiped when the secret is dropped.
+    /// # Arguments
+    /// - `secret: String`
+    /// 
+    /// Returns `SecretString`
+    /// 
+    pub fn new(secret: String) -> SecretString {
+        let locked = memory::lock(secret.as_str());
+        SecretString { secret, locked }
+    }
+
+    ///
+    /// Borrow the secret text
+    /// 
+    /// Returns `&str`
+    /// 
+    pub fn expose(&self) -> &str {
+        &self.secret
+    }
+
+    ///
+    /// Check whether the secret is empty
+    /// 
+    /// Returns `bool`
+    /// 
+    pub fn is_empty(&self) -> bool {
+        self.secret.is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> SecretString {
+        SecretString::new(secret)
+    }
+}
+
+// Compare without going through plain strings
+impl PartialEq for SecretString {
+    fn eq(&self, other: &SecretString) -> bool {
+        self.secret == other.secret
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        // Wipe the buffer before it is unlocked and freed
+        let mut bytes = mem::take(&mut self.secret).into_bytes();
+        bytes.as_mut_slice().zeroize();
+        if self.locked {
+            memory::unlock(bytes.as_slice());
+        }
+    }
+}
+
+///
+/// Key or other secret bytes that are wiped from memory when dropped, the
+/// counterpart of `SecretString` for binary secrets. The bytes are locked
+/// in RAM while they are held.
+/// # Data
+/// - `secret: Box<[u8]>`
+/// - `locked: bool`
+///     - Whether the bytes are locked in RAM
+/// 
+pub struct SecretBytes {
+    secret: Box<[u8]>,
+    locked: bool,
+}
+
+impl SecretBytes {
+    ///
+    /// Take ownership of the bytes, they are wiped when the secret is dropped.
+    /// # Arguments
+    /// - `secret: Vec<u8>`
+    /// 
+    /// Returns `SecretBytes`
+    /// 
+    pub fn new(mut secret: Vec<u8>) -> SecretBytes {
+        // Copy into a buffer of the exact size, shrinking could leave the
+        // bytes behind in the old allocation
+        let boxed: Box<[u8]> = secret.as_slice().into();
+        secret.zeroize();
+        let locked = memory::lock(&*boxed);
+        SecretBytes { secret: boxed, locked }
+    }
+
+    ///
+    /// Borrow the secret bytes
+    /// 
+    /// Returns `&[u8]`
+    /// 
+    pub fn expose(&self) -> &[u8] {
+        &self.secret
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+        if self.locked {
+            memory::unlock(&*self.secret);
+        }
+    }
+}
@@ -0,0 +1,287 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use sharks::{Share, Sharks};
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    fs,
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+};
+use zeroize::Zeroize;
+
+// Import functions from other files
+use crate::masterfile::{self, MasterfileData};
+
+// Prefix of every printable share
+const SHARE_PREFIX: &str = "rusty-vault-share";
+
+// Domain separation for the fingerprint of the shared key material
+const FINGERPRINT_CONTEXT: &[u8] = b"rusty-vault share fingerprint";
+
+// Permissions of share files and their directory, for the user only
+const DIR_MODE: u32 = 0o700;
+const FILE_MODE: u32 = 0o600;
+
+///
+/// Fingerprint of the key material, stored in every share so shares of
+/// different vaults or a wrong combination are detected on recovery.
+/// # Arguments
+/// - `key_material: &[u8]`
+/// 
+/// Returns `String`
+/// 
+fn fingerprint(key_material: &[u8]) -> String {
+    let digest = Sha256::new()
+        .chain_update(FINGERPRINT_CONTEXT)
+        .chain_update(key_material)
+        .finalize();
+    hex::encode(&digest[..8])
+}
+
+///
+/// Split the keys of a vault into Shamir shares, any `threshold` of which
+/// rebuild the keys. Each share is a single line of printable text of the
+/// form `rusty-vault-share:<threshold>:<fingerprint>:<share>`.
+/// # Arguments
+/// - `masterfile_path: &str`
+///     - Path to the masterfile, the fingerprint of the keys is recorded in it
+/// - `data: &MasterfileData`
+///     - Data of the unlocked masterfile
+/// - `threshold: u8`
+///     - Number of shares needed to recover the keys
+/// - `count: u8`
+///     - Number of shares to create
+/// 
+/// Returns `Result<Vec<String>, anyhow::Error>`
+/// 
+pub fn split_key(
+    masterfile_path: &str,
+    data: &MasterfileData,
+    threshold: u8,
+    count: u8,
+) -> Result<Vec<String>, anyhow::Error> {
+    if threshold < 2 || threshold > count {
+        return Err(anyhow!("Threshold must be at least 2 and at most the number of shares."));
+    }
+
+    let mut key_material = masterfile::material_from_data(data);
+    let key_fingerprint = fingerprint(&key_material);
+    masterfile::set_share_check(masterfile_path, &hex::decode(&key_fingerprint)?)?;
+    let shares = Sharks(threshold)
+        .dealer_rng(&key_material, &mut OsRng)
+        .take(count as usize)
+        .map(|share| {
+            let mut share_bytes = Vec::from(&share);
+            let text = format!("{}:{}:{}:{}",
+                SHARE_PREFIX, threshold, key_fingerprint, hex::encode(&share_bytes));
+            share_bytes.zeroize();
+            text
+        })
+        .collect();
+    key_material.zeroize();
+    Ok(shares)
+}
+
+///
+/// Write each share to its own file that only the user can read. Existing
+/// files are never overwritten.
+/// # Arguments
+/// - `dist_path: &str`
+///     - Directory to write the shares to, created if missing
+/// - `vault_name: &str`
+///     - Name of the vault, used in the filenames
+/// - `shares: &[String]`
+///     - Shares returned by `split_key`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn write_shares(
+    dist_path: &str,
+    vault_name: &str,
+    shares: &[String],
+) -> Result<(), anyhow::Error> {
+    fs::DirBuilder::new().recursive(true).mode(DIR_MODE).create(dist_path)?;
+    for (index, share) in shares.iter().enumerate() {
+        let share_path = format!("{}/{}-share-{}.txt", dist_path, vault_name, index + 1);
+        let mut share_file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(FILE_MODE)
+            .open(&share_path)
+            .map_err(|err| anyhow!("Could not create {}: {}", share_path, err))?;
+        share_file.write_all(format!("{}\n", share).as_bytes())?;
+    }
+    Ok(())
+}
+
+///
+/// Parse a single share. The input can be the share itself or the path
+/// to a file holding it.
+/// # Arguments
+/// - `input: &str`
+/// 
+/// Returns `Result<(u8, String, Share), anyhow::Error>`, the threshold,
+/// the fingerprint and the share
+/// 
+pub fn parse_share(input: &str) -> Result<(u8, String, Share), anyhow::Error> {
+    let text = if input.trim().starts_with(SHARE_PREFIX) {
+        input.trim().to_string()
+    } else {
+        fs::read_to_string(input)
+            .map_err(|_| anyhow!("{} is not a share or a share file.", input))?
+            .trim()
+            .to_string()
+    };
+
+    let parts = text.split(':').collect::<Vec<&str>>();
+    if parts.len() != 4 || parts[0] != SHARE_PREFIX {
+        return Err(anyhow!("Malformed share."));
+    }
+    let threshold = parts[1].parse::<u8>()
+        .map_err(|_| anyhow!("Malformed share threshold."))?;
+    let mut share_bytes = hex::decode(parts[3])?;
+    let share = Share::try_from(share_bytes.as_slice())
+        .map_err(|err| anyhow!("Malformed share: {}", err));
+    share_bytes.zeroize();
+    Ok((threshold, parts[2].to_string(), share?))
+}
+
+///
+/// Rebuild the keys of a vault from enough of its shares.
+/// # Arguments
+/// - `shares: &[(u8, String, Share)]`
+///     - Shares returned by `parse_share`
+/// - `masterfile_path: &str`
+///     - Path to the masterfile of the vault the shares belong to
+/// 
+/// Returns `Result<MasterfileData, anyhow::Error>`
+/// 
+pub fn combine_shares(
+    shares: &[(u8, String, Share)],
+    masterfile_path: &str,
+) -> Result<MasterfileData, anyhow::Error> {
+    let (threshold, key_fingerprint, _) = shares.first()
+        .ok_or_else(|| anyhow!("No shares given."))?;
+    if shares.iter().any(|(other_threshold, other_fingerprint, _)|
+        other_threshold != threshold || other_fingerprint != key_fingerprint) {
+        return Err(anyhow!("Shares belong to different splits."));
+    }
+
+    // The same share entered twice would count twice towards the threshold
+    let mut indexes = HashSet::new();
+    for (_, _, share) in shares {
+        let mut bytes = Vec::from(share);
+        let index = bytes[0];
+        bytes.zeroize();
+        if !indexes.insert(index) {
+            return Err(anyhow!("Share {} was given more than once.", index));
+        }
+    }
+
+    // Make sure the shares were split from this vault
+    match masterfile::read_share_check(masterfile_path)? {
+        Some(share_check) if hex::encode(&share_check) == *key_fingerprint => (),
+        _ => return Err(anyhow!("Shares do not belong to this vault.")),
+    }
+
+    let mut key_material = Sharks(*threshold)
+        .recover(shares.iter().map(|(_, _, share)| share))
+        .map_err(|err| anyhow!("{}", err))?;
+    if &fingerprint(&key_material) != key_fingerprint || key_material.len() != masterfile::KEY_MATERIAL_LEN {
+        key_material.zeroize();
+        return Err(anyhow!("Shares do not rebuild the vault keys."));
+    }
+
+    let data = masterfile::data_from_material(&key_material, masterfile::read_settings(masterfile_path)?);
+    key_material.zeroize();
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use crate::testutil;
+
+    fn test_vault(name: &str) -> (String, MasterfileData) {
+        testutil::test_vault(&format!("shares-{}", name), &testutil::test_settings())
+    }
+
+    fn parse(share: &str) -> (u8, String, Share) {
+        parse_share(share).unwrap()
+    }
+
+    #[test]
+    fn split_and_combine() {
+        let (masterfile_path, data) = test_vault("combine");
+        assert!(split_key(&masterfile_path, &data, 1, 3).is_err());
+        assert!(split_key(&masterfile_path, &data, 4, 3).is_err());
+        let shares = split_key(&masterfile_path, &data, 3, 5).unwrap();
+
+        // Any three shares rebuild the keys, also from a share file
+        let share_file = masterfile_path.replace("masterfile.e", "share");
+        fs::write(&share_file, format!("{}\n", shares[4])).unwrap();
+        let recovered = combine_shares(&[parse(&shares[0]), parse(&shares[2]), parse(&share_file)],
+            &masterfile_path).unwrap();
+        assert_eq!(recovered.master_key, data.master_key);
+        assert_eq!(recovered.folder_salt, data.folder_salt);
+        assert_eq!(recovered.folder_nonce, data.folder_nonce);
+
+        assert!(combine_shares(&[parse(&shares[0]), parse(&shares[1])], &masterfile_path).is_err());
+        assert!(combine_shares(&[], &masterfile_path).is_err());
+    }
+
+    #[test]
+    fn rejected_shares() {
+        let (masterfile_path, data) = test_vault("rejected");
+        let (other_path, other_data) = test_vault("rejected-other");
+        let shares = split_key(&masterfile_path, &data, 2, 3).unwrap();
+        let other_shares = split_key(&other_path, &other_data, 2, 3).unwrap();
+
+        // Shares of another vault, or mixed with one
+        assert!(combine_shares(&[parse(&shares[0]), parse(&shares[1])], &other_path).is_err());
+        assert!(combine_shares(&[parse(&shares[0]), parse(&other_shares[1])], &masterfile_path).is_err());
+
+        // The same share twice
+        assert!(combine_shares(&[parse(&shares[1]), parse(&shares[1])], &masterfile_path).is_err());
+
+        // A share with a flipped digit rebuilds other keys
+        let mut tampered = shares[1].clone();
+        let last = if tampered.ends_with('0') { '1' } else { '0' };
+        tampered.pop();
+        tampered.push(last);
+        assert!(combine_shares(&[parse(&shares[0]), parse(&tampered)], &masterfile_path).is_err());
+    }
+
+    #[test]
+    fn malformed_shares() {
+        assert!(parse_share("rusty-vault-share:2:abcd").is_err());
+        assert!(parse_share("rusty-vault-share:x:abcd:0102").is_err());
+        assert!(parse_share("rusty-vault-share:2:abcd:zz").is_err());
+        assert!(parse_share("rusty-vault-share:2:abcd:").is_err());
+        assert!(parse_share("other-share:2:abcd:0102").is_err());
+        assert!(parse_share("/nonexistent/share").is_err());
+    }
+
+    #[test]
+    fn private_share_files() {
+        let (masterfile_path, data) = test_vault("files");
+        let shares = split_key(&masterfile_path, &data, 2, 3).unwrap();
+        let dist_path = format!("{}/shares/nested", masterfile_path.trim_end_matches("/masterfile.e"));
+        write_shares(&dist_path, "vault", &shares).unwrap();
+
+        assert_eq!(fs::metadata(&dist_path).unwrap().permissions().mode() & 0o777, DIR_MODE);
+        for index in 1..=3 {
+            let share_path = format!("{}/vault-share-{}.txt", dist_path, index);
+            assert_eq!(fs::metadata(&share_path).unwrap().permissions().mode() & 0o777, FILE_MODE);
+            parse_share(&share_path).unwrap();
+        }
+
+        // Shares written earlier are left alone
+        assert!(write_shares(&dist_path, "vault", &shares).is_err());
+        assert_eq!(fs::read_to_string(format!("{}/vault-share-1.txt", dist_path)).unwrap(), format!("{}\n", shares[0]));
+    }
+}
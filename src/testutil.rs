@@ -0,0 +1,52 @@
+// Import functions from external crates
+use std::{env, fs, process};
+
+// Import functions from other files
+use crate::{
+    kdf::KdfParams,
+    masterfile::{self, MasterfileData, VaultSettings},
+};
+
+// Cheapest parameters argon2 accepts, to keep the tests fast
+pub const TEST_KDF: KdfParams = KdfParams { mem_cost: 8, time_cost: 1, lanes: 1 };
+
+///
+/// Get the default vault settings with the cheapest key derivation
+/// 
+/// Returns `VaultSettings`
+/// 
+pub fn test_settings() -> VaultSettings {
+    VaultSettings { kdf: TEST_KDF, ..Default::default() }
+}
+
+///
+/// Create an empty directory for a test, removing whatever an earlier run
+/// left in it.
+/// # Arguments
+/// - `name: &str`
+///     - Name of the test, unique across the crate
+/// 
+/// Returns `String`
+/// 
+pub fn test_dir(name: &str) -> String {
+    let dir = env::temp_dir().join(format!("rusty-vault-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.to_string_lossy().to_string()
+}
+
+///
+/// Create a vault with the password "password" in an empty directory.
+/// # Arguments
+/// - `name: &str`
+///     - Name of the test, unique across the crate
+/// - `settings: &VaultSettings`
+/// 
+/// Returns `(String, MasterfileData)`, the path of the masterfile and the
+/// keys of the vault
+/// 
+pub fn test_vault(name: &str, settings: &VaultSettings) -> (String, MasterfileData) {
+    let dir = test_dir(name);
+    let data = masterfile::create_masterfile(&dir, "password", None, settings).unwrap();
+    (format!("{}/masterfile.e", dir), data)
+}
@@ -1,10 +1,11 @@
-/// 
+///
 /// Module for Vault object
 /// 
 #[allow(clippy::module_inception)]
 pub mod vault {
     // Import functions from file
-    use crate::functions;
+    use anyhow::anyhow;
+    use crate::{autolock::AutoLock, functions};
 
     ///
     /// Data structure for Vault
@@ -21,6 +22,14 @@ pub mod vault {
     ///         - 1: unlocked
     ///         - 2: mixed
     ///         - 3: None/Error
+    ///         - 4: offline, the top directory is not there
+    /// - `hashed_password: Vec<u8>`
+    ///     - Hash of the vault password
+    /// - `autolock: AutoLock`
+    ///     - When the vault is locked again without being asked to
+    /// - `uuid: Option<String>`
+    ///     - UUID recorded in the masterfile, used to find the vault again
+    ///       when it moves
     /// 
     #[derive(Clone)]
     pub struct Vault {
@@ -29,6 +38,8 @@ pub mod vault {
         pub path: String,
         pub status: u8, 
         pub hashed_password: Vec<u8>,
+        pub autolock: AutoLock,
+        pub uuid: Option<String>,
     }
     
     impl Vault{
@@ -40,27 +51,45 @@ pub mod vault {
         /// - `master_file_path: String`
         ///     - Path of the masterfile
         /// 
-        /// Returns `Vault`
+        /// Returns `Result<Vault, anyhow::Error>`, an error when the path
+        /// does not end in `/masterfile.e`
         /// 
         pub fn new(
             name: String, 
             master_file_path: String,
             hashed_password: Vec<u8>,
-        ) -> Vault{
+        ) -> Result<Vault, anyhow::Error> {
             // Strip suffix to get the top dir path
-            let path: String = String::from(master_file_path
-                .strip_suffix("/masterfile.e").unwrap());
+            let path = top_dir_path(&master_file_path)?;
 
             // Set initial encryption status
             // TODO: Status is immediated rechecked so set this as a simple initialized variable
             let status = functions::check_vault_status(&path);
-            Vault {
+            Ok(Vault {
                 name,
                 master_file_path,
                 path,
                 status,
                 hashed_password,
-            }
+                autolock: AutoLock::default(),
+                uuid: None,
+            })
+        }
+
+        ///
+        /// Point the vault to the masterfile at its new location and check
+        /// its status there.
+        /// # Arguments
+        /// - `master_file_path: String`
+        ///     - New path of the masterfile
+        /// 
+        /// Returns `Result<(), anyhow::Error>`
+        /// 
+        pub fn relocate(&mut self, master_file_path: String) -> Result<(), anyhow::Error> {
+            self.path = top_dir_path(&master_file_path)?;
+            self.master_file_path = master_file_path;
+            self.status = functions::check_vault_status(&self.path);
+            Ok(())
         }
 
         ///
@@ -83,4 +112,17 @@ pub mod vault {
             Ok(())
         }
     }
+
+    ///
+    /// Get the top directory of a vault from the path to its masterfile
+    /// # Arguments
+    /// - `master_file_path: &str`
+    /// 
+    /// Returns `Result<String, anyhow::Error>`
+    /// 
+    fn top_dir_path(master_file_path: &str) -> Result<String, anyhow::Error> {
+        master_file_path.strip_suffix("/masterfile.e")
+            .map(String::from)
+            .ok_or_else(|| anyhow!("{} is not the path to a masterfile.", master_file_path))
+    }
 }
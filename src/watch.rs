@@ -0,0 +1,193 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+// Import functions from other files
+use crate::{
+    encryptionFunctions,
+    flatstore,
+    functions,
+    masterfile::MasterfileData,
+    mirror,
+    rekey,
+};
+
+// Time a path has to stay untouched before it is encrypted, so files that
+// are still being written are left alone
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+// How often pending paths are checked while no events arrive
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+///
+/// Watch a locked vault and encrypt plaintext files as soon as they are
+/// created or modified in it. New folders are encrypted as a whole once
+/// nothing below them changes any more, including their names. Runs until
+/// the process is killed.
+/// # Arguments
+/// - `masterfile_path: &str`
+///     - Path to the masterfile of the vault
+/// - `masterfile_data: &Arc<MasterfileData>`
+///     - Data of the decrypted masterfile
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn watch_vault(
+    masterfile_path: &str,
+    masterfile_data: &Arc<MasterfileData>,
+) -> Result<(), anyhow::Error> {
+    let top = masterfile_path.strip_suffix("/masterfile.e")
+        .ok_or_else(|| anyhow!("{} is not a masterfile.", masterfile_path))?;
+    if functions::check_vault_status(top) == 1 {
+        return Err(anyhow!("The vault is unlocked. Lock it before watching it."));
+    }
+
+    // Start watching before the first pass so nothing is missed in between
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(Path::new(top), RecursiveMode::Recursive)?;
+
+    // Encrypt anything left in plaintext while the vault was not watched
+    functions::process_vault(masterfile_path, masterfile_data, true)?;
+    println!("Watching {} for new files", top);
+
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    loop {
+        // Collect the paths touched since the last pass. Pending paths are
+        // checked after every event too, so a busy vault still gets encrypted
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        if is_watched(top, &path, masterfile_data) {
+                            pending.insert(path, Instant::now());
+                        }
+                    }
+                }
+            }
+            Ok(Err(err)) => println!("Watch error: {}", err),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(anyhow!("Stopped receiving changes of {}.", top));
+            }
+        }
+
+        // Encrypt the paths that have been quiet long enough
+        let quiet = pending.iter()
+            .filter(|(_, touched)| touched.elapsed() >= DEBOUNCE)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<PathBuf>>();
+        for path in quiet {
+            let target = plaintext_root(top, &path);
+
+            // Wait until nothing below a new folder is being written
+            if target.is_dir() && pending.iter()
+                .any(|(other, touched)| other.starts_with(&target) && touched.elapsed() < DEBOUNCE) {
+                continue;
+            }
+            pending.retain(|other, _| !other.starts_with(&target));
+            if let Err(err) = encrypt_target(top, &target, masterfile_data) {
+                println!("Could not encrypt {}: {}", target.display(), err);
+            }
+        }
+    }
+}
+
+///
+/// Check whether a changed path is plaintext that belongs to the vault,
+/// skipping the masterfile, encrypted files and files of the program.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// - `path: &Path`
+///     - Changed path
+/// - `masterfile_data: &MasterfileData`
+/// 
+/// Returns `bool`
+/// 
+fn is_watched(top: &str, path: &Path, masterfile_data: &MasterfileData) -> bool {
+    let relative = match path.strip_prefix(top) {
+        Ok(relative) => relative,
+        Err(_) => return false,
+    };
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+    if masterfile_data.settings.layout == flatstore::LAYOUT_FLAT
+        && relative.starts_with(flatstore::STORE_DIR) {
+        return false;
+    }
+    !(relative.as_os_str().is_empty() || name.ends_with(".encrypted") || name.ends_with(".rekey")
+        || name == "masterfile.e" || name == rekey::JOURNAL_NAME || mirror::is_manifest(&path.to_string_lossy())
+        || name == ".DS_Store" || name == "Icon")
+}
+
+///
+/// Get the highest folder above a path whose name is still plaintext, or
+/// the path itself when every folder above it is encrypted.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// - `path: &Path`
+/// 
+/// Returns `PathBuf`
+/// 
+fn plaintext_root(top: &str, path: &Path) -> PathBuf {
+    let mut target = path.to_path_buf();
+    for ancestor in path.ancestors().skip(1) {
+        if ancestor == Path::new(top) || !ancestor.starts_with(top) {
+            break;
+        }
+        if !ancestor.to_string_lossy().ends_with(".encrypted") {
+            target = ancestor.to_path_buf();
+        }
+    }
+    target
+}
+
+///
+/// Encrypt a plaintext file, or a plaintext folder with everything in it.
+/// # Arguments
+/// - `top: &str`
+///     - Path to the top directory of the vault
+/// - `target: &Path`
+///     - File or folder to encrypt
+/// - `masterfile_data: &Arc<MasterfileData>`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+fn encrypt_target(
+    top: &str,
+    target: &Path,
+    masterfile_data: &Arc<MasterfileData>,
+) -> Result<(), anyhow::Error> {
+    // The path may have been moved away or encrypted in the meantime
+    if !target.exists() {
+        return Ok(());
+    }
+
+    // Flat vaults move every plaintext entry into the store at once
+    if masterfile_data.settings.layout == flatstore::LAYOUT_FLAT {
+        flatstore::lock_vault(top, masterfile_data)?;
+        println!("Encrypted {}", target.display());
+        return Ok(());
+    }
+
+    let target_path = target.to_str()
+        .ok_or_else(|| anyhow!("Invalid path {}.", target.display()))?;
+    if target.is_dir() {
+        functions::process_tree(target_path, masterfile_data, true)?;
+        encryptionFunctions::encrypt_foldername(target_path, masterfile_data)?;
+    } else if !target_path.ends_with(".encrypted") {
+        encryptionFunctions::encrypt_file(target_path, &masterfile_data.master_key, &masterfile_data.settings)?;
+    }
+    println!("Encrypted {}", target.display());
+    Ok(())
+}
@@ -0,0 +1,261 @@
+// Import functions from external crates
+use anyhow::anyhow;
+use rand::{rngs::OsRng, RngCore};
+use std::{
+    collections::BTreeSet,
+    ffi::CString,
+    fs,
+    io::{Seek, SeekFrom, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+// Passes of random data written over plaintext before it is removed, set
+// from the command line or the config file. Zero removes files normally
+static PASSES: OnceLock<u32> = OnceLock::new();
+
+// Filesystems that were already warned about, by their device
+static WARNED: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
+// Size of the chunks written over a file
+const CHUNK_LEN: usize = 64 * 1024;
+
+// Linux filesystems that write changed blocks to a new place
+#[cfg(target_os = "linux")]
+const COPY_ON_WRITE_MAGIC: &[(i64, &str)] = &[
+    (0x9123683e, "btrfs"),
+    (0x2fc12fc1, "ZFS"),
+    (0xca451a4e, "bcachefs"),
+    (0xf2f52010, "F2FS"),
+    (0x3434, "NILFS"),
+];
+
+// Linux filesystems that only live in memory, tmpfs and ramfs
+#[cfg(target_os = "linux")]
+const RAM_MAGIC: &[i64] = &[0x01021994, 0x858458f6];
+
+///
+/// Set the number of passes written over plaintext before it is removed.
+/// # Arguments
+/// - `passes: u32`
+///     - Zero to remove files without overwriting them
+/// 
+pub fn set_passes(passes: u32) {
+    PASSES.get_or_init(|| passes);
+}
+
+///
+/// Remove a plaintext file once its encrypted copy is written, overwriting
+/// it first when secure deletion is turned on.
+/// # Arguments
+/// - `path: &str`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn remove_plaintext(path: &str) -> Result<(), anyhow::Error> {
+    match PASSES.get() {
+        Some(&passes) if passes > 0 => shred_file(path, passes),
+        _ => Ok(fs::remove_file(path)?),
+    }
+}
+
+///
+/// Overwrite a file with random data, then truncate it, give it a random
+/// name and remove it. Every pass and the truncation are flushed to the
+/// disk. A warning is printed the first time a file is on a filesystem
+/// where overwriting does not reach the old blocks.
+/// # Arguments
+/// - `path: &str`
+/// - `passes: u32`
+///     - Passes of random data written over the contents
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn shred_file(path: &str, passes: u32) -> Result<(), anyhow::Error> {
+    warn_if_ineffective(path);
+
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    let len = file.metadata()?.len();
+    let mut chunk = vec![0u8; CHUNK_LEN];
+    for _ in 0..passes {
+        file.seek(SeekFrom::Start(0))?;
+        let mut written = 0;
+        while written < len {
+            let count = (len - written).min(CHUNK_LEN as u64) as usize;
+            OsRng.fill_bytes(&mut chunk[..count]);
+            file.write_all(&chunk[..count])?;
+            written += count as u64;
+        }
+        file.sync_all()?;
+    }
+    file.set_len(0)?;
+    file.sync_all()?;
+    drop(file);
+
+    // Hide the name in the directory before the entry goes away
+    let parent = Path::new(path).parent()
+        .ok_or_else(|| anyhow!("Invalid path {}.", path))?;
+    let mut name = [0u8; 16];
+    OsRng.fill_bytes(&mut name);
+    let hidden_path = parent.join(hex::encode(name));
+    fs::rename(path, &hidden_path)?;
+    fs::remove_file(&hidden_path)?;
+    Ok(())
+}
+
+///
+/// Warn once per filesystem when the file is on a copy-on-write or log
+/// structured filesystem, where the overwritten data stays on the disk.
+/// # Arguments
+/// - `path: &str`
+/// 
+fn warn_if_ineffective(path: &str) {
+    let device = match fs::metadata(path) {
+        Ok(metadata) => std::os::unix::fs::MetadataExt::dev(&metadata),
+        Err(_) => return,
+    };
+    if !WARNED.lock().unwrap().insert(device) {
+        return;
+    }
+    if let Some(filesystem) = copy_on_write_filesystem(path) {
+        println!("##Warning##");
+        println!("{} is on {}, which writes changes to new blocks. Overwriting does not remove the plaintext from the disk there.",
+            path, filesystem);
+    }
+}
+
+///
+/// Get the name of the filesystem of a path if it is known to keep old
+/// blocks when a file is overwritten
+/// # Arguments
+/// - `path: &str`
+/// 
+/// Returns `Option<String>`
+/// 
+fn copy_on_write_filesystem(path: &str) -> Option<String> {
+    let stat = statfs(path)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        COPY_ON_WRITE_MAGIC.iter()
+            .find(|(magic, _)| *magic == filesystem_type(&stat))
+            .map(|(_, name)| name.to_string())
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let name = filesystem_name(&stat);
+        matches!(&name[..], "apfs" | "zfs").then_some(name)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = stat;
+        None
+    }
+}
+
+///
+/// Check whether a directory is on a filesystem that only lives in memory,
+/// so plaintext written there never reaches a disk
+/// # Arguments
+/// - `path: &str`
+/// 
+/// Returns `bool`
+/// 
+pub fn is_ram_backed(path: &str) -> bool {
+    let stat = match statfs(path) {
+        Some(stat) => stat,
+        None => return false,
+    };
+
+    #[cfg(target_os = "linux")]
+    {
+        RAM_MAGIC.contains(&filesystem_type(&stat))
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        filesystem_name(&stat) == "tmpfs"
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = stat;
+        false
+    }
+}
+
+///
+/// Wipe a directory with everything in it, overwriting every file at least
+/// once before it is removed. Symbolic links are removed without touching
+/// what they point to.
+/// # Arguments
+/// - `dir_path: &str`
+/// 
+/// Returns `Result<(), anyhow::Error>`
+/// 
+pub fn wipe_dir(dir_path: &str) -> Result<(), anyhow::Error> {
+    let passes = PASSES.get().copied().unwrap_or(0).max(1);
+    for entry in fs::read_dir(dir_path)? {
+        let entry = entry?;
+        let path = entry.path();
+        let path = path.to_str()
+            .ok_or_else(|| anyhow!("Invalid path {}.", path.display()))?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            wipe_dir(path)?;
+        } else if file_type.is_file() {
+            shred_file(path, passes)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    fs::remove_dir(dir_path)?;
+    Ok(())
+}
+
+///
+/// Get the filesystem statistics of a path
+/// # Arguments
+/// - `path: &str`
+/// 
+/// Returns `Option<libc::statfs>`
+/// 
+fn statfs(path: &str) -> Option<libc::statfs> {
+    let c_path = CString::new(Path::new(path).as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat)
+}
+
+///
+/// Get the magic number of the filesystem from its statistics, whose type
+/// differs between platforms
+/// # Arguments
+/// - `stat: &libc::statfs`
+/// 
+/// Returns `i64`
+/// 
+#[cfg(target_os = "linux")]
+#[allow(clippy::unnecessary_cast)]
+fn filesystem_type(stat: &libc::statfs) -> i64 {
+    stat.f_type as i64
+}
+
+///
+/// Get the name of the filesystem from its statistics
+/// # Arguments
+/// - `stat: &libc::statfs`
+/// 
+/// Returns `String`
+/// 
+#[cfg(target_os = "macos")]
+fn filesystem_name(stat: &libc::statfs) -> String {
+    unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) }
+        .to_string_lossy()
+        .to_string()
+}